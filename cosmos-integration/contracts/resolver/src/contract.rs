@@ -117,6 +117,11 @@ pub fn execute_deploy_src(
         return Err(ContractError::InvalidSecretHash {});
     }
 
+    // A second order under the same hash would be unlocked by the same secret
+    if SECRET_HASH_TO_ORDER_ID.has(deps.storage, &secret_hash) {
+        return Err(ContractError::HashlockAlreadyUsed {});
+    }
+
     let current_time = env.block.time;
     let timelock = current_time.plus_seconds(timelock_seconds);
     
@@ -391,6 +396,9 @@ pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
         QueryMsg::OrderBySecretHash { secret_hash } => {
             to_json_binary(&query_order_by_secret_hash(deps, secret_hash)?)
         }
+        QueryMsg::EscrowsBySecretHash { secret_hash } => {
+            to_json_binary(&query_escrows_by_secret_hash(deps, secret_hash)?)
+        }
         QueryMsg::OrdersByResolver { resolver, start_after, limit } => {
             to_json_binary(&query_orders_by_resolver(deps, resolver, start_after, limit)?)
         }
@@ -427,6 +435,16 @@ fn query_order_by_secret_hash(deps: Deps, secret_hash: String) -> StdResult<u64>
     SECRET_HASH_TO_ORDER_ID.load(deps.storage, &secret_hash)
 }
 
+// Every order escrowed under a secret hash: none or one, since deploy_src
+// rejects a hash already in use
+fn query_escrows_by_secret_hash(deps: Deps, secret_hash: String) -> StdResult<OrdersResponse> {
+    let orders = match SECRET_HASH_TO_ORDER_ID.may_load(deps.storage, &secret_hash)? {
+        Some(order_id) => vec![ORDERS.load(deps.storage, order_id)?],
+        None => vec![],
+    };
+    Ok(OrdersResponse { orders })
+}
+
 fn query_orders_by_resolver(
     deps: Deps,
     resolver: String,
@@ -585,4 +603,81 @@ fn query_escrow_immutables(deps: Deps, order_id: u64) -> StdResult<EscrowImmutab
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn migrate(_deps: DepsMut, _env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
     Ok(Response::new())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+    use cosmwasm_std::{coin, coins, from_json};
+
+    use crate::state::CHAIN_ID_ETHEREUM;
+
+    fn secret_hash(secret: &str) -> String {
+        format!("{:x}", Sha256::digest(secret.as_bytes()))
+    }
+
+    fn instantiated(deps: DepsMut) {
+        let msg = InstantiateMsg {
+            atomic_swap_contract: "atomic_swap".to_string(),
+            bridge_contract: "bridge".to_string(),
+        };
+        instantiate(deps, mock_env(), mock_info("owner", &[]), msg).unwrap();
+    }
+
+    // 1000uatom with a 100uatom safety deposit, from the resolver
+    fn deploy_src(deps: DepsMut, secret_hash: &str) -> Result<Response, ContractError> {
+        let msg = ExecuteMsg::DeploySrc {
+            initiator: "initiator".to_string(),
+            dst_chain_id: CHAIN_ID_ETHEREUM,
+            dst_recipient: "0xrecipient".to_string(),
+            dst_token: "0xtoken".to_string(),
+            src_amount: coin(1000, "uatom"),
+            dst_amount: "1000".to_string(),
+            secret_hash: secret_hash.to_string(),
+            safety_deposit: coin(100, "uatom"),
+            timelock: 3600,
+        };
+        execute(deps, mock_env(), mock_info("resolver", &coins(1100, "uatom")), msg)
+    }
+
+    fn escrows(deps: Deps, secret_hash: &str) -> Vec<ResolverOrder> {
+        let msg = QueryMsg::EscrowsBySecretHash { secret_hash: secret_hash.to_string() };
+        from_json::<OrdersResponse>(&query(deps, mock_env(), msg).unwrap()).unwrap().orders
+    }
+
+    #[test]
+    fn test_hashlock_already_used() {
+        let mut deps = mock_dependencies();
+        instantiated(deps.as_mut());
+        deploy_src(deps.as_mut(), &secret_hash("secret")).unwrap();
+
+        let err = deploy_src(deps.as_mut(), &secret_hash("secret")).unwrap_err();
+        assert!(matches!(err, ContractError::HashlockAlreadyUsed {}));
+
+        // The hash stays taken once its order is cancelled
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(3601);
+        let msg = ExecuteMsg::Cancel { order_id: 1 };
+        execute(deps.as_mut(), env, mock_info("initiator", &[]), msg).unwrap();
+        let err = deploy_src(deps.as_mut(), &secret_hash("secret")).unwrap_err();
+        assert!(matches!(err, ContractError::HashlockAlreadyUsed {}));
+
+        deploy_src(deps.as_mut(), &secret_hash("other secret")).unwrap();
+    }
+
+    #[test]
+    fn test_escrows_by_secret_hash() {
+        let mut deps = mock_dependencies();
+        instantiated(deps.as_mut());
+        assert!(escrows(deps.as_ref(), &secret_hash("secret")).is_empty());
+
+        deploy_src(deps.as_mut(), &secret_hash("other secret")).unwrap();
+        deploy_src(deps.as_mut(), &secret_hash("secret")).unwrap();
+
+        let orders = escrows(deps.as_ref(), &secret_hash("secret"));
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].order_id, 2);
+        assert_eq!(orders[0].secret_hash, secret_hash("secret"));
+        assert_eq!(orders[0].src_amount, coin(1000, "uatom"));
+    }
+}
@@ -21,6 +21,9 @@ pub enum ContractError {
     #[error("Invalid secret hash")]
     InvalidSecretHash {},
 
+    #[error("Hashlock already used")]
+    HashlockAlreadyUsed {},
+
     #[error("Order already completed")]
     OrderAlreadyCompleted {},
 
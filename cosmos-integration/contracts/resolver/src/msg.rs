@@ -49,6 +49,7 @@ pub enum QueryMsg {
     Config {},
     Order { order_id: u64 },
     OrderBySecretHash { secret_hash: String },
+    EscrowsBySecretHash { secret_hash: String },
     OrdersByResolver { resolver: String, start_after: Option<u64>, limit: Option<u32> },
     OrdersByInitiator { initiator: String, start_after: Option<u64>, limit: Option<u32> },
     CanWithdraw { order_id: u64, user: String },
@@ -266,6 +266,22 @@ export class CosmosResolver {
     return orderId;
   }
 
+  // Every order escrowed under a secret hash; empty when the hash is unused
+  async queryEscrowsBySecretHash(secretHash: string): Promise<ResolverOrder[]> {
+    this.ensureResolverContract();
+
+    if (!this.queryClient) {
+      this.queryClient = await CosmWasmClient.connect(this.config.rpcEndpoint);
+    }
+
+    const response = await this.queryClient.queryContractSmart(
+      this.config.resolverContract!,
+      { escrows_by_secret_hash: { secret_hash: secretHash } }
+    );
+
+    return response.orders.map((order: any) => this.parseOrder(order));
+  }
+
   async queryCanWithdraw(orderId: number, user: string): Promise<{
     canWithdraw: boolean;
     reason?: string;
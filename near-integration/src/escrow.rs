@@ -0,0 +1,256 @@
+// Escrow of a single order, deployed by `escrow_factory` to a sub-account of
+// the factory named after its escrow key. The account holds the order's NEAR
+// and nothing else. Once the payout went through it deletes itself, sending
+// the storage balance back to the maker, and tells the factory.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, is_promise_success, near_bindgen, require, AccountId, Gas, NearToken, PanicOnDefault,
+    Promise,
+};
+
+use crate::events::{self, Event};
+use crate::htlc_core::{self, EscrowOutcome, EscrowTerms};
+
+const GAS_FOR_RESOLVE_PAYOUT: Gas = Gas::from_tgas(25);
+const GAS_FOR_ON_ESCROW_CLOSED: Gas = Gas::from_tgas(10);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum EscrowStatus {
+    Active,
+    Withdrawing, // Payout to the taker in flight
+    Refunding,   // Payout to the maker in flight
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct FusionEscrow {
+    factory: AccountId,
+    terms: EscrowTerms,
+    status: EscrowStatus,
+    secret: Option<String>,
+}
+
+#[near_bindgen]
+impl FusionEscrow {
+    // Called by the factory in the batch that creates the account, so the
+    // caller is the factory
+    #[init]
+    pub fn new(terms: EscrowTerms) -> Self {
+        require!(
+            env::account_balance().as_yoctonear() >= terms.amount.0,
+            "Escrow not funded"
+        );
+        Self {
+            factory: env::predecessor_account_id(),
+            terms,
+            status: EscrowStatus::Active,
+            secret: None,
+        }
+    }
+
+    // The taker withdraws with the secret until the timelock
+    pub fn withdraw(&mut self, secret: String) -> Promise {
+        require!(self.status == EscrowStatus::Active, "Escrow not active");
+        require!(!htlc_core::is_expired(self.terms.timelock.0), "Escrow expired");
+        require!(env::predecessor_account_id() == self.terms.taker, "Not the taker");
+        htlc_core::verify_secret(&secret, &self.terms.hashlock, self.terms.hash_algorithm);
+
+        self.status = EscrowStatus::Withdrawing;
+        self.secret = Some(secret.clone());
+
+        events::HtlcWithdrawn {
+            htlc_id: self.terms.order_hash.clone(),
+            secret,
+            withdrawn_by: self.terms.taker.clone(),
+            amount: self.terms.amount,
+        }
+        .emit();
+
+        self.payout(self.terms.taker.clone())
+    }
+
+    // The maker takes the funds back once the timelock passed
+    pub fn refund(&mut self) -> Promise {
+        require!(self.status == EscrowStatus::Active, "Escrow not active");
+        require!(htlc_core::is_expired(self.terms.timelock.0), "Escrow not expired");
+        require!(env::predecessor_account_id() == self.terms.maker, "Not the maker");
+
+        self.status = EscrowStatus::Refunding;
+
+        events::HtlcRefunded {
+            htlc_id: self.terms.order_hash.clone(),
+            refunded_to: self.terms.maker.clone(),
+            amount: self.terms.amount,
+        }
+        .emit();
+
+        self.payout(self.terms.maker.clone())
+    }
+
+    // Callback of the payout. A failed transfer reopens the escrow; a
+    // successful one closes it: the account is deleted with its remaining
+    // balance going to the maker, and the factory drops its record.
+    #[private]
+    pub fn resolve_payout(&mut self) -> bool {
+        if !is_promise_success() {
+            self.status = EscrowStatus::Active;
+            self.secret = None;
+            events::PayoutFailed { htlc_id: self.terms.order_hash.clone(), fill_id: None }.emit();
+            return false;
+        }
+
+        let outcome = match self.status {
+            EscrowStatus::Withdrawing => EscrowOutcome::Withdrawn,
+            _ => EscrowOutcome::Refunded,
+        };
+        Promise::new(env::current_account_id())
+            .delete_account(self.terms.maker.clone())
+            .then(Promise::new(self.factory.clone()).function_call(
+                "on_escrow_closed".to_string(),
+                serde_json::to_vec(&serde_json::json!({
+                    "escrow_key": self.terms.escrow_key(),
+                    "outcome": outcome,
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(0),
+                GAS_FOR_ON_ESCROW_CLOSED,
+            ));
+        true
+    }
+
+    // View methods
+    pub fn get_terms(&self) -> EscrowTerms {
+        self.terms.clone()
+    }
+
+    pub fn get_status(&self) -> EscrowStatus {
+        self.status
+    }
+
+    pub fn get_secret(&self) -> Option<String> {
+        self.secret.clone()
+    }
+
+    pub fn get_factory(&self) -> AccountId {
+        self.factory.clone()
+    }
+}
+
+impl FusionEscrow {
+    fn payout(&self, receiver: AccountId) -> Promise {
+        Promise::new(receiver)
+            .transfer(NearToken::from_yoctonear(self.terms.amount.0))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_PAYOUT)
+                    .resolve_payout(),
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::json_types::{U128, U64};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+    use sha2::{Digest, Sha256};
+
+    use crate::htlc_core::HashAlgorithm;
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn escrow_id() -> AccountId {
+        "0123456789abcdef01234567.factory.near".parse().unwrap()
+    }
+
+    fn as_account(account_id: AccountId, seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(escrow_id())
+            .predecessor_account_id(account_id)
+            .account_balance(NearToken::from_near(2))
+            .block_timestamp(START + seconds * 1_000_000_000)
+            .build());
+    }
+
+    fn payout_result(result: PromiseResult) {
+        testing_env!(
+            VMContextBuilder::new()
+                .current_account_id(escrow_id())
+                .predecessor_account_id(escrow_id())
+                .block_timestamp(START)
+                .build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+    }
+
+    // Escrow of 1 NEAR from accounts(1) to accounts(2), for the secret [5; 32]
+    fn escrow() -> FusionEscrow {
+        as_account("factory.near".parse().unwrap(), 0);
+        FusionEscrow::new(EscrowTerms {
+            order_hash: "0123456789abcdef01234567".repeat(2) + "0123456789abcdef",
+            maker: accounts(1),
+            taker: accounts(2),
+            amount: U128(NearToken::from_near(1).as_yoctonear()),
+            hashlock: hex::encode(Sha256::digest([5u8; 32])),
+            hash_algorithm: HashAlgorithm::Sha256,
+            timelock: U64(START / 1_000_000_000 + 3600),
+        })
+    }
+
+    #[test]
+    fn test_taker_withdraws_and_escrow_closes() {
+        let mut escrow = escrow();
+        assert_eq!(escrow.get_factory(), "factory.near".parse::<AccountId>().unwrap());
+
+        as_account(accounts(2), 60);
+        escrow.withdraw(hex::encode([5u8; 32]));
+        assert_eq!(escrow.get_status(), EscrowStatus::Withdrawing);
+        assert_eq!(escrow.get_secret(), Some(hex::encode([5u8; 32])));
+
+        payout_result(PromiseResult::Successful(vec![]));
+        assert!(escrow.resolve_payout());
+    }
+
+    #[test]
+    fn test_failed_payout_reopens_escrow() {
+        let mut escrow = escrow();
+        as_account(accounts(2), 60);
+        escrow.withdraw(hex::encode([5u8; 32]));
+
+        payout_result(PromiseResult::Failed);
+        assert!(!escrow.resolve_payout());
+        assert_eq!(escrow.get_status(), EscrowStatus::Active);
+        assert_eq!(escrow.get_secret(), None);
+        assert!(get_logs().iter().any(|log| log.contains(r#""event":"payout_failed""#)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Escrow not expired")]
+    fn test_refund_waits_for_timelock() {
+        let mut escrow = escrow();
+        as_account(accounts(1), 60);
+        escrow.refund();
+    }
+
+    #[test]
+    fn test_maker_refunds_after_timelock() {
+        let mut escrow = escrow();
+        as_account(accounts(1), 3600);
+        escrow.refund();
+        assert_eq!(escrow.get_status(), EscrowStatus::Refunding);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not the taker")]
+    fn test_only_taker_withdraws() {
+        let mut escrow = escrow();
+        as_account(accounts(3), 60);
+        escrow.withdraw(hex::encode([5u8; 32]));
+    }
+}
@@ -0,0 +1,397 @@
+// Deploys one `escrow` contract per order, the NEAR counterpart of the EVM
+// `EscrowFactory`. Each escrow lives on its own sub-account named after the
+// hash of its maker and order hash, so an order's funds sit in an account of
+// their own and a bug in one escrow cannot reach the others. The factory keeps
+// a record of every escrow until it closes.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LazyOption, UnorderedMap};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, is_promise_success, near_bindgen, require, AccountId, BorshStorageKey, Gas, NearToken,
+    PanicOnDefault, Promise,
+};
+
+use crate::events::{self, Event};
+use crate::htlc_core::{self, EscrowOutcome, EscrowTerms, HashAlgorithm};
+
+// Fewest hex characters of the escrow key in an escrow account name. The
+// name takes as many as fit in the 64 of an account id, so factory names can
+// have up to 39 characters.
+const MIN_ESCROW_NAME_LENGTH: usize = 24;
+// Escrow account and state besides the code, in bytes
+const ESCROW_STATE_BYTES: u64 = 1_000;
+const GAS_FOR_ESCROW_INIT: Gas = Gas::from_tgas(10);
+const GAS_FOR_ON_ESCROW_DEPLOYED: Gas = Gas::from_tgas(10);
+
+#[derive(BorshSerialize, BorshStorageKey)]
+enum StorageKey {
+    EscrowCode,
+    Escrows,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EscrowRecord {
+    pub escrow_id: AccountId,
+    pub terms: EscrowTerms,
+    pub escrow_storage_cost: U128, // Staked on the escrow account, back to the maker when it is deleted
+    pub record_storage_cost: U128, // Staked on this record, refunded when the escrow closes
+    pub created_at: U64,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct EscrowFactory {
+    owner_id: AccountId,
+    escrow_code: LazyOption<Vec<u8>>,
+    escrows: UnorderedMap<String, EscrowRecord>, // escrow key -> record, until the escrow closes
+}
+
+#[near_bindgen]
+impl EscrowFactory {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            owner_id: env::predecessor_account_id(),
+            escrow_code: LazyOption::new(StorageKey::EscrowCode, None),
+            escrows: UnorderedMap::new(StorageKey::Escrows),
+        }
+    }
+
+    // Stores the escrow wasm passed as raw input. Escrows already deployed
+    // keep the code they were deployed with.
+    pub fn set_escrow_code(&mut self) {
+        require!(env::predecessor_account_id() == self.owner_id, "Not the owner");
+        let code = env::input().expect("Missing escrow code");
+        require!(!code.is_empty(), "Missing escrow code");
+        self.escrow_code.set(&code);
+    }
+
+    // Deploys the escrow of an order, funded with `amount` of the attached
+    // deposit. The rest pays for the escrow account (see
+    // `get_deployment_cost`) and for the record kept here; whatever is left
+    // over goes back to the maker. Each maker can use an order hash once.
+    #[payable]
+    pub fn create_escrow(
+        &mut self,
+        order_hash: String,
+        taker: AccountId,
+        amount: U128,
+        hashlock: String,
+        timelock_seconds: u64,
+        hash_algorithm: Option<HashAlgorithm>,
+    ) -> Promise {
+        let maker = env::predecessor_account_id();
+        let attached = env::attached_deposit().as_yoctonear();
+        let initial_storage = env::storage_usage();
+
+        require!(amount.0 > 0, "Amount must be greater than 0");
+        require!(is_valid_order_hash(&order_hash), "Invalid order hash");
+        htlc_core::assert_valid_hashlock(&hashlock);
+        htlc_core::assert_valid_timelock(timelock_seconds);
+        let escrow_key = htlc_core::escrow_key(&maker, &order_hash);
+        require!(self.escrows.get(&escrow_key).is_none(), "Escrow already exists");
+        let code = self.escrow_code.get().expect("Escrow code not set");
+
+        let escrow_id = Self::escrow_account_id(&escrow_key);
+        let terms = EscrowTerms {
+            order_hash: order_hash.clone(),
+            maker: maker.clone(),
+            taker: taker.clone(),
+            amount,
+            hashlock: hashlock.clone(),
+            hash_algorithm: hash_algorithm.unwrap_or_default(),
+            timelock: U64(htlc_core::now_seconds() + timelock_seconds),
+        };
+        let escrow_storage_cost = Self::storage_cost(code.len() as u64 + ESCROW_STATE_BYTES);
+        let mut record = EscrowRecord {
+            escrow_id: escrow_id.clone(),
+            terms: terms.clone(),
+            escrow_storage_cost: U128(escrow_storage_cost),
+            record_storage_cost: U128(0),
+            created_at: U64(htlc_core::now_seconds()),
+        };
+        self.escrows.insert(&escrow_key, &record);
+        record.record_storage_cost = U128(Self::storage_cost(env::storage_usage() - initial_storage));
+        self.escrows.insert(&escrow_key, &record);
+
+        let required = amount.0 + escrow_storage_cost + record.record_storage_cost.0;
+        require!(attached >= required, "Insufficient deposit for the escrow and its storage");
+        if attached > required {
+            Promise::new(maker.clone()).transfer(NearToken::from_yoctonear(attached - required));
+        }
+
+        events::EscrowCreated {
+            order_hash,
+            escrow_id: escrow_id.clone(),
+            maker,
+            taker,
+            amount,
+            hashlock,
+            timelock: terms.timelock,
+        }
+        .emit();
+
+        Promise::new(escrow_id)
+            .create_account()
+            .transfer(NearToken::from_yoctonear(amount.0 + escrow_storage_cost))
+            .deploy_contract(code)
+            .function_call(
+                "new".to_string(),
+                serde_json::to_vec(&serde_json::json!({ "terms": terms })).unwrap(),
+                NearToken::from_yoctonear(0),
+                GAS_FOR_ESCROW_INIT,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_ESCROW_DEPLOYED)
+                    .on_escrow_deployed(escrow_key),
+            )
+    }
+
+    // Callback of the deployment. If any action failed, the funds came back
+    // to the factory and go on to the maker with the record's storage.
+    #[private]
+    pub fn on_escrow_deployed(&mut self, escrow_key: String) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+
+        let record = self.escrows.remove(&escrow_key).expect("Escrow not found");
+        let refund = record.terms.amount.0 + record.escrow_storage_cost.0 + record.record_storage_cost.0;
+        events::EscrowClosed {
+            order_hash: record.terms.order_hash,
+            escrow_id: record.escrow_id,
+            outcome: EscrowOutcome::DeployFailed,
+        }
+        .emit();
+        Promise::new(record.terms.maker).transfer(NearToken::from_yoctonear(refund));
+        false
+    }
+
+    // Called by an escrow once it paid out and deleted itself. Drops the
+    // record and refunds its storage to the maker.
+    pub fn on_escrow_closed(&mut self, escrow_key: String, outcome: EscrowOutcome) -> bool {
+        let record = self.escrows.get(&escrow_key).expect("Escrow not found");
+        require!(env::predecessor_account_id() == record.escrow_id, "Not the escrow");
+        require!(outcome != EscrowOutcome::DeployFailed, "Invalid outcome");
+        if !is_promise_success() {
+            // The escrow account is still there and keeps its record
+            return false;
+        }
+
+        self.escrows.remove(&escrow_key);
+        events::EscrowClosed { order_hash: record.terms.order_hash, escrow_id: record.escrow_id, outcome }.emit();
+        Promise::new(record.terms.maker).transfer(NearToken::from_yoctonear(record.record_storage_cost.0));
+        true
+    }
+
+    // View methods
+    pub fn get_escrow(&self, maker: AccountId, order_hash: String) -> Option<EscrowRecord> {
+        self.escrows.get(&htlc_core::escrow_key(&maker, &order_hash))
+    }
+
+    // Escrows not closed yet
+    pub fn get_escrows(&self, offset: u64, limit: u64) -> Vec<EscrowRecord> {
+        self.escrows.values().skip(offset as usize).take(limit as usize).collect()
+    }
+
+    pub fn get_escrow_account_id(&self, maker: AccountId, order_hash: String) -> AccountId {
+        require!(is_valid_order_hash(&order_hash), "Invalid order hash");
+        Self::escrow_account_id(&htlc_core::escrow_key(&maker, &order_hash))
+    }
+
+    // What the escrow account stakes for storage; `create_escrow` needs this
+    // plus the amount and a little for the record
+    pub fn get_deployment_cost(&self) -> U128 {
+        let code_len = self.escrow_code.get().map_or(0, |code| code.len() as u64);
+        U128(Self::storage_cost(code_len + ESCROW_STATE_BYTES))
+    }
+
+    pub fn get_owner_id(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+}
+
+impl EscrowFactory {
+    fn escrow_account_id(escrow_key: &str) -> AccountId {
+        let factory_id = env::current_account_id();
+        let name_length = (63 - factory_id.as_str().len().min(63)).min(escrow_key.len());
+        require!(name_length >= MIN_ESCROW_NAME_LENGTH, "Factory account id too long");
+        format!("{}.{}", &escrow_key[..name_length], factory_id).parse().unwrap()
+    }
+
+    fn storage_cost(bytes: u64) -> u128 {
+        bytes as u128 * env::storage_byte_cost().as_yoctonear()
+    }
+}
+
+// Lowercase hex of a 32-byte hash
+fn is_valid_order_hash(order_hash: &str) -> bool {
+    order_hash.len() == 64 && order_hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+    use sha2::{Digest, Sha256};
+
+    const START: u64 = 1_000_000_000_000_000_000;
+    const CODE_LEN: usize = 10_000;
+
+    fn factory_id() -> AccountId {
+        "factory.near".parse().unwrap()
+    }
+
+    fn order_hash(seed: u8) -> String {
+        hex::encode(Sha256::digest([seed; 32]))
+    }
+
+    // Escrow key of accounts(1)'s order
+    fn escrow_key(seed: u8) -> String {
+        htlc_core::escrow_key(&accounts(1), &order_hash(seed))
+    }
+
+    fn as_account(account_id: AccountId, deposit: NearToken) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(factory_id())
+            .predecessor_account_id(account_id)
+            .attached_deposit(deposit)
+            .block_timestamp(START)
+            .build());
+    }
+
+    fn with_result(predecessor: AccountId, result: PromiseResult) {
+        testing_env!(
+            VMContextBuilder::new().current_account_id(factory_id()).predecessor_account_id(predecessor).build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+    }
+
+    fn factory() -> EscrowFactory {
+        as_account(accounts(0), NearToken::from_near(0));
+        let mut factory = EscrowFactory::new();
+        let mut context = VMContextBuilder::new()
+            .current_account_id(factory_id())
+            .predecessor_account_id(accounts(0))
+            .build();
+        context.input = vec![0; CODE_LEN];
+        testing_env!(context);
+        factory.set_escrow_code();
+        factory
+    }
+
+    // Escrow of 1 NEAR from accounts(1) to accounts(2), with 1 NEAR attached
+    // for storage
+    fn create(factory: &mut EscrowFactory, seed: u8) -> EscrowRecord {
+        create_as(factory, accounts(1), seed)
+    }
+
+    fn create_as(factory: &mut EscrowFactory, maker: AccountId, seed: u8) -> EscrowRecord {
+        as_account(maker.clone(), NearToken::from_near(2));
+        factory.create_escrow(
+            order_hash(seed),
+            accounts(2),
+            U128(NearToken::from_near(1).as_yoctonear()),
+            hex::encode(Sha256::digest([9u8; 32])),
+            3600,
+            None,
+        );
+        factory.get_escrow(maker, order_hash(seed)).unwrap()
+    }
+
+    #[test]
+    fn test_create_escrow_on_order_sub_account() {
+        let mut factory = factory();
+        let record = create(&mut factory, 1);
+
+        // As much of the key as fits next to "factory.near"
+        let expected: AccountId = format!("{}.factory.near", &escrow_key(1)[..51]).parse().unwrap();
+        assert_eq!(record.escrow_id, expected);
+        assert_eq!(factory.get_escrow_account_id(accounts(1), order_hash(1)), expected);
+        assert_eq!(record.terms.maker, accounts(1));
+        assert_eq!(record.terms.timelock, U64(START / 1_000_000_000 + 3600));
+        assert_eq!(record.escrow_storage_cost, factory.get_deployment_cost());
+        assert!(record.record_storage_cost.0 > 0);
+        assert_eq!(factory.get_escrows(0, 10).len(), 1);
+        assert!(get_logs().iter().any(|log| log.contains(r#""event":"escrow_created""#)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Escrow already exists")]
+    fn test_one_escrow_per_order() {
+        let mut factory = factory();
+        create(&mut factory, 1);
+        create(&mut factory, 1);
+    }
+
+    #[test]
+    fn test_order_hashes_are_per_maker() {
+        let mut factory = factory();
+        let mine = create(&mut factory, 1);
+
+        // Another maker reusing the order hash gets an escrow of its own
+        let theirs = create_as(&mut factory, accounts(3), 1);
+        assert_ne!(theirs.escrow_id, mine.escrow_id);
+        assert_eq!(factory.get_escrow(accounts(1), order_hash(1)).unwrap().escrow_id, mine.escrow_id);
+        assert_eq!(factory.get_escrows(0, 10).len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient deposit for the escrow and its storage")]
+    fn test_deposit_must_cover_storage() {
+        let mut factory = factory();
+        as_account(accounts(1), NearToken::from_near(1));
+        factory.create_escrow(
+            order_hash(1),
+            accounts(2),
+            U128(NearToken::from_near(1).as_yoctonear()),
+            hex::encode(Sha256::digest([9u8; 32])),
+            3600,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_closed_escrow_drops_record() {
+        let mut factory = factory();
+        let record = create(&mut factory, 1);
+
+        with_result(record.escrow_id.clone(), PromiseResult::Successful(vec![]));
+        assert!(factory.on_escrow_closed(escrow_key(1), EscrowOutcome::Withdrawn));
+        assert!(factory.get_escrow(accounts(1), order_hash(1)).is_none());
+        assert!(get_logs().iter().any(|log| log.contains(&format!(
+            r#""event":"escrow_closed","data":[{{"order_hash":"{}","escrow_id":"{}","outcome":"Withdrawn"}}]"#,
+            order_hash(1),
+            record.escrow_id
+        ))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not the escrow")]
+    fn test_only_escrow_closes_its_record() {
+        let mut factory = factory();
+        create(&mut factory, 1);
+        let other = create(&mut factory, 2);
+
+        with_result(other.escrow_id, PromiseResult::Successful(vec![]));
+        factory.on_escrow_closed(escrow_key(1), EscrowOutcome::Withdrawn);
+    }
+
+    #[test]
+    fn test_failed_deployment_refunds_maker() {
+        let mut factory = factory();
+        create(&mut factory, 1);
+
+        with_result(factory_id(), PromiseResult::Failed);
+        assert!(!factory.on_escrow_deployed(escrow_key(1)));
+        assert!(factory.get_escrow(accounts(1), order_hash(1)).is_none());
+        assert!(get_logs().iter().any(|log| log.contains(r#""outcome":"DeployFailed""#)));
+    }
+}
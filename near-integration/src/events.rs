@@ -0,0 +1,383 @@
+// NEP-297 events shared by the NEAR HTLC contracts.
+//
+// Every state transition logs one line of the form
+// EVENT_JSON:{"standard":"fusion_htlc","version":"1.0.0","event":"htlc_created","data":[{...}]}
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::Serialize;
+use near_sdk::{env, AccountId};
+
+#[cfg(feature = "fusion-plus")]
+use crate::fusion_plus::{FusionHTLC, PartialFill};
+use crate::htlc_core::{EscrowOutcome, HashAlgorithm};
+
+pub const EVENT_STANDARD: &str = "fusion_htlc";
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a, T: Serialize> {
+    standard: &'static str,
+    version: &'static str,
+    event: &'static str,
+    data: [&'a T; 1],
+}
+
+pub trait Event: Serialize + Sized {
+    const NAME: &'static str;
+
+    fn emit(&self) {
+        let log = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_STANDARD_VERSION,
+            event: Self::NAME,
+            data: [self],
+        };
+        env::log_str(&format!("EVENT_JSON:{}", serde_json::to_string(&log).unwrap()));
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HtlcCreated {
+    pub htlc_id: String,
+    pub sender: AccountId,
+    pub receiver: Option<AccountId>, // None for an open order
+    pub token_id: Option<AccountId>,
+    pub amount: U128,
+    pub hashlock: String,
+    pub hash_algorithm: HashAlgorithm,
+    pub timelock: U64,
+    pub allow_partial_fills: bool,
+    pub parts_count: u32,
+}
+
+impl Event for HtlcCreated {
+    const NAME: &'static str = "htlc_created";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HtlcWithdrawn {
+    pub htlc_id: String,
+    pub secret: String,
+    pub withdrawn_by: AccountId,
+    pub amount: U128,
+}
+
+impl Event for HtlcWithdrawn {
+    const NAME: &'static str = "htlc_withdrawn";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HtlcRefunded {
+    pub htlc_id: String,
+    pub refunded_to: AccountId,
+    pub amount: U128,
+}
+
+impl Event for HtlcRefunded {
+    const NAME: &'static str = "htlc_refunded";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FillCreated {
+    pub fill_id: String,
+    pub htlc_id: String,
+    pub filler: AccountId,
+    pub amount: U128,
+    pub secret_index: Option<u32>,
+    pub secret_hash: String,
+}
+
+impl Event for FillCreated {
+    const NAME: &'static str = "fill_created";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FillWithdrawn {
+    pub fill_id: String,
+    pub htlc_id: String,
+    pub secret: String,
+    pub withdrawn_by: AccountId,
+    pub amount: U128,
+}
+
+impl Event for FillWithdrawn {
+    const NAME: &'static str = "fill_withdrawn";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FillRefunded {
+    pub fill_id: String,
+    pub htlc_id: String,
+    pub refunded_to: AccountId,
+    pub amount: U128,
+}
+
+impl Event for FillRefunded {
+    const NAME: &'static str = "fill_refunded";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositCreated {
+    pub deposit_id: String,
+    pub htlc_id: String,
+    pub depositor: AccountId,
+    pub amount: U128,
+}
+
+impl Event for DepositCreated {
+    const NAME: &'static str = "deposit_created";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositClaimed {
+    pub deposit_id: String,
+    pub htlc_id: String,
+    pub claimed_by: AccountId,
+    pub amount: U128,
+}
+
+impl Event for DepositClaimed {
+    const NAME: &'static str = "deposit_claimed";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PayoutFailed {
+    pub htlc_id: String,
+    pub fill_id: Option<String>,
+}
+
+impl Event for PayoutFailed {
+    const NAME: &'static str = "payout_failed";
+}
+
+// A safety deposit payout that failed; the deposit can be claimed again
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositPayoutFailed {
+    pub deposit_id: String,
+    pub htlc_id: String,
+}
+
+impl Event for DepositPayoutFailed {
+    const NAME: &'static str = "deposit_payout_failed";
+}
+
+// An item of a batch call that was skipped; `index` is its position in the
+// batch and `htlc_id` is None for creations
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchItemFailed {
+    pub index: u32,
+    pub htlc_id: Option<String>,
+    pub reason: String,
+}
+
+impl Event for BatchItemFailed {
+    const NAME: &'static str = "batch_item_failed";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OwnershipTransferred {
+    pub previous_owner_id: AccountId,
+    pub new_owner_id: AccountId,
+}
+
+impl Event for OwnershipTransferred {
+    const NAME: &'static str = "ownership_transferred";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseChanged {
+    pub paused: bool,
+}
+
+impl Event for PauseChanged {
+    const NAME: &'static str = "pause_changed";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ProtocolFeeChanged {
+    pub protocol_fee_bps: u16,
+}
+
+impl Event for ProtocolFeeChanged {
+    const NAME: &'static str = "protocol_fee_changed";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeesWithdrawn {
+    pub token_id: Option<AccountId>,
+    pub receiver_id: AccountId,
+    pub amount: U128,
+}
+
+impl Event for FeesWithdrawn {
+    const NAME: &'static str = "fees_withdrawn";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RetentionPeriodChanged {
+    pub retention_seconds: u64,
+}
+
+impl Event for RetentionPeriodChanged {
+    const NAME: &'static str = "retention_period_changed";
+}
+
+// The full record of a settled HTLC removed from state, for archives
+#[cfg(feature = "fusion-plus")]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HtlcPruned {
+    pub htlc: FusionHTLC,
+    pub fills: Vec<PartialFill>,
+}
+
+#[cfg(feature = "fusion-plus")]
+impl Event for HtlcPruned {
+    const NAME: &'static str = "htlc_pruned";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HtlcSwept {
+    pub htlc_id: String,
+    pub keeper: AccountId,
+    pub reward: U128,
+}
+
+impl Event for HtlcSwept {
+    const NAME: &'static str = "htlc_swept";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OrderTaken {
+    pub htlc_id: String,
+    pub taker: AccountId,
+    pub taken_until: U64, // Exclusivity ends, in seconds
+}
+
+impl Event for OrderTaken {
+    const NAME: &'static str = "order_taken";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OrderCancelled {
+    pub htlc_id: String,
+    pub refunded_to: AccountId,
+    pub amount: U128, // The unfilled remainder
+}
+
+impl Event for OrderCancelled {
+    const NAME: &'static str = "order_cancelled";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ResolverRegistered {
+    pub account_id: AccountId,
+}
+
+impl Event for ResolverRegistered {
+    const NAME: &'static str = "resolver_registered";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ResolverStatusChanged {
+    pub account_id: AccountId,
+    pub whitelisted: bool,
+}
+
+impl Event for ResolverStatusChanged {
+    const NAME: &'static str = "resolver_status_changed";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ResolverStakeChanged {
+    pub account_id: AccountId,
+    pub stake: U128,
+}
+
+impl Event for ResolverStakeChanged {
+    const NAME: &'static str = "resolver_stake_changed";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ResolverSlashed {
+    pub account_id: AccountId,
+    pub htlc_id: String,
+    pub maker: AccountId,
+    pub amount: U128,
+}
+
+impl Event for ResolverSlashed {
+    const NAME: &'static str = "resolver_slashed";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EscrowCreated {
+    pub order_hash: String,
+    pub escrow_id: AccountId,
+    pub maker: AccountId,
+    pub taker: AccountId,
+    pub amount: U128,
+    pub hashlock: String,
+    pub timelock: U64,
+}
+
+impl Event for EscrowCreated {
+    const NAME: &'static str = "escrow_created";
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EscrowClosed {
+    pub order_hash: String,
+    pub escrow_id: AccountId,
+    pub outcome: EscrowOutcome,
+}
+
+impl Event for EscrowClosed {
+    const NAME: &'static str = "escrow_closed";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs};
+
+    #[test]
+    fn test_event_log_format() {
+        HtlcRefunded {
+            htlc_id: "htlc_1".to_string(),
+            refunded_to: accounts(1),
+            amount: U128(5),
+        }
+        .emit();
+
+        assert_eq!(
+            get_logs(),
+            vec![r#"EVENT_JSON:{"standard":"fusion_htlc","version":"1.0.0","event":"htlc_refunded","data":[{"htlc_id":"htlc_1","refunded_to":"bob","amount":"5"}]}"#]
+        );
+    }
+}
@@ -3,16 +3,12 @@ use near_sdk::collections::{LookupMap, UnorderedMap};
 use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    env, near_bindgen, require, AccountId, BorshStorageKey, Gas, NearToken, PanicOnDefault,
-    Promise,
+    env, is_promise_success, near_bindgen, require, AccountId, BorshStorageKey, Gas, NearToken,
+    PanicOnDefault, Promise,
 };
-use sha2::{Digest, Sha256};
 
-// Constants
-const MIN_TIMELOCK_DURATION: u64 = 3600; // 1 hour in seconds
-const MAX_TIMELOCK_DURATION: u64 = 2592000; // 30 days in seconds
-const HASH_LENGTH: usize = 32; // SHA-256 hash length
-const TGAS: u64 = 1_000_000_000_000;
+use crate::events::{self, Event};
+use crate::htlc_core::{self, HashAlgorithm};
 
 // Storage keys
 #[derive(BorshSerialize, BorshStorageKey)]
@@ -36,6 +32,7 @@ pub struct HTLC {
     pub secret: Option<String>, // Revealed secret
     pub withdrawn: bool,
     pub refunded: bool,
+    pub settled_by: Option<AccountId>, // Who withdrew or refunded
     pub created_at: U64,
 }
 
@@ -49,6 +46,14 @@ pub struct SafetyDeposit {
     pub created_at: U64,
 }
 
+// An HTLC with the safety deposits still held for it
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HashlockEscrows {
+    pub htlc: HTLC,
+    pub safety_deposits: Vec<SafetyDeposit>,
+}
+
 // Note: In production, would use proper NEP-141 token standard interfaces
 
 // Main contract
@@ -62,35 +67,6 @@ pub struct FusionHTLCContract {
     next_deposit_id: u64,
 }
 
-// Events
-#[derive(Serialize, Deserialize)]
-#[serde(crate = "near_sdk::serde")]
-pub struct HTLCCreatedEvent {
-    pub htlc_id: String,
-    pub sender: AccountId,
-    pub receiver: AccountId,
-    pub amount: U128,
-    pub hashlock: String,
-    pub timelock: U64,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(crate = "near_sdk::serde")]
-pub struct HTLCWithdrawnEvent {
-    pub htlc_id: String,
-    pub secret: String,
-    pub withdrawn_by: AccountId,
-    pub withdrawn_at: U64,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(crate = "near_sdk::serde")]
-pub struct HTLCRefundedEvent {
-    pub htlc_id: String,
-    pub refunded_to: AccountId,
-    pub refunded_at: U64,
-}
-
 // Implementation
 #[near_bindgen]
 impl FusionHTLCContract {
@@ -105,7 +81,7 @@ impl FusionHTLCContract {
         }
     }
 
-    // Create HTLC for NEAR tokens
+    // Create HTLC for NEAR tokens. The hashlock is a SHA-256 hash.
     #[payable]
     pub fn create_htlc(
         &mut self,
@@ -115,22 +91,13 @@ impl FusionHTLCContract {
     ) -> String {
         let sender = env::predecessor_account_id();
         let amount = env::attached_deposit();
-        let current_time = env::block_timestamp() / 1_000_000_000; // Convert to seconds
+        let current_time = htlc_core::now_seconds();
 
         // Validate inputs
         require!(amount > NearToken::from_yoctonear(0), "Amount must be greater than 0");
-        require!(
-            hashlock.len() == HASH_LENGTH * 2,
-            "Invalid hashlock length"
-        );
-        require!(
-            timelock_seconds >= MIN_TIMELOCK_DURATION,
-            "Timelock too short"
-        );
-        require!(
-            timelock_seconds <= MAX_TIMELOCK_DURATION,
-            "Timelock too long"
-        );
+        htlc_core::assert_valid_hashlock(&hashlock);
+        require!(!self.secret_to_htlc.contains_key(&hashlock), "Hashlock already used");
+        htlc_core::assert_valid_timelock(timelock_seconds);
 
         let timelock = current_time + timelock_seconds;
         let htlc_id = format!("htlc_{}", self.next_htlc_id);
@@ -147,6 +114,7 @@ impl FusionHTLCContract {
             secret: None,
             withdrawn: false,
             refunded: false,
+            settled_by: None,
             created_at: U64(current_time),
         };
 
@@ -154,17 +122,19 @@ impl FusionHTLCContract {
         self.secret_to_htlc.insert(&hashlock, &htlc_id);
 
         // Emit event
-        env::log_str(
-            &serde_json::to_string(&HTLCCreatedEvent {
-                htlc_id: htlc_id.clone(),
-                sender,
-                receiver,
-                amount: U128(amount.as_yoctonear()),
-                hashlock,
-                timelock: U64(timelock),
-            })
-            .unwrap(),
-        );
+        events::HtlcCreated {
+            htlc_id: htlc_id.clone(),
+            sender,
+            receiver: Some(receiver),
+            token_id: None,
+            amount: U128(amount.as_yoctonear()),
+            hashlock,
+            hash_algorithm: HashAlgorithm::Sha256,
+            timelock: U64(timelock),
+            allow_partial_fills: false,
+            parts_count: 1,
+        }
+        .emit();
 
         htlc_id
     }
@@ -179,22 +149,13 @@ impl FusionHTLCContract {
         timelock_seconds: u64,
     ) -> String {
         let sender = env::predecessor_account_id();
-        let current_time = env::block_timestamp() / 1_000_000_000;
+        let current_time = htlc_core::now_seconds();
 
         // Validate inputs
         require!(amount.0 > 0, "Amount must be greater than 0");
-        require!(
-            hashlock.len() == HASH_LENGTH * 2,
-            "Invalid hashlock length"
-        );
-        require!(
-            timelock_seconds >= MIN_TIMELOCK_DURATION,
-            "Timelock too short"
-        );
-        require!(
-            timelock_seconds <= MAX_TIMELOCK_DURATION,
-            "Timelock too long"
-        );
+        htlc_core::assert_valid_hashlock(&hashlock);
+        require!(!self.secret_to_htlc.contains_key(&hashlock), "Hashlock already used");
+        htlc_core::assert_valid_timelock(timelock_seconds);
 
         let timelock = current_time + timelock_seconds;
         let htlc_id = format!("htlc_{}", self.next_htlc_id);
@@ -211,6 +172,7 @@ impl FusionHTLCContract {
             secret: None,
             withdrawn: false,
             refunded: false,
+            settled_by: None,
             created_at: U64(current_time),
         };
 
@@ -220,17 +182,19 @@ impl FusionHTLCContract {
         // Note: Actual token transfer would be handled via ft_transfer_call
         // The tokens should be transferred to this contract before calling this method
 
-        env::log_str(
-            &serde_json::to_string(&HTLCCreatedEvent {
-                htlc_id: htlc_id.clone(),
-                sender,
-                receiver,
-                amount,
-                hashlock,
-                timelock: U64(timelock),
-            })
-            .unwrap(),
-        );
+        events::HtlcCreated {
+            htlc_id: htlc_id.clone(),
+            sender,
+            receiver: Some(receiver),
+            token_id: Some(token_id),
+            amount,
+            hashlock,
+            hash_algorithm: HashAlgorithm::Sha256,
+            timelock: U64(timelock),
+            allow_partial_fills: false,
+            parts_count: 1,
+        }
+        .emit();
 
         htlc_id
     }
@@ -238,42 +202,35 @@ impl FusionHTLCContract {
     // Withdraw funds by providing the correct secret
     pub fn withdraw(&mut self, htlc_id: String, secret: String) -> Promise {
         let htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
-        let current_time = env::block_timestamp() / 1_000_000_000;
         let withdrawer = env::predecessor_account_id();
 
         // Validate state
         require!(!htlc.withdrawn, "Already withdrawn");
         require!(!htlc.refunded, "Already refunded");
-        require!(current_time < htlc.timelock.0, "HTLC expired");
+        require!(!htlc_core::is_expired(htlc.timelock.0), "HTLC expired");
         require!(withdrawer == htlc.receiver, "Not the receiver");
 
         // Verify secret
-        let secret_bytes = hex::decode(&secret).expect("Invalid hex secret");
-        let mut hasher = Sha256::new();
-        hasher.update(&secret_bytes);
-        let secret_hash = hex::encode(hasher.finalize());
-
-        require!(secret_hash == htlc.hashlock, "Invalid secret");
+        htlc_core::verify_secret(&secret, &htlc.hashlock, HashAlgorithm::Sha256);
 
         // Update state
         let mut updated_htlc = htlc.clone();
         updated_htlc.withdrawn = true;
         updated_htlc.secret = Some(secret.clone());
+        updated_htlc.settled_by = Some(withdrawer.clone());
         self.htlcs.insert(&htlc_id, &updated_htlc);
 
         // Emit event
-        env::log_str(
-            &serde_json::to_string(&HTLCWithdrawnEvent {
-                htlc_id: htlc_id.clone(),
-                secret,
-                withdrawn_by: withdrawer.clone(),
-                withdrawn_at: U64(current_time),
-            })
-            .unwrap(),
-        );
+        events::HtlcWithdrawn {
+            htlc_id: htlc_id.clone(),
+            secret,
+            withdrawn_by: withdrawer.clone(),
+            amount: htlc.amount,
+        }
+        .emit();
 
         // Transfer funds
-        if let Some(token_id) = htlc.token_id {
+        let payout = if let Some(token_id) = htlc.token_id {
             // NEP-141 token transfer
             Promise::new(token_id).function_call(
                 "ft_transfer".to_string(),
@@ -289,38 +246,41 @@ impl FusionHTLCContract {
         } else {
             // NEAR transfer
             Promise::new(withdrawer).transfer(NearToken::from_yoctonear(htlc.amount.0))
-        }
+        };
+        payout.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(Gas::from_tgas(5))
+                .resolve_payout(htlc_id),
+        )
     }
 
     // Refund funds after timeout
     pub fn refund(&mut self, htlc_id: String) -> Promise {
         let htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
-        let current_time = env::block_timestamp() / 1_000_000_000;
         let refunder = env::predecessor_account_id();
 
         // Validate state
         require!(!htlc.withdrawn, "Already withdrawn");
         require!(!htlc.refunded, "Already refunded");
-        require!(current_time >= htlc.timelock.0, "HTLC not expired");
+        require!(htlc_core::is_expired(htlc.timelock.0), "HTLC not expired");
         require!(refunder == htlc.sender, "Not the sender");
 
         // Update state
         let mut updated_htlc = htlc.clone();
         updated_htlc.refunded = true;
+        updated_htlc.settled_by = Some(refunder.clone());
         self.htlcs.insert(&htlc_id, &updated_htlc);
 
         // Emit event
-        env::log_str(
-            &serde_json::to_string(&HTLCRefundedEvent {
-                htlc_id: htlc_id.clone(),
-                refunded_to: refunder.clone(),
-                refunded_at: U64(current_time),
-            })
-            .unwrap(),
-        );
+        events::HtlcRefunded {
+            htlc_id: htlc_id.clone(),
+            refunded_to: refunder.clone(),
+            amount: htlc.amount,
+        }
+        .emit();
 
         // Transfer funds back
-        if let Some(token_id) = htlc.token_id {
+        let payout = if let Some(token_id) = htlc.token_id {
             // NEP-141 token transfer
             Promise::new(token_id).function_call(
                 "ft_transfer".to_string(),
@@ -336,20 +296,42 @@ impl FusionHTLCContract {
         } else {
             // NEAR transfer
             Promise::new(refunder).transfer(NearToken::from_yoctonear(htlc.amount.0))
+        };
+        payout.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(Gas::from_tgas(5))
+                .resolve_payout(htlc_id),
+        )
+    }
+
+    // Callback for withdraw and refund payouts. Reopens the HTLC if the
+    // transfer failed so the funds stay recoverable.
+    #[private]
+    pub fn resolve_payout(&mut self, htlc_id: String) -> bool {
+        if is_promise_success() {
+            return true;
         }
+
+        let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
+        htlc.withdrawn = false;
+        htlc.refunded = false;
+        htlc.secret = None;
+        htlc.settled_by = None;
+        self.htlcs.insert(&htlc_id, &htlc);
+
+        events::PayoutFailed { htlc_id, fill_id: None }.emit();
+        false
     }
 
-    // Create safety deposit for resolver
+    // Create safety deposit for resolver, locked until the HTLC settles
     #[payable]
     pub fn create_safety_deposit(&mut self, htlc_id: String) -> String {
         let resolver = env::predecessor_account_id();
         let amount = env::attached_deposit();
-        let current_time = env::block_timestamp() / 1_000_000_000;
+        let current_time = htlc_core::now_seconds();
 
-        require!(
-            self.htlcs.get(&htlc_id).is_some(),
-            "HTLC does not exist"
-        );
+        let htlc = self.htlcs.get(&htlc_id).expect("HTLC does not exist");
+        require!(!htlc.withdrawn && !htlc.refunded, "HTLC already settled");
         require!(amount > NearToken::from_yoctonear(0), "Deposit amount must be greater than 0");
 
         let deposit_id = format!("deposit_{}", self.next_deposit_id);
@@ -357,32 +339,78 @@ impl FusionHTLCContract {
 
         let safety_deposit = SafetyDeposit {
             id: deposit_id.clone(),
-            htlc_id,
-            resolver,
+            htlc_id: htlc_id.clone(),
+            resolver: resolver.clone(),
             amount: U128(amount.as_yoctonear()),
             created_at: U64(current_time),
         };
 
         self.safety_deposits.insert(&deposit_id, &safety_deposit);
 
+        events::DepositCreated {
+            deposit_id: deposit_id.clone(),
+            htlc_id,
+            depositor: resolver,
+            amount: safety_deposit.amount,
+        }
+        .emit();
+
         deposit_id
     }
 
-    // Claim safety deposit
+    // Claim safety deposit once the HTLC settled. It goes back to its
+    // resolver, unless the HTLC was refunded and the resolver is the receiver
+    // that let the timelock pass; then the sender gets it.
     pub fn claim_safety_deposit(&mut self, deposit_id: String) -> Promise {
         let deposit = self
             .safety_deposits
             .get(&deposit_id)
             .expect("Deposit not found");
+        let htlc = self.htlcs.get(&deposit.htlc_id).expect("HTLC not found");
         let claimer = env::predecessor_account_id();
 
-        require!(claimer == deposit.resolver, "Not the resolver");
+        require!(htlc.withdrawn || htlc.refunded, "HTLC not settled");
+        let recipient = if htlc.refunded && deposit.resolver == htlc.receiver {
+            htlc.sender
+        } else {
+            deposit.resolver.clone()
+        };
+        require!(recipient == claimer, "Not entitled to the deposit");
 
         // Remove deposit
         self.safety_deposits.remove(&deposit_id);
 
-        // Transfer deposit back
-        Promise::new(claimer).transfer(NearToken::from_yoctonear(deposit.amount.0))
+        events::DepositClaimed {
+            deposit_id,
+            htlc_id: deposit.htlc_id.clone(),
+            claimed_by: claimer.clone(),
+            amount: deposit.amount,
+        }
+        .emit();
+
+        // Transfer deposit, put back if the transfer fails
+        Promise::new(claimer)
+            .transfer(NearToken::from_yoctonear(deposit.amount.0))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas::from_tgas(5))
+                    .resolve_deposit_payout(deposit),
+            )
+    }
+
+    #[private]
+    pub fn resolve_deposit_payout(&mut self, deposit: SafetyDeposit) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+
+        self.safety_deposits.insert(&deposit.id, &deposit);
+        events::DepositPayoutFailed {
+            deposit_id: deposit.id,
+            htlc_id: deposit.htlc_id,
+        }
+        .emit();
+        false
     }
 
     // View functions
@@ -398,10 +426,17 @@ impl FusionHTLCContract {
         }
     }
 
+    // Everything revealing the hashlock's secret settles. Scans every safety
+    // deposit, as they are not indexed per HTLC.
+    pub fn get_escrows_by_hashlock(&self, hashlock: String) -> Option<HashlockEscrows> {
+        let htlc = self.get_htlc_by_hashlock(hashlock)?;
+        let safety_deposits = self.safety_deposits.values().filter(|deposit| deposit.htlc_id == htlc.id).collect();
+        Some(HashlockEscrows { htlc, safety_deposits })
+    }
+
     pub fn can_withdraw(&self, htlc_id: String) -> bool {
         if let Some(htlc) = self.htlcs.get(&htlc_id) {
-            let current_time = env::block_timestamp() / 1_000_000_000;
-            !htlc.withdrawn && !htlc.refunded && current_time < htlc.timelock.0
+            !htlc.withdrawn && !htlc.refunded && !htlc_core::is_expired(htlc.timelock.0)
         } else {
             false
         }
@@ -409,8 +444,7 @@ impl FusionHTLCContract {
 
     pub fn can_refund(&self, htlc_id: String) -> bool {
         if let Some(htlc) = self.htlcs.get(&htlc_id) {
-            let current_time = env::block_timestamp() / 1_000_000_000;
-            !htlc.withdrawn && !htlc.refunded && current_time >= htlc.timelock.0
+            !htlc.withdrawn && !htlc.refunded && htlc_core::is_expired(htlc.timelock.0)
         } else {
             false
         }
@@ -472,7 +506,8 @@ impl FusionHTLCContract {
 mod tests {
     use super::*;
     use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::{testing_env, VMContext};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig, VMContext};
+    use sha2::{Digest, Sha256};
 
     fn get_context(predecessor: AccountId) -> VMContext {
         VMContextBuilder::new()
@@ -531,7 +566,7 @@ mod tests {
 
         // Fast forward time
         let mut context = get_context(accounts(1));
-        context.block_timestamp = 2 * 3600 * 1_000_000_000; // 2 hours later
+        context.block_timestamp += 2 * 3600 * 1_000_000_000; // 2 hours later
         testing_env!(context);
 
         // Refund
@@ -541,4 +576,159 @@ mod tests {
         let htlc = contract.get_htlc(htlc_id).unwrap();
         assert!(htlc.refunded);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_failed_withdraw_payout_reopens_htlc() {
+        let mut context = get_context(accounts(1));
+        context.attached_deposit = NearToken::from_near(1);
+        testing_env!(context);
+
+        let mut contract = FusionHTLCContract::new();
+        let htlc_id = contract.create_htlc(accounts(2), hex::encode(Sha256::digest([7u8; 32])), 3600);
+
+        testing_env!(get_context(accounts(2)));
+        contract.withdraw(htlc_id.clone(), hex::encode([7u8; 32]));
+
+        // The receiver account was deleted before the transfer landed
+        testing_env!(
+            get_context(accounts(0)),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        assert!(!contract.resolve_payout(htlc_id.clone()));
+
+        let htlc = contract.get_htlc(htlc_id.clone()).unwrap();
+        assert!(!htlc.withdrawn);
+        assert!(htlc.secret.is_none());
+        assert!(contract.can_withdraw(htlc_id));
+    }
+
+    #[test]
+    #[should_panic(expected = "HTLC not settled")]
+    fn test_safety_deposit_locked_until_settlement() {
+        let mut context = get_context(accounts(1));
+        context.attached_deposit = NearToken::from_near(1);
+        testing_env!(context);
+
+        let mut contract = FusionHTLCContract::new();
+        let htlc_id = contract.create_htlc(accounts(2), hex::encode([0u8; 32]), 3600);
+
+        let mut context = get_context(accounts(3));
+        context.attached_deposit = NearToken::from_millinear(100);
+        testing_env!(context);
+        let deposit_id = contract.create_safety_deposit(htlc_id);
+
+        contract.claim_safety_deposit(deposit_id);
+    }
+
+    #[test]
+    fn test_lapsed_receiver_deposit_goes_to_sender() {
+        let mut context = get_context(accounts(1));
+        context.attached_deposit = NearToken::from_near(1);
+        testing_env!(context);
+
+        let mut contract = FusionHTLCContract::new();
+        let htlc_id = contract.create_htlc(accounts(2), hex::encode([0u8; 32]), 3600);
+
+        // The receiver and another resolver back the swap
+        let mut deposit_ids = vec![];
+        for resolver in [accounts(2), accounts(3)] {
+            let mut context = get_context(resolver);
+            context.attached_deposit = NearToken::from_millinear(100);
+            testing_env!(context);
+            deposit_ids.push(contract.create_safety_deposit(htlc_id.clone()));
+        }
+
+        let mut context = get_context(accounts(1));
+        context.block_timestamp += 2 * 3600 * 1_000_000_000;
+        testing_env!(context.clone());
+        contract.refund(htlc_id);
+
+        // The receiver let the timelock pass, the other resolver did not
+        contract.claim_safety_deposit(deposit_ids[0].clone());
+        context.predecessor_account_id = accounts(3);
+        testing_env!(context);
+        contract.claim_safety_deposit(deposit_ids[1].clone());
+
+        // A failed transfer puts the deposit back
+        testing_env!(
+            get_context(accounts(0)),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let deposit = SafetyDeposit {
+            id: deposit_ids[1].clone(),
+            htlc_id: "htlc_1".to_string(),
+            resolver: accounts(3),
+            amount: U128(NearToken::from_millinear(100).as_yoctonear()),
+            created_at: U64(1_000_000_000),
+        };
+        assert!(!contract.resolve_deposit_payout(deposit));
+        assert!(contract.get_safety_deposit(deposit_ids[1].clone()).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "Not entitled to the deposit")]
+    fn test_lapsed_receiver_cannot_reclaim_deposit() {
+        let mut context = get_context(accounts(1));
+        context.attached_deposit = NearToken::from_near(1);
+        testing_env!(context);
+
+        let mut contract = FusionHTLCContract::new();
+        let htlc_id = contract.create_htlc(accounts(2), hex::encode([0u8; 32]), 3600);
+
+        let mut context = get_context(accounts(2));
+        context.attached_deposit = NearToken::from_millinear(100);
+        testing_env!(context);
+        let deposit_id = contract.create_safety_deposit(htlc_id.clone());
+
+        let mut context = get_context(accounts(1));
+        context.block_timestamp += 2 * 3600 * 1_000_000_000;
+        testing_env!(context.clone());
+        contract.refund(htlc_id);
+
+        context.predecessor_account_id = accounts(2);
+        testing_env!(context);
+        contract.claim_safety_deposit(deposit_id);
+    }
+
+    #[test]
+    fn test_escrows_by_hashlock() {
+        let mut context = get_context(accounts(1));
+        context.attached_deposit = NearToken::from_near(1);
+        testing_env!(context);
+
+        let mut contract = FusionHTLCContract::new();
+        let hashlock = hex::encode(Sha256::digest([7u8; 32]));
+        contract.create_htlc(accounts(2), hex::encode([0u8; 32]), 3600);
+        let htlc_id = contract.create_htlc(accounts(2), hashlock.clone(), 3600);
+
+        let mut context = get_context(accounts(3));
+        context.attached_deposit = NearToken::from_millinear(100);
+        testing_env!(context);
+        let deposit_id = contract.create_safety_deposit(htlc_id.clone());
+
+        let escrows = contract.get_escrows_by_hashlock(hashlock).unwrap();
+        assert_eq!(escrows.htlc.id, htlc_id);
+        assert_eq!(escrows.safety_deposits.len(), 1);
+        assert_eq!(escrows.safety_deposits[0].id, deposit_id);
+        assert!(contract.get_escrows_by_hashlock(hex::encode([9u8; 32])).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Hashlock already used")]
+    fn test_hashlock_reuse_rejected() {
+        let mut context = get_context(accounts(1));
+        context.attached_deposit = NearToken::from_near(1);
+        testing_env!(context);
+
+        let mut contract = FusionHTLCContract::new();
+        let hashlock = hex::encode(Sha256::digest([7u8; 32]));
+        contract.create_htlc(accounts(2), hashlock.clone(), 3600);
+        contract.create_htlc(accounts(3), hashlock, 3600);
+    }
+}
@@ -6,10 +6,15 @@ use near_sdk::{
     env, near_bindgen, require, AccountId, BorshStorageKey, NearToken, PanicOnDefault,
     Promise,
 };
-use sha2::{Digest, Sha256};
+
+use crate::events::{self, Event};
+use crate::htlc_core::{self, HashAlgorithm};
+use crate::merkle;
 
 // This is an enhanced version with partial fills support
 
+const MAX_FILLS: u32 = 256;
+
 // Safety deposit structure (from base contract)
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
@@ -29,6 +34,7 @@ enum StorageKey {
     SecretToHTLC,
     PartialFills,
     FillerToHTLC,
+    FillerFills { filler_hash: Vec<u8> },
 }
 
 // Partial fill structure
@@ -39,9 +45,11 @@ pub struct PartialFill {
     pub htlc_id: String,
     pub filler: AccountId,
     pub amount: U128,
-    pub secret_hash: String,
+    pub secret_index: u32,
+    pub secret_hash: String, // Leaf `secret_index` of the HTLC's Merkle root
     pub secret: Option<String>,
     pub claimed: bool,
+    pub timelock: U64, // Withdrawable until, refundable from
     pub created_at: U64,
 }
 
@@ -56,32 +64,31 @@ pub struct HTLCPartial {
     pub total_amount: U128,
     pub remaining_amount: U128,
     pub min_fill_amount: U128,
-    pub hashlock: String,
+    pub hashlock: String, // Merkle root over one secret hash per fill
     pub timelock: U64,
     pub allow_partial_fills: bool,
+    pub parts_count: u32,           // Most fills the HTLC takes, one secret each
+    pub next_secret_index: u32,     // Secret the next fill reveals
+    pub fill_timelock_seconds: u64, // How long each fill stays withdrawable
     pub fills: Vec<String>, // Fill IDs
     pub withdrawn: bool,
     pub refunded: bool,
     pub created_at: U64,
 }
 
-// Events for partial fills
-#[derive(Serialize, Deserialize)]
+// Parameters of `create_htlc_partial`
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
-pub struct PartialFillCreatedEvent {
-    pub fill_id: String,
-    pub htlc_id: String,
-    pub filler: AccountId,
-    pub amount: U128,
-    pub secret_hash: String,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(crate = "near_sdk::serde")]
-pub struct PartialFillClaimedEvent {
-    pub fill_id: String,
-    pub secret: String,
-    pub claimed_by: AccountId,
+pub struct CreateHTLCPartialArgs {
+    pub receiver: AccountId,
+    pub hashlock: String,
+    pub timelock_seconds: u64,
+    pub allow_partial_fills: bool,
+    pub min_fill_amount: U128,
+    #[serde(default)]
+    pub parts_count: Option<u32>,
+    #[serde(default)]
+    pub fill_timelock_seconds: Option<u64>, // Defaults to `timelock_seconds`
 }
 
 // Enhanced contract with partial fills
@@ -114,30 +121,32 @@ impl FusionHTLCPartialContract {
         }
     }
 
-    // Create HTLC with partial fills support
+    // Create HTLC with partial fills support. `hashlock` is the Merkle root
+    // (see `merkle`) over the hashes of `parts_count` maker secrets, so every
+    // fill is bound to a secret of its own. Secrets and the tree are hashed
+    // with SHA-256 only. A fill can be withdrawn for
+    // `fill_timelock_seconds` after it is made, but never past the HTLC's
+    // timelock.
     #[payable]
-    pub fn create_htlc_partial(
-        &mut self,
-        receiver: AccountId,
-        hashlock: String,
-        timelock_seconds: u64,
-        allow_partial_fills: bool,
-        min_fill_amount: U128,
-    ) -> String {
+    pub fn create_htlc_partial(&mut self, args: CreateHTLCPartialArgs) -> String {
+        let CreateHTLCPartialArgs {
+            receiver,
+            hashlock,
+            timelock_seconds,
+            allow_partial_fills,
+            min_fill_amount,
+            parts_count,
+            fill_timelock_seconds,
+        } = args;
         let sender = env::predecessor_account_id();
         let amount = env::attached_deposit();
-        let current_time = env::block_timestamp() / 1_000_000_000;
+        let current_time = htlc_core::now_seconds();
 
         // Validate inputs
         require!(amount > NearToken::from_yoctonear(0), "Amount must be greater than 0");
-        require!(
-            hashlock.len() == 64, // SHA-256 hex string
-            "Invalid hashlock length"
-        );
-        require!(
-            timelock_seconds >= 3600 && timelock_seconds <= 2592000,
-            "Timelock must be between 1 hour and 30 days"
-        );
+        htlc_core::assert_valid_hashlock(&hashlock);
+        require!(!self.secret_to_htlc.contains_key(&hashlock), "Hashlock already used");
+        htlc_core::assert_valid_timelock(timelock_seconds);
 
         if allow_partial_fills {
             require!(
@@ -145,6 +154,19 @@ impl FusionHTLCPartialContract {
                 "Invalid minimum fill amount"
             );
         }
+        let parts_count = if allow_partial_fills { parts_count.expect("Missing parts count") } else { 1 };
+        require!(parts_count > 0 && parts_count <= MAX_FILLS, "Invalid parts count");
+        // Every fill takes at least `min_fill_amount` or all that is left, so
+        // this many secrets always last until the whole amount is filled
+        require!(
+            !allow_partial_fills || min_fill_amount.0.saturating_mul(parts_count as u128) >= amount.as_yoctonear(),
+            "Parts count cannot cover the amount"
+        );
+        let fill_timelock_seconds = fill_timelock_seconds.unwrap_or(timelock_seconds);
+        require!(
+            fill_timelock_seconds > 0 && fill_timelock_seconds <= timelock_seconds,
+            "Invalid fill timelock"
+        );
 
         let timelock = current_time + timelock_seconds;
         let htlc_id = format!("htlc_{}", self.next_htlc_id);
@@ -165,6 +187,9 @@ impl FusionHTLCPartialContract {
             hashlock: hashlock.clone(),
             timelock: U64(timelock),
             allow_partial_fills,
+            parts_count,
+            next_secret_index: 0,
+            fill_timelock_seconds,
             fills: Vec::new(),
             withdrawn: false,
             refunded: false,
@@ -174,24 +199,38 @@ impl FusionHTLCPartialContract {
         self.htlcs.insert(&htlc_id, &htlc);
         self.secret_to_htlc.insert(&hashlock, &htlc_id);
 
-        env::log_str(&format!(
-            "HTLCPartialCreated: {} {} {} {} {}",
-            htlc_id, sender, receiver, amount, allow_partial_fills
-        ));
+        events::HtlcCreated {
+            htlc_id: htlc_id.clone(),
+            sender,
+            receiver: Some(receiver),
+            token_id: None,
+            amount: htlc.total_amount,
+            hashlock,
+            hash_algorithm: HashAlgorithm::Sha256,
+            timelock: U64(timelock),
+            allow_partial_fills,
+            parts_count,
+        }
+        .emit();
 
         htlc_id
     }
 
-    // Create a partial fill for an HTLC
+    // Create a partial fill for an HTLC. The fill takes the next unused
+    // secret, `secret_index`; `secret_hash` and `merkle_proof` show that the
+    // maker committed to it.
     #[payable]
     pub fn create_partial_fill(
         &mut self,
         htlc_id: String,
         fill_amount: U128,
+        secret_index: u32,
+        secret_hash: String,
+        merkle_proof: Vec<String>,
     ) -> String {
         let filler = env::predecessor_account_id();
         let attached = env::attached_deposit();
-        let current_time = env::block_timestamp() / 1_000_000_000;
+        let current_time = htlc_core::now_seconds();
 
         let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
 
@@ -199,9 +238,9 @@ impl FusionHTLCPartialContract {
         require!(htlc.allow_partial_fills, "Partial fills not allowed");
         require!(!htlc.withdrawn, "HTLC already withdrawn");
         require!(!htlc.refunded, "HTLC already refunded");
-        require!(current_time < htlc.timelock.0, "HTLC expired");
+        require!(!htlc_core::is_expired(htlc.timelock.0), "HTLC expired");
         require!(
-            fill_amount.0 >= htlc.min_fill_amount.0,
+            fill_amount.0 >= htlc.min_fill_amount.0 || fill_amount.0 == htlc.remaining_amount.0,
             "Fill amount below minimum"
         );
         require!(
@@ -210,11 +249,13 @@ impl FusionHTLCPartialContract {
         );
         require!(attached >= NearToken::from_yoctonear(fill_amount.0), "Insufficient deposit");
 
-        // Generate unique secret hash for this fill
-        let fill_secret_data = format!("{}_{}_{}", htlc_id, filler, self.next_fill_id);
-        let mut hasher = Sha256::new();
-        hasher.update(fill_secret_data.as_bytes());
-        let fill_secret_hash = hex::encode(hasher.finalize());
+        // Each secret backs one fill only, refunded or not, so a revealed
+        // secret never unlocks another fill
+        require!(htlc.next_secret_index < htlc.parts_count, "No secrets left");
+        require!(secret_index == htlc.next_secret_index, "Invalid secret index");
+        let leaf = merkle::leaf(HashAlgorithm::Sha256, secret_index, &decode_hash(&secret_hash));
+        let proof: Vec<merkle::Hash> = merkle_proof.iter().map(|node| decode_hash(node)).collect();
+        require!(merkle::verify_proof(HashAlgorithm::Sha256, &decode_hash(&htlc.hashlock), leaf, &proof), "Invalid merkle proof");
 
         let fill_id = format!("fill_{}", self.next_fill_id);
         self.next_fill_id += 1;
@@ -224,14 +265,17 @@ impl FusionHTLCPartialContract {
             htlc_id: htlc_id.clone(),
             filler: filler.clone(),
             amount: fill_amount,
-            secret_hash: fill_secret_hash.clone(),
+            secret_index,
+            secret_hash: secret_hash.clone(),
             secret: None,
             claimed: false,
+            timelock: U64((current_time + htlc.fill_timelock_seconds).min(htlc.timelock.0)),
             created_at: U64(current_time),
         };
 
         // Update HTLC
         htlc.remaining_amount = U128(htlc.remaining_amount.0 - fill_amount.0);
+        htlc.next_secret_index += 1;
         htlc.fills.push(fill_id.clone());
         self.htlcs.insert(&htlc_id, &htlc);
 
@@ -242,7 +286,9 @@ impl FusionHTLCPartialContract {
         let mut filler_fills = self
             .filler_to_htlc
             .get(&filler)
-            .unwrap_or_else(|| Vector::new(b"f"));
+            .unwrap_or_else(|| {
+                Vector::new(StorageKey::FillerFills { filler_hash: env::sha256(filler.as_bytes()) })
+            });
         filler_fills.push(&fill_id);
         self.filler_to_htlc.insert(&filler, &filler_fills);
 
@@ -251,21 +297,20 @@ impl FusionHTLCPartialContract {
             Promise::new(filler.clone()).transfer(attached.saturating_sub(NearToken::from_yoctonear(fill_amount.0)));
         }
 
-        env::log_str(
-            &serde_json::to_string(&PartialFillCreatedEvent {
-                fill_id: fill_id.clone(),
-                htlc_id,
-                filler,
-                amount: fill_amount,
-                secret_hash: fill_secret_hash,
-            })
-            .unwrap(),
-        );
+        events::FillCreated {
+            fill_id: fill_id.clone(),
+            htlc_id,
+            filler,
+            amount: fill_amount,
+            secret_index: Some(secret_index),
+            secret_hash,
+        }
+        .emit();
 
         fill_id
     }
 
-    // Withdraw a specific partial fill
+    // Withdraw a specific partial fill by revealing its own secret
     pub fn withdraw_partial_fill(
         &mut self,
         fill_id: String,
@@ -277,19 +322,13 @@ impl FusionHTLCPartialContract {
             .expect("Fill not found");
         let htlc = self.htlcs.get(&fill.htlc_id).expect("HTLC not found");
         let withdrawer = env::predecessor_account_id();
-        let current_time = env::block_timestamp() / 1_000_000_000;
 
         // Validate
         require!(!fill.claimed, "Fill already claimed");
         require!(withdrawer == htlc.receiver, "Not the receiver");
-        require!(current_time < htlc.timelock.0, "HTLC expired");
+        require!(!htlc_core::is_expired(fill.timelock.0), "Fill expired");
 
-        // Verify main secret
-        let secret_bytes = hex::decode(&secret).expect("Invalid hex secret");
-        let mut hasher = Sha256::new();
-        hasher.update(&secret_bytes);
-        let secret_hash = hex::encode(hasher.finalize());
-        require!(secret_hash == htlc.hashlock, "Invalid secret");
+        htlc_core::verify_secret(&secret, &fill.secret_hash, HashAlgorithm::Sha256);
 
         // Update fill
         fill.claimed = true;
@@ -310,43 +349,49 @@ impl FusionHTLCPartialContract {
             self.htlcs.insert(&fill.htlc_id, &updated_htlc);
         }
 
-        env::log_str(
-            &serde_json::to_string(&PartialFillClaimedEvent {
-                fill_id,
-                secret,
-                claimed_by: withdrawer.clone(),
-            })
-            .unwrap(),
-        );
+        events::FillWithdrawn {
+            fill_id,
+            htlc_id: fill.htlc_id.clone(),
+            secret,
+            withdrawn_by: withdrawer.clone(),
+            amount: fill.amount,
+        }
+        .emit();
 
         // Transfer funds
         Promise::new(withdrawer).transfer(NearToken::from_yoctonear(fill.amount.0))
     }
 
-    // Refund unclaimed partial fills after timeout
+    // Refund an unclaimed partial fill once its own timelock passed. Its
+    // secret stays used, so its amount is not offered to fillers again.
     pub fn refund_partial_fill(&mut self, fill_id: String) -> Promise {
         let fill = self
             .partial_fills
             .get(&fill_id)
             .expect("Fill not found");
         let htlc = self.htlcs.get(&fill.htlc_id).expect("HTLC not found");
-        let current_time = env::block_timestamp() / 1_000_000_000;
         let refunder = env::predecessor_account_id();
 
         // Validate
         require!(!fill.claimed, "Fill already claimed");
-        require!(current_time >= htlc.timelock.0, "HTLC not expired");
+        require!(htlc_core::is_expired(fill.timelock.0), "Fill not expired");
         require!(refunder == fill.filler, "Not the filler");
 
         // Remove fill
         self.partial_fills.remove(&fill_id);
 
-        // Update HTLC remaining amount
         let mut updated_htlc = htlc.clone();
-        updated_htlc.remaining_amount = U128(updated_htlc.remaining_amount.0 + fill.amount.0);
         updated_htlc.fills.retain(|fid| fid != &fill_id);
         self.htlcs.insert(&fill.htlc_id, &updated_htlc);
 
+        events::FillRefunded {
+            fill_id,
+            htlc_id: fill.htlc_id.clone(),
+            refunded_to: refunder.clone(),
+            amount: fill.amount,
+        }
+        .emit();
+
         // Transfer refund
         Promise::new(refunder).transfer(NearToken::from_yoctonear(fill.amount.0))
     }
@@ -381,4 +426,11 @@ impl FusionHTLCPartialContract {
             Vec::new()
         }
     }
-}
\ No newline at end of file
+}
+
+fn decode_hash(value: &str) -> merkle::Hash {
+    hex::decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .expect("Invalid hash")
+}
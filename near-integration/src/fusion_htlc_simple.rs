@@ -1,20 +1,19 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::UnorderedMap;
+use near_sdk::collections::{LookupMap, UnorderedMap};
 use near_sdk::{
     env, near_bindgen, require, AccountId, BorshStorageKey, NearToken, PanicOnDefault,
     Promise,
 };
 use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
-// Constants
-const MIN_TIMELOCK: u64 = 3600; // 1 hour
-const MAX_TIMELOCK: u64 = 2592000; // 30 days
+use crate::events::{self, Event};
+use crate::htlc_core::{self, HashAlgorithm, WithdrawAuthorization};
 
 #[derive(BorshSerialize, BorshStorageKey)]
 enum StorageKey {
     HTLCs,
+    HashAlgorithms,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
@@ -24,6 +23,10 @@ pub struct HTLCInfo {
     pub receiver: AccountId,
     pub amount: U128,
     pub hashlock: String,
+    // Not part of the stored record, which predates it; HTLCs on anything
+    // but SHA-256 keep it under `StorageKey::HashAlgorithms`
+    #[borsh(skip)]
+    pub hash_algorithm: HashAlgorithm,
     pub timelock: U64,
     pub secret: Option<String>,
     pub withdrawn: bool,
@@ -47,19 +50,21 @@ impl FusionHTLC {
         }
     }
 
+    // `hash_algorithm` defaults to SHA-256
     #[payable]
     pub fn create_htlc(
         &mut self,
         receiver: AccountId,
         hashlock: String,
         timelock_seconds: u64,
+        hash_algorithm: Option<HashAlgorithm>,
     ) -> String {
         let amount = env::attached_deposit();
         require!(amount > NearToken::from_yoctonear(0), "Amount must be greater than 0");
-        require!(hashlock.len() == 64, "Invalid hashlock");
-        require!(timelock_seconds >= MIN_TIMELOCK && timelock_seconds <= MAX_TIMELOCK, "Invalid timelock");
+        htlc_core::assert_valid_hashlock(&hashlock);
+        htlc_core::assert_valid_timelock(timelock_seconds);
 
-        let current_time = env::block_timestamp() / 1_000_000_000;
+        let current_time = htlc_core::now_seconds();
         let htlc_id = format!("htlc_{}", self.next_id);
         self.next_id += 1;
 
@@ -68,6 +73,7 @@ impl FusionHTLC {
             receiver,
             amount: U128(amount.as_yoctonear()),
             hashlock,
+            hash_algorithm: hash_algorithm.unwrap_or_default(),
             timelock: U64(current_time + timelock_seconds),
             secret: None,
             withdrawn: false,
@@ -75,46 +81,108 @@ impl FusionHTLC {
         };
 
         self.htlcs.insert(&htlc_id, &htlc);
-        env::log_str(&format!("HTLC created: {}", htlc_id));
+        if htlc.hash_algorithm != HashAlgorithm::Sha256 {
+            Self::hash_algorithms().insert(&htlc_id, &htlc.hash_algorithm);
+        }
+        events::HtlcCreated {
+            htlc_id: htlc_id.clone(),
+            sender: htlc.sender,
+            receiver: Some(htlc.receiver),
+            token_id: None,
+            amount: htlc.amount,
+            hashlock: htlc.hashlock,
+            hash_algorithm: htlc.hash_algorithm,
+            timelock: htlc.timelock,
+            allow_partial_fills: false,
+            parts_count: 1,
+        }.emit();
         
         htlc_id
     }
 
     pub fn withdraw(&mut self, htlc_id: String, secret: String) -> Promise {
-        let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
-        let current_time = env::block_timestamp() / 1_000_000_000;
+        let mut htlc = self.load_htlc(&htlc_id).expect("HTLC not found");
         
         require!(!htlc.withdrawn && !htlc.refunded, "Already processed");
         require!(env::predecessor_account_id() == htlc.receiver, "Not receiver");
-        require!(current_time < htlc.timelock.0, "Expired");
+        require!(!htlc_core::is_expired(htlc.timelock.0), "Expired");
         
         // Verify secret
-        let secret_bytes = hex::decode(&secret).expect("Invalid hex");
-        let mut hasher = Sha256::new();
-        hasher.update(&secret_bytes);
-        let hash = hex::encode(hasher.finalize());
-        require!(hash == htlc.hashlock, "Invalid secret");
+        htlc_core::verify_secret(&secret, &htlc.hashlock, htlc.hash_algorithm);
 
         htlc.withdrawn = true;
-        htlc.secret = Some(secret);
+        htlc.secret = Some(secret.clone());
         self.htlcs.insert(&htlc_id, &htlc);
 
-        env::log_str(&format!("HTLC withdrawn: {}", htlc_id));
+        events::HtlcWithdrawn {
+            htlc_id,
+            secret,
+            withdrawn_by: htlc.receiver.clone(),
+            amount: htlc.amount,
+        }.emit();
         Promise::new(htlc.receiver).transfer(NearToken::from_yoctonear(htlc.amount.0))
     }
 
+    // Withdraw on behalf of the receiver, who signed a `WithdrawAuthorization`
+    // and so needs no NEAR for gas. The caller earns the signed tip.
+    pub fn withdraw_for(
+        &mut self,
+        htlc_id: String,
+        secret: String,
+        payout: AccountId,
+        tip: U128,
+        expiry: U64,
+        signature: String,
+    ) -> Promise {
+        let mut htlc = self.load_htlc(&htlc_id).expect("HTLC not found");
+        let relayer = env::predecessor_account_id();
+
+        require!(!htlc.withdrawn && !htlc.refunded, "Already processed");
+        require!(!htlc_core::is_expired(htlc.timelock.0), "Expired");
+        require!(tip.0 <= htlc.amount.0, "Tip exceeds amount");
+        WithdrawAuthorization {
+            contract_id: env::current_account_id(),
+            htlc_id: htlc_id.clone(),
+            payout: payout.clone(),
+            tip: tip.0,
+            expiry: expiry.0,
+        }
+        .verify(&htlc.receiver, &signature);
+        htlc_core::verify_secret(&secret, &htlc.hashlock, htlc.hash_algorithm);
+
+        htlc.withdrawn = true;
+        htlc.secret = Some(secret.clone());
+        self.htlcs.insert(&htlc_id, &htlc);
+
+        events::HtlcWithdrawn {
+            htlc_id,
+            secret,
+            withdrawn_by: relayer.clone(),
+            amount: htlc.amount,
+        }.emit();
+        let payout = Promise::new(payout).transfer(NearToken::from_yoctonear(htlc.amount.0 - tip.0));
+        if tip.0 > 0 {
+            payout.and(Promise::new(relayer).transfer(NearToken::from_yoctonear(tip.0)))
+        } else {
+            payout
+        }
+    }
+
     pub fn refund(&mut self, htlc_id: String) -> Promise {
         let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
-        let current_time = env::block_timestamp() / 1_000_000_000;
         
         require!(!htlc.withdrawn && !htlc.refunded, "Already processed");
         require!(env::predecessor_account_id() == htlc.sender, "Not sender");
-        require!(current_time >= htlc.timelock.0, "Not expired");
+        require!(htlc_core::is_expired(htlc.timelock.0), "Not expired");
 
         htlc.refunded = true;
         self.htlcs.insert(&htlc_id, &htlc);
 
-        env::log_str(&format!("HTLC refunded: {}", htlc_id));
+        events::HtlcRefunded {
+            htlc_id,
+            refunded_to: htlc.sender.clone(),
+            amount: htlc.amount,
+        }.emit();
         Promise::new(htlc.sender).transfer(NearToken::from_yoctonear(htlc.amount.0))
     }
 
@@ -123,10 +191,13 @@ impl FusionHTLC {
         self.htlcs.get(&htlc_id).is_some()
     }
 
+    pub fn get_htlc(&self, htlc_id: String) -> Option<HTLCInfo> {
+        self.load_htlc(&htlc_id)
+    }
+
     pub fn can_withdraw(&self, htlc_id: String) -> bool {
         if let Some(htlc) = self.htlcs.get(&htlc_id) {
-            let current_time = env::block_timestamp() / 1_000_000_000;
-            !htlc.withdrawn && !htlc.refunded && current_time < htlc.timelock.0
+            !htlc.withdrawn && !htlc.refunded && !htlc_core::is_expired(htlc.timelock.0)
         } else {
             false
         }
@@ -134,19 +205,34 @@ impl FusionHTLC {
 
     pub fn can_refund(&self, htlc_id: String) -> bool {
         if let Some(htlc) = self.htlcs.get(&htlc_id) {
-            let current_time = env::block_timestamp() / 1_000_000_000;
-            !htlc.withdrawn && !htlc.refunded && current_time >= htlc.timelock.0
+            !htlc.withdrawn && !htlc.refunded && htlc_core::is_expired(htlc.timelock.0)
         } else {
             false
         }
     }
 }
 
+impl FusionHTLC {
+    // Left out of the contract struct so its layout stays the one already
+    // deployed
+    fn hash_algorithms() -> LookupMap<String, HashAlgorithm> {
+        LookupMap::new(StorageKey::HashAlgorithms)
+    }
+
+    fn load_htlc(&self, htlc_id: &String) -> Option<HTLCInfo> {
+        self.htlcs.get(htlc_id).map(|mut htlc| {
+            htlc.hash_algorithm = Self::hash_algorithms().get(htlc_id).unwrap_or_default();
+            htlc
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
     use near_sdk::testing_env;
+    use sha2::{Digest, Sha256};
 
     #[test]
     fn test_create_and_withdraw() {
@@ -165,7 +251,7 @@ mod tests {
         hasher.update(secret.as_bytes());
         let hashlock = hex::encode(hasher.finalize());
 
-        let htlc_id = contract.create_htlc(accounts(2), hashlock.clone(), 3600);
+        let htlc_id = contract.create_htlc(accounts(2), hashlock.clone(), 3600, None);
         
         assert!(contract.htlc_exists(htlc_id.clone()));
         assert!(contract.can_withdraw(htlc_id.clone()));
@@ -174,7 +260,7 @@ mod tests {
         testing_env!(VMContextBuilder::new()
             .current_account_id(accounts(0))
             .predecessor_account_id(accounts(2))
-            .block_timestamp(1_000_000_000_000_000_000 + 1800_000_000_000) // 30 minutes later in nanoseconds
+            .block_timestamp(1_000_000_000_000_000_000 + 1_800_000_000_000) // 30 minutes later in nanoseconds
             .build());
 
         contract.withdraw(htlc_id.clone(), hex::encode(secret));
@@ -183,6 +269,90 @@ mod tests {
         assert!(!contract.can_refund(htlc_id));
     }
 
+    #[test]
+    fn test_keccak256_hashlock() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+
+        let mut contract = FusionHTLC::new();
+        let secret = [3u8; 32];
+        let hashlock = hex::encode(env::keccak256(&secret));
+        let htlc_id = contract.create_htlc(accounts(2), hashlock, 3600, Some(HashAlgorithm::Keccak256));
+        assert_eq!(contract.get_htlc(htlc_id.clone()).unwrap().hash_algorithm, HashAlgorithm::Keccak256);
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        contract.withdraw(htlc_id.clone(), hex::encode(secret));
+        assert!(contract.get_htlc(htlc_id).unwrap().withdrawn);
+    }
+
+    #[test]
+    fn test_record_keeps_pre_hash_algorithm_layout() {
+        let stored = near_sdk::borsh::to_vec(&(
+            accounts(1),
+            accounts(2),
+            U128(5),
+            hex::encode([0u8; 32]),
+            U64(3600),
+            None::<String>,
+            false,
+            false,
+        ))
+        .unwrap();
+        let htlc: HTLCInfo = near_sdk::borsh::from_slice(&stored).unwrap();
+        assert_eq!(htlc.timelock, U64(3600));
+        assert_eq!(htlc.hash_algorithm, HashAlgorithm::Sha256);
+        assert_eq!(near_sdk::borsh::to_vec(&htlc).unwrap(), stored);
+    }
+
+    #[test]
+    fn test_withdraw_for_signed_by_receiver() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let receiver: AccountId = hex::encode(key.verifying_key().to_bytes()).parse().unwrap();
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let mut contract = FusionHTLC::new();
+        let htlc_id = contract.create_htlc(receiver, hex::encode(Sha256::digest([4u8; 32])), 3600, None);
+
+        let authorization = WithdrawAuthorization {
+            contract_id: accounts(0),
+            htlc_id: htlc_id.clone(),
+            payout: accounts(4),
+            tip: 1000,
+            expiry: 1_000_000_000 + 600,
+        };
+        let signature = key.sign(&near_sdk::borsh::to_vec(&authorization).unwrap());
+
+        // Relayed by an unrelated account
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        contract.withdraw_for(
+            htlc_id.clone(),
+            hex::encode([4u8; 32]),
+            accounts(4),
+            U128(1000),
+            U64(1_000_000_000 + 600),
+            hex::encode(signature.to_bytes()),
+        );
+        assert!(contract.get_htlc(htlc_id).unwrap().withdrawn);
+    }
+
     #[test]
     fn test_refund() {
         let context = VMContextBuilder::new()
@@ -195,18 +365,19 @@ mod tests {
 
         let mut contract = FusionHTLC::new();
         let hashlock = hex::encode([0u8; 32]);
-        let htlc_id = contract.create_htlc(accounts(2), hashlock, 3600);
+        let htlc_id = contract.create_htlc(accounts(2), hashlock, 3600, None);
 
         // Fast forward time (2 hours later, after expiry)
         testing_env!(VMContextBuilder::new()
             .current_account_id(accounts(0))
             .predecessor_account_id(accounts(1))
-            .block_timestamp(1_000_000_000_000_000_000 + 7200_000_000_000) // 2 hours later in nanoseconds
+            .block_timestamp(1_000_000_000_000_000_000 + 7_200_000_000_000) // 2 hours later in nanoseconds
             .build());
 
         contract.refund(htlc_id.clone());
         
         assert!(!contract.can_withdraw(htlc_id.clone()));
         assert!(!contract.can_refund(htlc_id));
+        assert!(get_logs()[0].starts_with(r#"EVENT_JSON:{"standard":"fusion_htlc","version":"1.0.0","event":"htlc_refunded""#));
     }
 }
\ No newline at end of file
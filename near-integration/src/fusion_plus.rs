@@ -1,27 +1,87 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedMap, Vector};
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet};
 use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
     env, near_bindgen, require, AccountId, BorshStorageKey, NearToken, PanicOnDefault,
-    Promise,
+    Promise, PromiseOrValue,
 };
-use sha2::{Digest, Sha256};
+
+use crate::events::{self, Event};
+use crate::htlc_core::{self, HashAlgorithm, WithdrawAuthorization};
+use crate::merkle;
+
+mod admin;
+mod batch;
+mod payout;
+mod prune;
+mod query;
+mod resolvers;
+mod solvency;
+mod stages;
+#[cfg(test)]
+mod state_machine_tests;
+mod storage;
+mod sweep;
+mod token;
+mod upgrade;
+
+use admin::DEFAULT_RETENTION_SECONDS;
+pub use admin::ContractConfig;
+pub use batch::{BatchCreateHTLC, BatchWithdraw};
+pub use payout::{PayoutKind, Settlement};
+pub use query::{HTLCFilter, HTLCPage, TokenFilter, TokenStats};
+pub use resolvers::{Resolver, ResolverConfig, ResolverStats, ResolverStatus};
+pub use solvency::AssetSolvency;
+pub use stages::{Stage, StageDurations, Timelocks};
+pub use storage::StorageAccount;
+pub use token::FtMessage;
+pub use upgrade::{FusionHTLCV0, FusionPlusContractV0, PartialFillV0, SafetyDepositV0, VersionedContract};
 
 // Constants
-const MIN_TIMELOCK: u64 = 3600; // 1 hour
-const MAX_TIMELOCK: u64 = 2592000; // 30 days
-const TGAS: u64 = 1_000_000_000_000;
+const MAX_PARTS_COUNT: u32 = 256;
 
 // Storage keys
 #[derive(BorshSerialize, BorshStorageKey)]
 enum StorageKey {
+    // V0 records and indexes, converted by `migrate_records`; left in place
+    // so the later keys keep their prefixes
+    #[allow(dead_code)]
     HTLCs,
     PartialFills { htlc_id_hash: Vec<u8> },
+    #[allow(dead_code)]
     SafetyDeposits,
     SecretToHTLC,
+    #[allow(dead_code)]
     UserHTLCs { user_hash: Vec<u8> },
+    #[allow(dead_code)]
     ActiveHTLCs,
+    StorageAccounts,
+    Fills,
+    AccruedFees,
+    TokenStats,
+    ResolverHTLCs { account_hash: Vec<u8> },
+    FillerHTLCs { account_hash: Vec<u8> },
+    HTLCDeposits { htlc_id_hash: Vec<u8> },
+    Resolvers,
+    TokenLedger,
+    UserHTLCNumbers { user_hash: Vec<u8> },
+    ActiveHTLCNumbers,
+    HTLCsV1,
+    SafetyDepositsV1,
+}
+
+// Per-account HTLC index holding sequence numbers, in order, so queries can
+// page through it from a cursor
+type HTLCIndex = LookupMap<AccountId, TreeMap<u64, ()>>;
+
+// HTLC ids are `htlc_` followed by the HTLC's sequence number
+fn htlc_id(number: u64) -> String {
+    format!("htlc_{}", number)
+}
+
+fn htlc_number(htlc_id: &str) -> u64 {
+    htlc_id.strip_prefix("htlc_").and_then(|number| number.parse().ok()).expect("Invalid HTLC id")
 }
 
 // Main HTLC structure supporting both full and partial fills
@@ -30,21 +90,32 @@ enum StorageKey {
 pub struct FusionHTLC {
     pub id: String,
     pub sender: AccountId,
-    pub receiver: AccountId,
+    pub receiver: Option<AccountId>, // None until a resolver takes an open order
+    pub open_order: bool,            // Created without a receiver
+    pub taken_until: Option<U64>,    // Open orders: end of the receiver's exclusivity
     pub token_id: Option<AccountId>, // None for NEAR, Some for NEP-141
     pub total_amount: U128,
     pub remaining_amount: U128,
-    pub hashlock: String,
-    pub timelock: U64,
+    pub hashlock: String, // Merkle root of parts_count + 1 secrets for partial fills
+    pub hash_algorithm: HashAlgorithm, // Hash of every secret, including fill secrets
+    pub timelock: U64, // Start of cancellation, same as `timelocks.cancellation_start`
+    pub timelocks: Timelocks,
     pub secret: Option<String>,
     pub allow_partial_fills: bool,
+    pub parts_count: u32,
     pub min_fill_amount: U128,
     pub safety_deposit_amount: U128,
+    pub pending_fills: u32,
+    pub resolvers: Vec<AccountId>, // Accounts that posted a safety deposit
+    pub settled_by: Option<AccountId>, // Who withdrew or cancelled
+    pub taker_lapsed: bool, // The receiver let its withdrawal window pass; its safety deposits go to `settled_by`
     pub status: HTLCStatus,
     pub created_at: U64,
+    pub settled_at: Option<U64>, // Last settlement of the HTLC or one of its fills
+    pub storage_bytes: u64, // Storage staked by the sender, released when the HTLC is pruned
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub enum HTLCStatus {
     Active,
@@ -61,11 +132,15 @@ pub struct PartialFill {
     pub htlc_id: String,
     pub filler: AccountId,
     pub amount: U128,
+    pub secret_index: u32,
+    pub secret_hash: String,
+    pub secret: Option<String>,
     pub status: FillStatus,
     pub created_at: U64,
+    pub storage_bytes: u64, // Storage staked by the filler, released when its HTLC is pruned
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub enum FillStatus {
     Pending,
@@ -73,7 +148,8 @@ pub enum FillStatus {
     Refunded,
 }
 
-// Safety deposit for resolvers
+// Safety deposit for resolvers, locked until its HTLC settles and then paid
+// back, or to whoever settled it if the depositor let its window lapse
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct SafetyDeposit {
@@ -82,206 +158,214 @@ pub struct SafetyDeposit {
     pub depositor: AccountId,
     pub amount: U128,
     pub created_at: U64,
+    pub storage_bytes: u64,
 }
 
-// Events
-#[derive(Serialize, Deserialize)]
+// HTLC creation parameters, shared by `create_htlc`, batches and NEP-141
+// deposits
+#[derive(Serialize, Deserialize, Clone, Default)]
 #[serde(crate = "near_sdk::serde")]
-pub struct HTLCCreatedEvent {
-    pub htlc_id: String,
-    pub sender: AccountId,
-    pub receiver: AccountId,
-    pub amount: U128,
+pub struct CreateHTLCArgs {
+    #[serde(default)]
+    pub receiver: Option<AccountId>, // None for an open order
     pub hashlock: String,
-    pub timelock: U64,
+    pub timelock_seconds: u64,
+    #[serde(default)]
     pub allow_partial_fills: bool,
+    #[serde(default)]
+    pub parts_count: Option<u32>,
+    #[serde(default)]
+    pub min_fill_amount: Option<U128>,
+    #[serde(default)]
+    pub require_safety_deposit: bool,
+    #[serde(default)]
+    pub stage_durations: Option<StageDurations>, // Overrides `timelock_seconds`
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(crate = "near_sdk::serde")]
-pub struct HTLCWithdrawnEvent {
-    pub htlc_id: String,
-    pub secret: String,
-    pub withdrawn_by: AccountId,
-    pub amount: U128,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(crate = "near_sdk::serde")]
-pub struct PartialFillCreatedEvent {
-    pub fill_id: String,
-    pub htlc_id: String,
-    pub filler: AccountId,
-    pub amount: U128,
+impl CreateHTLCArgs {
+    fn durations(&self) -> StageDurations {
+        self.stage_durations.clone().unwrap_or_else(|| StageDurations::from_timelock(self.timelock_seconds))
+    }
 }
 
 // Main contract
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct FusionPlusContract {
+    owner_id: AccountId,
+    pending_owner_id: Option<AccountId>, // Proposed owner until it accepts
+    paused: bool, // Blocks new HTLCs and fills, never settlement
+    protocol_fee_bps: u16, // Taken from every withdrawal
+    accrued_fees: UnorderedMap<Option<AccountId>, u128>, // Per token, None for NEAR
     htlcs: UnorderedMap<String, FusionHTLC>,
-    partial_fills: LookupMap<String, Vector<PartialFill>>,
+    partial_fills: LookupMap<String, PartialFill>, // fill_id -> fill
+    htlc_fills: LookupMap<String, UnorderedSet<String>>, // htlc_id -> fill ids
     safety_deposits: UnorderedMap<String, SafetyDeposit>,
+    htlc_deposits: LookupMap<String, UnorderedSet<String>>, // htlc_id -> deposit ids
     secret_to_htlc: LookupMap<String, String>,
-    user_htlcs: LookupMap<AccountId, Vector<String>>,
-    active_htlcs: Vector<String>,
+    user_htlcs: HTLCIndex, // As sender or receiver
+    resolver_htlcs: HTLCIndex,
+    filler_htlcs: HTLCIndex,
+    active_htlcs: TreeMap<u64, ()>, // Sequence numbers of open HTLCs and those with pending fills
+    storage_accounts: LookupMap<AccountId, StorageAccount>,
     next_htlc_id: u64,
     next_fill_id: u64,
     next_deposit_id: u64,
-    total_volume: U128,
-    total_htlcs_created: u64,
+    token_stats: UnorderedMap<Option<AccountId>, TokenStats>, // Per token, None for NEAR
+    sweep_cursor: u64, // Sequence number of the last HTLC the previous sweep looked at
+    resolver_config: ResolverConfig,
+    resolvers: UnorderedMap<AccountId, Resolver>,
+    total_resolver_stake: u128, // In `resolver_config.stake_token`
+    token_ledger: UnorderedMap<AccountId, u128>, // NEP-141 tokens received less tokens sent
+    // Sum of the unlocked NEP-145 storage balances. Decrements saturate, as
+    // accounts registered before the field existed were never added.
+    storage_available: u128,
+    retention_seconds: u64, // How long settled HTLCs are kept before they can be pruned
 }
 
 #[near_bindgen]
 impl FusionPlusContract {
+    // The account that initializes the contract becomes its owner
     #[init]
     pub fn new() -> Self {
+        Self::write_state_version();
         Self {
-            htlcs: UnorderedMap::new(StorageKey::HTLCs),
-            partial_fills: LookupMap::new(StorageKey::PartialFills { htlc_id_hash: vec![] }),
-            safety_deposits: UnorderedMap::new(StorageKey::SafetyDeposits),
+            owner_id: env::predecessor_account_id(),
+            pending_owner_id: None,
+            paused: false,
+            protocol_fee_bps: 0,
+            accrued_fees: UnorderedMap::new(StorageKey::AccruedFees),
+            htlcs: UnorderedMap::new(StorageKey::HTLCsV1),
+            partial_fills: LookupMap::new(StorageKey::Fills),
+            htlc_fills: LookupMap::new(StorageKey::PartialFills { htlc_id_hash: vec![] }),
+            safety_deposits: UnorderedMap::new(StorageKey::SafetyDepositsV1),
+            htlc_deposits: LookupMap::new(StorageKey::HTLCDeposits { htlc_id_hash: vec![] }),
             secret_to_htlc: LookupMap::new(StorageKey::SecretToHTLC),
-            user_htlcs: LookupMap::new(StorageKey::UserHTLCs { user_hash: vec![] }),
-            active_htlcs: Vector::new(StorageKey::ActiveHTLCs),
+            user_htlcs: LookupMap::new(StorageKey::UserHTLCNumbers { user_hash: vec![] }),
+            resolver_htlcs: LookupMap::new(StorageKey::ResolverHTLCs { account_hash: vec![] }),
+            filler_htlcs: LookupMap::new(StorageKey::FillerHTLCs { account_hash: vec![] }),
+            active_htlcs: TreeMap::new(StorageKey::ActiveHTLCNumbers),
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
             next_htlc_id: 1,
             next_fill_id: 1,
             next_deposit_id: 1,
-            total_volume: U128(0),
-            total_htlcs_created: 0,
+            token_stats: UnorderedMap::new(StorageKey::TokenStats),
+            sweep_cursor: 0,
+            resolver_config: ResolverConfig::default(),
+            resolvers: UnorderedMap::new(StorageKey::Resolvers),
+            total_resolver_stake: 0,
+            token_ledger: UnorderedMap::new(StorageKey::TokenLedger),
+            storage_available: 0,
+            retention_seconds: DEFAULT_RETENTION_SECONDS,
         }
     }
 
-    // Create HTLC with optional partial fills support
+    // Create HTLC with optional partial fills support. With partial fills the
+    // hashlock is the Merkle root over `parts_count + 1` secret hashes.
+    // Without `stage_durations` the taker can withdraw until
+    // `timelock_seconds` and only the sender can cancel afterwards. Secrets
+    // are hashed with SHA-256 unless `hash_algorithm` says otherwise.
+    // Without a `receiver` the order is open: it always takes a safety
+    // deposit, and the resolver posting it becomes the receiver.
     #[payable]
-    pub fn create_htlc(
-        &mut self,
-        receiver: AccountId,
-        hashlock: String,
-        timelock_seconds: u64,
-        allow_partial_fills: bool,
-        min_fill_amount: Option<U128>,
-        require_safety_deposit: bool,
-    ) -> String {
-        let sender = env::predecessor_account_id();
+    pub fn create_htlc(&mut self, args: CreateHTLCArgs) -> String {
         let amount = env::attached_deposit();
-        let current_time = env::block_timestamp() / 1_000_000_000;
-
-        // Validations
         require!(amount > NearToken::from_yoctonear(0), "Amount must be greater than 0");
-        require!(hashlock.len() == 64, "Invalid hashlock");
-        require!(timelock_seconds >= MIN_TIMELOCK && timelock_seconds <= MAX_TIMELOCK, "Invalid timelock");
-
-        let min_fill = if allow_partial_fills {
-            let min = min_fill_amount.unwrap_or(U128(amount.as_yoctonear() / 10)); // Default 10%
-            require!(min.0 > 0 && min.0 <= amount.as_yoctonear(), "Invalid min fill amount");
-            min
-        } else {
-            U128(amount.as_yoctonear())
-        };
-
-        let htlc_id = format!("htlc_{}", self.next_htlc_id);
-        self.next_htlc_id += 1;
-
-        let htlc = FusionHTLC {
-            id: htlc_id.clone(),
-            sender: sender.clone(),
-            receiver: receiver.clone(),
-            token_id: None,
-            total_amount: U128(amount.as_yoctonear()),
-            remaining_amount: U128(amount.as_yoctonear()),
-            hashlock: hashlock.clone(),
-            timelock: U64(current_time + timelock_seconds),
-            secret: None,
-            allow_partial_fills,
-            min_fill_amount: min_fill,
-            safety_deposit_amount: U128(if require_safety_deposit { amount.as_yoctonear() / 20 } else { 0 }),
-            status: HTLCStatus::Active,
-            created_at: U64(current_time),
-        };
-
-        // Store HTLC
-        self.htlcs.insert(&htlc_id, &htlc);
-        self.secret_to_htlc.insert(&hashlock, &htlc_id);
-        self.active_htlcs.push(&htlc_id);
-
-        // Track user HTLCs
-        self.add_user_htlc(&sender, &htlc_id);
-        self.add_user_htlc(&receiver, &htlc_id);
-
-        // Update stats
-        self.total_volume = U128(self.total_volume.0 + amount.as_yoctonear());
-        self.total_htlcs_created += 1;
-
-        // Initialize partial fills vector if needed
-        if allow_partial_fills {
-            let fills_key = Self::get_fills_key(&htlc_id);
-            self.partial_fills.insert(&htlc_id, &Vector::new(fills_key));
-        }
-
-        // Emit event
-        env::log_str(&serde_json::to_string(&HTLCCreatedEvent {
-            htlc_id: htlc_id.clone(),
-            sender,
-            receiver,
-            amount: U128(amount.as_yoctonear()),
-            hashlock,
-            timelock: U64(current_time + timelock_seconds),
-            allow_partial_fills,
-        }).unwrap());
 
-        htlc_id
+        self.internal_create_htlc(env::predecessor_account_id(), None, amount.as_yoctonear(), args)
     }
 
-    // Withdraw funds by providing the correct secret
+    // Withdraw funds to the receiver by providing the correct secret. Who may
+    // call depends on the stage: the receiver, then its resolvers, then anyone.
     pub fn withdraw(&mut self, htlc_id: String, secret: String) -> Promise {
-        let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
-        let current_time = env::block_timestamp() / 1_000_000_000;
         let withdrawer = env::predecessor_account_id();
+        let htlc = self
+            .check_withdraw(&htlc_id, &secret, &withdrawer)
+            .unwrap_or_else(|e| env::panic_str(e));
+        let (htlc, previous_status) = self.settle_withdraw(htlc, secret, withdrawer.clone(), &withdrawer);
+
+        // Transfer funds, less the protocol fee
+        let receiver = htlc.receiver.clone().expect("Order not taken");
+        let fee = self.protocol_fee(htlc.total_amount.0);
+        self.internal_payout(&htlc, receiver, htlc.total_amount.0 - fee, fee, PayoutKind::Withdraw, previous_status)
+    }
 
-        // Validations
-        require!(htlc.status == HTLCStatus::Active || htlc.status == HTLCStatus::PartiallyFilled, "HTLC not active");
-        require!(current_time < htlc.timelock.0, "HTLC expired");
-        require!(withdrawer == htlc.receiver, "Not the receiver");
-        require!(!htlc.allow_partial_fills, "Use withdraw_partial for partial fills");
-
-        // Verify secret
-        self.verify_secret(&secret, &htlc.hashlock);
-
-        // Update HTLC
-        htlc.status = HTLCStatus::Completed;
-        htlc.secret = Some(secret.clone());
-        self.htlcs.insert(&htlc_id, &htlc);
-
-        // Remove from active
-        self.remove_from_active(&htlc_id);
-
-        // Emit event
-        env::log_str(&serde_json::to_string(&HTLCWithdrawnEvent {
+    // Withdraw on behalf of the receiver, who signed a `WithdrawAuthorization`
+    // and so needs no NEAR for gas. Stages apply as if the receiver called;
+    // the caller settles the HTLC and is paid the signed tip.
+    pub fn withdraw_for(
+        &mut self,
+        htlc_id: String,
+        secret: String,
+        payout: AccountId,
+        tip: U128,
+        expiry: U64,
+        signature: String,
+    ) -> Promise {
+        let relayer = env::predecessor_account_id();
+        let htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
+        let receiver = htlc.receiver.unwrap_or_else(|| env::panic_str("Order not taken"));
+        let htlc = self
+            .check_withdraw(&htlc_id, &secret, &receiver)
+            .unwrap_or_else(|e| env::panic_str(e));
+        let fee = self.protocol_fee(htlc.total_amount.0);
+        require!(tip.0 <= htlc.total_amount.0 - fee, "Tip exceeds amount");
+        WithdrawAuthorization {
+            contract_id: env::current_account_id(),
             htlc_id,
-            secret,
-            withdrawn_by: withdrawer.clone(),
-            amount: htlc.total_amount,
-        }).unwrap());
+            payout: payout.clone(),
+            tip: tip.0,
+            expiry: expiry.0,
+        }
+        .verify(&receiver, &signature);
 
-        // Transfer funds
-        Promise::new(withdrawer).transfer(NearToken::from_yoctonear(htlc.total_amount.0))
+        let (htlc, previous_status) = self.settle_withdraw(htlc, secret, relayer.clone(), &receiver);
+        let amount = htlc.total_amount.0 - fee - tip.0;
+        self.internal_payout(&htlc, payout, amount, fee, PayoutKind::RelayedWithdraw { relayer, tip }, previous_status)
     }
 
-    // Create a partial fill
-    #[payable]
-    pub fn create_partial_fill(&mut self, htlc_id: String, fill_amount: U128) -> String {
+    // Take the next part of a partial-fill order. The filler proves that
+    // `secret_hash` is leaf `secret_index` of the order's Merkle root; the
+    // index is fixed by how far the order is filled after this fill.
+    pub fn create_partial_fill(
+        &mut self,
+        htlc_id: String,
+        fill_amount: U128,
+        secret_index: u32,
+        secret_hash: String,
+        merkle_proof: Vec<String>,
+    ) -> String {
         let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
         let filler = env::predecessor_account_id();
-        let attached = env::attached_deposit();
-        let current_time = env::block_timestamp() / 1_000_000_000;
+        let current_time = htlc_core::now_seconds();
+        let initial_storage = env::storage_usage();
 
         // Validations
+        self.assert_not_paused();
+        self.assert_authorized_resolver(&filler);
         require!(htlc.allow_partial_fills, "Partial fills not allowed");
         require!(htlc.status == HTLCStatus::Active || htlc.status == HTLCStatus::PartiallyFilled, "HTLC not active");
         require!(current_time < htlc.timelock.0, "HTLC expired");
-        require!(fill_amount.0 >= htlc.min_fill_amount.0, "Below minimum fill");
+        require!(fill_amount.0 > 0, "Fill amount must be greater than 0");
         require!(fill_amount.0 <= htlc.remaining_amount.0, "Exceeds remaining amount");
-        require!(attached >= NearToken::from_yoctonear(fill_amount.0), "Insufficient deposit");
+        require!(
+            fill_amount.0 >= htlc.min_fill_amount.0 || fill_amount.0 == htlc.remaining_amount.0,
+            "Below minimum fill"
+        );
+
+        // The fill must reveal the secret of the part it reaches
+        let filled = htlc.total_amount.0 - htlc.remaining_amount.0;
+        let expected_index = merkle::expected_secret_index(htlc.total_amount.0, filled, fill_amount.0, htlc.parts_count)
+            .expect("Part already filled");
+        require!(secret_index == expected_index, "Invalid secret index");
+        let leaf = merkle::leaf(htlc.hash_algorithm, secret_index, &Self::decode_hash(&secret_hash));
+        let proof: Vec<merkle::Hash> = merkle_proof.iter().map(|node| Self::decode_hash(node)).collect();
+        require!(
+            merkle::verify_proof(htlc.hash_algorithm, &Self::decode_hash(&htlc.hashlock), leaf, &proof),
+            "Invalid merkle proof"
+        );
 
         // Create fill
         let fill_id = format!("fill_{}", self.next_fill_id);
@@ -292,159 +376,217 @@ impl FusionPlusContract {
             htlc_id: htlc_id.clone(),
             filler: filler.clone(),
             amount: fill_amount,
+            secret_index,
+            secret_hash: secret_hash.clone(),
+            secret: None,
             status: FillStatus::Pending,
             created_at: U64(current_time),
+            storage_bytes: 0,
         };
 
         // Store fill
-        let mut fills = self.partial_fills.get(&htlc_id)
-            .unwrap_or_else(|| Vector::new(Self::get_fills_key(&htlc_id)));
-        fills.push(&fill);
-        self.partial_fills.insert(&htlc_id, &fills);
+        self.partial_fills.insert(&fill_id, &fill);
+        let mut fill_ids = self.htlc_fills.get(&htlc_id).expect("No fills found");
+        fill_ids.insert(&fill_id);
+        self.htlc_fills.insert(&htlc_id, &fill_ids);
+        Self::index_htlc(
+            &mut self.filler_htlcs,
+            |account_hash| StorageKey::FillerHTLCs { account_hash },
+            &filler,
+            &htlc_id,
+        );
 
-        // Update HTLC
+        // Reserve the part out of the maker's escrow
         htlc.remaining_amount = U128(htlc.remaining_amount.0 - fill_amount.0);
+        htlc.pending_fills += 1;
         htlc.status = HTLCStatus::PartiallyFilled;
         self.htlcs.insert(&htlc_id, &htlc);
+        self.record_resolver_fill(&filler, fill_amount.0);
 
-        // Return excess
-        if attached > NearToken::from_yoctonear(fill_amount.0) {
-            Promise::new(filler.clone()).transfer(attached.saturating_sub(NearToken::from_yoctonear(fill_amount.0)));
-        }
+        // Charge the filler for the storage the fill occupies
+        let storage_bytes = self.charge_storage(&filler, initial_storage);
+        self.partial_fills.insert(&fill_id, &PartialFill { storage_bytes, ..fill });
 
         // Emit event
-        env::log_str(&serde_json::to_string(&PartialFillCreatedEvent {
+        events::FillCreated {
             fill_id: fill_id.clone(),
             htlc_id,
             filler,
             amount: fill_amount,
-        }).unwrap());
+            secret_index: Some(secret_index),
+            secret_hash,
+        }.emit();
 
         fill_id
     }
 
-    // Withdraw a partial fill
+    // Withdraw a partial fill to its filler by revealing the secret of its
+    // own part. Stages apply as in `withdraw`, with the filler as taker.
     pub fn withdraw_partial(&mut self, htlc_id: String, fill_id: String, secret: String) -> Promise {
         let htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
-        let current_time = env::block_timestamp() / 1_000_000_000;
+        let stage = Self::current_stage(&htlc);
         let withdrawer = env::predecessor_account_id();
 
-        // Validations
-        require!(withdrawer == htlc.receiver, "Not the receiver");
-        require!(current_time < htlc.timelock.0, "HTLC expired");
-        
-        // Verify secret
-        self.verify_secret(&secret, &htlc.hashlock);
+        require!(!stage.is_cancellation(), "HTLC expired");
+        require!(stage != Stage::Pending, "HTLC not final");
 
         // Find and update fill
-        let mut fills = self.partial_fills.get(&htlc_id).expect("No fills found");
-        let mut fill_index = None;
-        for i in 0..fills.len() {
-            if fills.get(i).unwrap().id == fill_id {
-                fill_index = Some(i);
-                break;
-            }
-        }
-        
-        let idx = fill_index.expect("Fill not found");
-        let mut fill = fills.get(idx).unwrap();
+        let mut fill = self.get_fill(&htlc_id, &fill_id);
         require!(fill.status == FillStatus::Pending, "Fill already processed");
-        
+        require!(
+            stage.can_withdraw(withdrawer == fill.filler, htlc.resolvers.contains(&withdrawer)),
+            "Not authorized to withdraw at this stage"
+        );
+
+        // Verify the part's secret
+        htlc_core::verify_secret(&secret, &fill.secret_hash, htlc.hash_algorithm);
+
         fill.status = FillStatus::Completed;
-        fills.replace(idx, &fill);
-        self.partial_fills.insert(&htlc_id, &fills);
+        fill.secret = Some(secret.clone());
+        self.partial_fills.insert(&fill_id, &fill);
 
-        // Update HTLC if all fills completed
+        // Complete the HTLC once every part is settled
+        let previous_status = htlc.status.clone();
         let mut htlc_mut = htlc.clone();
-        if htlc_mut.remaining_amount.0 == 0 && self.all_fills_completed(&htlc_id) {
+        htlc_mut.pending_fills -= 1;
+        htlc_mut.settled_at = Some(U64(htlc_core::now_seconds()));
+        let completes = htlc_mut.remaining_amount.0 == 0 && htlc_mut.pending_fills == 0;
+        if completes {
             htlc_mut.status = HTLCStatus::Completed;
             htlc_mut.secret = Some(secret.clone());
-            self.htlcs.insert(&htlc_id, &htlc_mut);
-            self.remove_from_active(&htlc_id);
+            htlc_mut.settled_by = Some(withdrawer.clone());
         }
+        self.htlcs.insert(&htlc_id, &htlc_mut);
+        self.unindex_if_done(&htlc_mut);
+        self.update_token_stats(&htlc.token_id, |stats| {
+            stats.withdrawn_volume = U128(stats.withdrawn_volume.0 + fill.amount.0);
+            if completes {
+                stats.active_htlcs = stats.active_htlcs.saturating_sub(1);
+                stats.completed_htlcs += 1;
+            }
+        });
 
-        // Transfer to receiver
-        Promise::new(withdrawer).transfer(NearToken::from_yoctonear(fill.amount.0))
+        events::FillWithdrawn {
+            fill_id: fill_id.clone(),
+            htlc_id,
+            secret,
+            withdrawn_by: withdrawer.clone(),
+            amount: fill.amount,
+        }.emit();
+
+        // Pay the part to its filler, less the protocol fee
+        let fee = self.protocol_fee(fill.amount.0);
+        self.internal_payout(
+            &htlc_mut,
+            fill.filler,
+            fill.amount.0 - fee,
+            fee,
+            PayoutKind::FillWithdraw { fill_id },
+            previous_status,
+        )
     }
 
-    // Refund HTLC after timeout
-    pub fn refund(&mut self, htlc_id: String) -> Promise {
-        let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
-        let current_time = env::block_timestamp() / 1_000_000_000;
+    // Refund HTLC to the sender after timeout: first by the sender or its
+    // resolvers, then by anyone. An order fully taken by fills settles
+    // without a transfer.
+    pub fn refund(&mut self, htlc_id: String) -> PromiseOrValue<bool> {
         let refunder = env::predecessor_account_id();
-
-        // Validations
-        require!(refunder == htlc.sender, "Not the sender");
-        require!(current_time >= htlc.timelock.0, "Not expired");
-        require!(htlc.status != HTLCStatus::Completed && htlc.status != HTLCStatus::Refunded, "Already processed");
-
-        // Calculate refund amount
-        let refund_amount = if htlc.allow_partial_fills {
-            htlc.remaining_amount.0
-        } else {
-            htlc.total_amount.0
-        };
-
-        // Update status
-        htlc.status = HTLCStatus::Refunded;
-        self.htlcs.insert(&htlc_id, &htlc);
-        self.remove_from_active(&htlc_id);
+        let htlc = self.check_refund(&htlc_id, &refunder).unwrap_or_else(|e| env::panic_str(e));
+        let (htlc, refund_amount, previous_status) = self.settle_refund(htlc, refunder);
+        if refund_amount == 0 {
+            // Nothing to send, so the refund has already gone through
+            self.slash_for_lapse(&htlc_id, None);
+            return PromiseOrValue::Value(true);
+        }
 
         // Refund
-        Promise::new(refunder).transfer(NearToken::from_yoctonear(refund_amount))
+        let sender = htlc.sender.clone();
+        self.internal_payout(&htlc, sender, refund_amount, 0, PayoutKind::Refund, previous_status).into()
     }
 
-    // Refund a partial fill after timeout
+    // Return an unclaimed part to the maker after timeout
     pub fn refund_partial_fill(&mut self, htlc_id: String, fill_id: String) -> Promise {
-        let htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
-        let current_time = env::block_timestamp() / 1_000_000_000;
-        
-        require!(current_time >= htlc.timelock.0, "Not expired");
+        let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
+        let stage = Self::current_stage(&htlc);
+        let refunder = env::predecessor_account_id();
+
+        require!(stage.is_cancellation(), "Not expired");
 
         // Find and update fill
-        let mut fills = self.partial_fills.get(&htlc_id).expect("No fills found");
-        let mut fill_index = None;
-        let mut filler = None;
-        let mut amount = 0u128;
-
-        for i in 0..fills.len() {
-            let fill = fills.get(i).unwrap();
-            if fill.id == fill_id {
-                require!(fill.status == FillStatus::Pending, "Fill already processed");
-                require!(env::predecessor_account_id() == fill.filler, "Not the filler");
-                fill_index = Some(i);
-                filler = Some(fill.filler.clone());
-                amount = fill.amount.0;
-                break;
-            }
-        }
+        let fill = self.get_fill(&htlc_id, &fill_id);
+        require!(fill.status == FillStatus::Pending, "Fill already processed");
+        require!(
+            stage.can_cancel(
+                refunder == htlc.sender,
+                refunder == fill.filler || htlc.resolvers.contains(&refunder)
+            ),
+            "Not authorized to cancel at this stage"
+        );
 
-        let idx = fill_index.expect("Fill not found");
-        let mut fill = fills.get(idx).unwrap();
-        fill.status = FillStatus::Refunded;
-        fills.replace(idx, &fill);
-        self.partial_fills.insert(&htlc_id, &fills);
+        self.settle_fill_refund(&mut htlc, fill)
+    }
 
-        // Update HTLC remaining amount
-        let mut htlc_mut = htlc.clone();
-        htlc_mut.remaining_amount = U128(htlc_mut.remaining_amount.0 + amount);
-        self.htlcs.insert(&htlc_id, &htlc_mut);
+    // Lets the maker take back an order before its timelock, as long as no
+    // resolver posted a safety deposit on it and nothing of it was filled.
+    // Returns the whole amount.
+    pub fn cancel_order(&mut self, htlc_id: String) -> Promise {
+        let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
+        let maker = env::predecessor_account_id();
+
+        require!(maker == htlc.sender, "Only the maker can cancel");
+        require!(htlc.status == HTLCStatus::Active || htlc.status == HTLCStatus::PartiallyFilled, "HTLC not active");
+        require!(htlc.resolvers.is_empty(), "Order already taken by a resolver");
+        require!(
+            htlc.remaining_amount == htlc.total_amount && htlc.pending_fills == 0,
+            "Order already partially filled"
+        );
+        let amount = htlc.total_amount.0;
+
+        let previous_status = htlc.status.clone();
+        htlc.status = HTLCStatus::Refunded;
+        htlc.remaining_amount = U128(0);
+        htlc.settled_by = Some(maker.clone());
+        htlc.settled_at = Some(U64(htlc_core::now_seconds()));
+        self.htlcs.insert(&htlc_id, &htlc);
+        self.active_htlcs.remove(&htlc_number(&htlc_id));
+        self.update_token_stats(&htlc.token_id, |stats| {
+            stats.active_htlcs = stats.active_htlcs.saturating_sub(1);
+            stats.refunded_htlcs += 1;
+            stats.refunded_volume = U128(stats.refunded_volume.0 + amount);
+        });
 
-        // Refund to filler
-        Promise::new(filler.unwrap()).transfer(NearToken::from_yoctonear(amount))
+        events::OrderCancelled { htlc_id, refunded_to: maker.clone(), amount: U128(amount) }.emit();
+
+        self.internal_payout(&htlc, maker, amount, 0, PayoutKind::Cancel, previous_status)
     }
 
-    // Create safety deposit
+    // Create safety deposit; it stays locked until the HTLC settles
     #[payable]
     pub fn create_safety_deposit(&mut self, htlc_id: String) -> String {
-        let htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
+        let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
         let depositor = env::predecessor_account_id();
         let amount = env::attached_deposit();
-        let current_time = env::block_timestamp() / 1_000_000_000;
+        let current_time = htlc_core::now_seconds();
+        let initial_storage = env::storage_usage();
 
+        self.assert_not_paused();
+        self.assert_authorized_resolver(&depositor);
+        require!(htlc.status == HTLCStatus::Active || htlc.status == HTLCStatus::PartiallyFilled, "HTLC not active");
         require!(htlc.safety_deposit_amount.0 > 0, "Safety deposit not required");
         require!(amount >= NearToken::from_yoctonear(htlc.safety_deposit_amount.0), "Insufficient deposit");
 
+        // The first resolver to deposit on an open order takes it and keeps
+        // it to itself for the exclusivity window
+        let exclusive = htlc.taken_until.is_some_and(|until| current_time < until.0);
+        if htlc.open_order && !exclusive {
+            require!(current_time < htlc.timelock.0, "HTLC expired");
+            self.take_order(&mut htlc, &depositor, current_time);
+        }
+        require!(
+            !htlc.open_order || htlc.receiver.as_ref() == Some(&depositor),
+            "Order taken by another resolver"
+        );
+
         let deposit_id = format!("deposit_{}", self.next_deposit_id);
         self.next_deposit_id += 1;
 
@@ -454,21 +596,39 @@ impl FusionPlusContract {
             depositor: depositor.clone(),
             amount: U128(amount.as_yoctonear()),
             created_at: U64(current_time),
+            storage_bytes: 0,
         };
 
-        self.safety_deposits.insert(&deposit_id, &deposit);
+        self.add_safety_deposit(&mut htlc, &deposit);
+        self.htlcs.insert(&htlc_id, &htlc);
+
+        let storage_bytes = self.charge_storage(&depositor, initial_storage);
+        self.safety_deposits.insert(&deposit_id, &SafetyDeposit { storage_bytes, ..deposit.clone() });
+
+        events::DepositCreated {
+            deposit_id: deposit_id.clone(),
+            htlc_id,
+            depositor,
+            amount: deposit.amount,
+        }.emit();
+
         deposit_id
     }
 
-    // Claim safety deposit
+    // Claim a safety deposit once its HTLC settled. Depositors get their
+    // deposits back, except the taker when it let its window pass: its
+    // deposits go to whoever withdrew or cancelled instead.
     pub fn claim_safety_deposit(&mut self, deposit_id: String) -> Promise {
         let deposit = self.safety_deposits.get(&deposit_id).expect("Deposit not found");
-        let claimer = env::predecessor_account_id();
+        let htlc = self.htlcs.get(&deposit.htlc_id).expect("HTLC not found");
 
-        require!(claimer == deposit.depositor, "Not the depositor");
+        require!(htlc.status == HTLCStatus::Completed || htlc.status == HTLCStatus::Refunded, "HTLC not settled");
+        require!(
+            Self::deposit_recipient(&htlc, &deposit) == env::predecessor_account_id(),
+            "Not entitled to the deposit"
+        );
 
-        self.safety_deposits.remove(&deposit_id);
-        Promise::new(claimer).transfer(NearToken::from_yoctonear(deposit.amount.0))
+        self.internal_deposit_payout(&htlc, deposit)
     }
 
     // View methods
@@ -481,41 +641,32 @@ impl FusionPlusContract {
             .and_then(|htlc_id| self.htlcs.get(&htlc_id))
     }
 
-    pub fn get_user_htlcs(&self, user: AccountId, offset: u64, limit: u64) -> Vec<FusionHTLC> {
-        self.user_htlcs.get(&user)
-            .map(|htlc_ids| {
-                htlc_ids.iter()
-                    .skip(offset as usize)
-                    .take(limit as usize)
-                    .filter_map(|id| self.htlcs.get(&id))
-                    .collect()
-            })
-            .unwrap_or_default()
+    // HTLCs the user sent or receives, oldest first; pages like `query_htlcs`
+    pub fn get_user_htlcs(&self, user: AccountId, from_cursor: Option<U64>, limit: Option<u32>) -> HTLCPage {
+        match self.user_htlcs.get(&user) {
+            Some(numbers) => self.index_page(&numbers, from_cursor, limit),
+            None => HTLCPage { htlcs: Vec::new(), next_cursor: None },
+        }
     }
 
-    pub fn get_active_htlcs(&self, offset: u64, limit: u64) -> Vec<FusionHTLC> {
-        self.active_htlcs.iter()
-            .skip(offset as usize)
-            .take(limit as usize)
-            .filter_map(|id| self.htlcs.get(&id))
-            .collect()
+    pub fn get_active_htlcs(&self, from_cursor: Option<U64>, limit: Option<u32>) -> HTLCPage {
+        self.index_page(&self.active_htlcs, from_cursor, limit)
     }
 
     pub fn get_partial_fills(&self, htlc_id: String) -> Vec<PartialFill> {
-        self.partial_fills.get(&htlc_id)
-            .map(|fills| fills.iter().collect())
+        self.htlc_fills.get(&htlc_id)
+            .map(|fill_ids| fill_ids.iter().filter_map(|id| self.partial_fills.get(&id)).collect())
             .unwrap_or_default()
     }
 
-    pub fn get_stats(&self) -> (U128, u64, u64) {
-        (self.total_volume, self.total_htlcs_created, self.active_htlcs.len())
+    pub fn get_htlc_stage(&self, htlc_id: String) -> Option<Stage> {
+        self.htlcs.get(&htlc_id).map(|htlc| Self::current_stage(&htlc))
     }
 
     pub fn can_withdraw(&self, htlc_id: String) -> bool {
         if let Some(htlc) = self.htlcs.get(&htlc_id) {
-            let current_time = env::block_timestamp() / 1_000_000_000;
             (htlc.status == HTLCStatus::Active || htlc.status == HTLCStatus::PartiallyFilled) 
-                && current_time < htlc.timelock.0
+                && Self::current_stage(&htlc).is_withdrawal()
         } else {
             false
         }
@@ -523,143 +674,1132 @@ impl FusionPlusContract {
 
     pub fn can_refund(&self, htlc_id: String) -> bool {
         if let Some(htlc) = self.htlcs.get(&htlc_id) {
-            let current_time = env::block_timestamp() / 1_000_000_000;
             htlc.status != HTLCStatus::Completed 
                 && htlc.status != HTLCStatus::Refunded 
-                && current_time >= htlc.timelock.0
+                && Self::current_stage(&htlc).is_cancellation()
         } else {
             false
         }
     }
 
     // Helper methods
-    fn verify_secret(&self, secret: &str, hashlock: &str) {
-        let secret_bytes = hex::decode(secret).expect("Invalid hex secret");
-        let mut hasher = Sha256::new();
-        hasher.update(&secret_bytes);
-        let hash = hex::encode(hasher.finalize());
-        require!(hash == hashlock, "Invalid secret");
+    pub(crate) fn internal_create_htlc(
+        &mut self,
+        sender: AccountId,
+        token_id: Option<AccountId>,
+        amount: u128,
+        args: CreateHTLCArgs,
+    ) -> String {
+        self.assert_not_paused();
+        self.check_create_args(&sender, amount, &args).unwrap_or_else(|e| env::panic_str(e));
+        let durations = args.durations();
+        let CreateHTLCArgs {
+            receiver,
+            hashlock,
+            allow_partial_fills,
+            parts_count,
+            min_fill_amount,
+            require_safety_deposit,
+            hash_algorithm,
+            ..
+        } = args;
+        let current_time = htlc_core::now_seconds();
+        let initial_storage = env::storage_usage();
+
+        let (min_fill, parts_count) = if allow_partial_fills {
+            let min = min_fill_amount.unwrap_or(U128(amount / 10)); // Default 10%
+            (min, parts_count.expect("Missing parts count"))
+        } else {
+            (U128(amount), 1)
+        };
+
+        let htlc_id = htlc_id(self.next_htlc_id);
+        self.next_htlc_id += 1;
+        let timelocks = Timelocks::new(current_time, &durations);
+
+        let htlc = FusionHTLC {
+            id: htlc_id.clone(),
+            sender: sender.clone(),
+            receiver: receiver.clone(),
+            open_order: receiver.is_none(),
+            taken_until: None,
+            token_id: token_id.clone(),
+            total_amount: U128(amount),
+            remaining_amount: U128(amount),
+            hashlock: hashlock.clone(),
+            hash_algorithm,
+            timelock: timelocks.cancellation_start,
+            timelocks: timelocks.clone(),
+            secret: None,
+            allow_partial_fills,
+            parts_count,
+            min_fill_amount: min_fill,
+            // Open orders are taken with a safety deposit
+            safety_deposit_amount: U128(if require_safety_deposit || receiver.is_none() { amount / 20 } else { 0 }),
+            pending_fills: 0,
+            resolvers: Vec::new(),
+            settled_by: None,
+            taker_lapsed: false,
+            status: HTLCStatus::Active,
+            created_at: U64(current_time),
+            settled_at: None,
+            storage_bytes: 0,
+        };
+
+        // Store HTLC
+        self.htlcs.insert(&htlc_id, &htlc);
+        self.secret_to_htlc.insert(&hashlock, &htlc_id);
+        self.active_htlcs.insert(&htlc_number(&htlc_id), &());
+
+        // Track user HTLCs
+        self.add_user_htlc(&sender, &htlc_id);
+        if let Some(receiver) = &receiver {
+            self.add_user_htlc(receiver, &htlc_id);
+        }
+
+        // Update stats
+        self.update_token_stats(&token_id, |stats| {
+            stats.htlcs_created += 1;
+            stats.active_htlcs += 1;
+            stats.volume = U128(stats.volume.0 + amount);
+        });
+
+        // Initialize the fill index if needed
+        if allow_partial_fills {
+            let fills_key = StorageKey::PartialFills { htlc_id_hash: env::sha256(htlc_id.as_bytes()) };
+            self.htlc_fills.insert(&htlc_id, &UnorderedSet::new(fills_key));
+        }
+
+        // Charge the sender for the storage the HTLC occupies
+        let storage_bytes = self.charge_storage(&sender, initial_storage);
+        self.htlcs.insert(&htlc_id, &FusionHTLC { storage_bytes, ..htlc });
+
+        // Emit event
+        events::HtlcCreated {
+            htlc_id: htlc_id.clone(),
+            sender,
+            receiver,
+            token_id,
+            amount: U128(amount),
+            hashlock,
+            hash_algorithm,
+            timelock: timelocks.cancellation_start,
+            allow_partial_fills,
+            parts_count,
+        }.emit();
+
+        htlc_id
     }
 
-    fn add_user_htlc(&mut self, user: &AccountId, htlc_id: &str) {
-        let mut user_htlcs = self.user_htlcs.get(user)
-            .unwrap_or_else(|| {
-                let key = format!("user_htlcs_{}", user);
-                Vector::new(key.as_bytes())
-            });
-        user_htlcs.push(&htlc_id.to_string());
-        self.user_htlcs.insert(user, &user_htlcs);
-    }
-
-    fn remove_from_active(&mut self, htlc_id: &str) {
-        let mut new_active = Vector::new(StorageKey::ActiveHTLCs);
-        for i in 0..self.active_htlcs.len() {
-            let id = self.active_htlcs.get(i).unwrap();
-            if id != htlc_id {
-                new_active.push(&id);
+    // Everything `internal_create_htlc` checks before writing, so a batch can
+    // skip a bad item instead of aborting
+    fn check_create_args(&self, sender: &AccountId, amount: u128, args: &CreateHTLCArgs) -> Result<(), &'static str> {
+        if amount == 0 {
+            return Err("Amount must be greater than 0");
+        }
+        if !htlc_core::is_valid_hashlock(&args.hashlock) {
+            return Err("Invalid hashlock length");
+        }
+        // A second HTLC under the same hashlock would be unlocked by the
+        // same secret
+        if self.secret_to_htlc.contains_key(&args.hashlock) {
+            return Err("Hashlock already used");
+        }
+        if !htlc_core::is_valid_timelock(args.durations().withdrawal_window()) {
+            return Err("Invalid timelock");
+        }
+        if args.allow_partial_fills {
+            let min = args.min_fill_amount.map_or(amount / 10, |min| min.0); // Default 10%
+            if min == 0 || min > amount {
+                return Err("Invalid min fill amount");
+            }
+            match args.parts_count {
+                None => return Err("Missing parts count"),
+                Some(parts) if parts == 0 || parts > MAX_PARTS_COUNT => return Err("Invalid parts count"),
+                Some(_) => {}
             }
         }
-        self.active_htlcs = new_active;
+        if self.storage_accounts.get(sender).is_none() {
+            return Err("Account not registered for storage");
+        }
+        Ok(())
     }
 
-    fn all_fills_completed(&self, htlc_id: &str) -> bool {
-        self.partial_fills.get(&htlc_id.to_string())
-            .map(|fills| {
-                fills.iter().all(|fill| fill.status == FillStatus::Completed)
-            })
-            .unwrap_or(true)
-    }
+    fn check_withdraw(&self, htlc_id: &str, secret: &str, withdrawer: &AccountId) -> Result<FusionHTLC, &'static str> {
+        let htlc = self.htlcs.get(&htlc_id.to_string()).ok_or("HTLC not found")?;
+        let stage = Self::current_stage(&htlc);
 
-    fn get_fills_key(htlc_id: &str) -> Vec<u8> {
-        format!("fills_{}", htlc_id).into_bytes()
+        if htlc.status != HTLCStatus::Active && htlc.status != HTLCStatus::PartiallyFilled {
+            return Err("HTLC not active");
+        }
+        if stage.is_cancellation() {
+            return Err("HTLC expired");
+        }
+        if stage == Stage::Pending {
+            return Err("HTLC not final");
+        }
+        let Some(receiver) = &htlc.receiver else {
+            return Err("Order not taken");
+        };
+        if !stage.can_withdraw(withdrawer == receiver, htlc.resolvers.contains(withdrawer)) {
+            return Err("Not authorized to withdraw at this stage");
+        }
+        if htlc.allow_partial_fills {
+            return Err("Use withdraw_partial for partial fills");
+        }
+        htlc_core::check_secret(secret, &htlc.hashlock, htlc.hash_algorithm)?;
+        Ok(htlc)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::testing_env;
 
-    #[test]
-    fn test_create_and_withdraw_htlc() {
-        let context = VMContextBuilder::new()
-            .current_account_id(accounts(0))
-            .predecessor_account_id(accounts(1))
-            .attached_deposit(NearToken::from_near(1))
-            .block_timestamp(1_000_000_000_000_000_000)
-            .build();
-        testing_env!(context);
+    // Marks a checked HTLC withdrawn; the caller pays the receiver.
+    // `authorized_by` is whose stage rights the withdrawal used, the receiver
+    // for a relayed one.
+    fn settle_withdraw(
+        &mut self,
+        mut htlc: FusionHTLC,
+        secret: String,
+        withdrawer: AccountId,
+        authorized_by: &AccountId,
+    ) -> (FusionHTLC, HTLCStatus) {
+        let current_time = htlc_core::now_seconds();
+        let previous_status = htlc.status.clone();
+        htlc.status = HTLCStatus::Completed;
+        htlc.secret = Some(secret.clone());
+        htlc.settled_by = Some(withdrawer.clone());
+        htlc.settled_at = Some(U64(current_time));
+        htlc.taker_lapsed =
+            htlc.receiver.as_ref() != Some(authorized_by) && Self::taker_window_passed(&htlc, current_time);
+        self.htlcs.insert(&htlc.id, &htlc);
+        self.active_htlcs.remove(&htlc_number(&htlc.id));
+        self.update_token_stats(&htlc.token_id, |stats| {
+            stats.active_htlcs = stats.active_htlcs.saturating_sub(1);
+            stats.completed_htlcs += 1;
+            stats.withdrawn_volume = U128(stats.withdrawn_volume.0 + htlc.total_amount.0);
+        });
+        self.record_resolver_fill(&withdrawer, htlc.total_amount.0);
+
+        events::HtlcWithdrawn {
+            htlc_id: htlc.id.clone(),
+            secret,
+            withdrawn_by: withdrawer,
+            amount: htlc.total_amount,
+        }.emit();
 
-        let mut contract = FusionPlusContract::new();
+        (htlc, previous_status)
+    }
 
-        let secret = "mysecret";
-        let mut hasher = Sha256::new();
-        hasher.update(secret.as_bytes());
-        let hashlock = hex::encode(hasher.finalize());
+    fn check_refund(&self, htlc_id: &str, refunder: &AccountId) -> Result<FusionHTLC, &'static str> {
+        let htlc = self.htlcs.get(&htlc_id.to_string()).ok_or("HTLC not found")?;
+        let stage = Self::current_stage(&htlc);
 
-        let htlc_id = contract.create_htlc(
-            accounts(2),
-            hashlock.clone(),
-            3600,
-            false,
-            None,
-            false,
-        );
+        if !stage.is_cancellation() {
+            return Err("Not expired");
+        }
+        if !stage.can_cancel(*refunder == htlc.sender, htlc.resolvers.contains(refunder)) {
+            return Err("Not authorized to cancel at this stage");
+        }
+        if htlc.status == HTLCStatus::Completed || htlc.status == HTLCStatus::Refunded {
+            return Err("Already processed");
+        }
+        Ok(htlc)
+    }
 
-        assert!(contract.get_htlc(htlc_id.clone()).is_some());
-        assert!(contract.can_withdraw(htlc_id.clone()));
+    // Marks a checked HTLC refunded and returns what goes back to the sender
+    fn settle_refund(&mut self, mut htlc: FusionHTLC, refunder: AccountId) -> (FusionHTLC, u128, HTLCStatus) {
+        let current_time = htlc_core::now_seconds();
+        let refund_amount = Self::refund_amount(&htlc);
+        let previous_status = htlc.status.clone();
+        htlc.status = HTLCStatus::Refunded;
+        htlc.settled_by = Some(refunder);
+        htlc.settled_at = Some(U64(current_time));
+        htlc.taker_lapsed = htlc.receiver.is_some() && Self::taker_window_passed(&htlc, current_time);
+        self.htlcs.insert(&htlc.id, &htlc);
+        self.unindex_if_done(&htlc);
+        self.update_token_stats(&htlc.token_id, |stats| {
+            stats.active_htlcs = stats.active_htlcs.saturating_sub(1);
+            stats.refunded_htlcs += 1;
+            stats.refunded_volume = U128(stats.refunded_volume.0 + refund_amount);
+        });
+
+        events::HtlcRefunded {
+            htlc_id: htlc.id.clone(),
+            refunded_to: htlc.sender.clone(),
+            amount: U128(refund_amount),
+        }.emit();
+
+        (htlc, refund_amount, previous_status)
+    }
 
-        // Switch to receiver
-        testing_env!(VMContextBuilder::new()
-            .current_account_id(accounts(0))
-            .predecessor_account_id(accounts(2))
-            .block_timestamp(1_000_000_000_000_000_000 + 1800_000_000_000)
-            .build());
+    // Marks a checked pending fill refunded and pays its part back to the
+    // maker
+    fn settle_fill_refund(&mut self, htlc: &mut FusionHTLC, mut fill: PartialFill) -> Promise {
+        fill.status = FillStatus::Refunded;
+        self.partial_fills.insert(&fill.id, &fill);
+        htlc.pending_fills -= 1;
+        htlc.settled_at = Some(U64(htlc_core::now_seconds()));
+        self.htlcs.insert(&htlc.id, htlc);
+        self.unindex_if_done(htlc);
+        self.update_token_stats(&htlc.token_id, |stats| {
+            stats.refunded_volume = U128(stats.refunded_volume.0 + fill.amount.0);
+        });
+
+        events::FillRefunded {
+            fill_id: fill.id.clone(),
+            htlc_id: htlc.id.clone(),
+            refunded_to: htlc.sender.clone(),
+            amount: fill.amount,
+        }.emit();
+
+        // Refund to the maker
+        let previous_status = htlc.status.clone();
+        self.internal_payout(
+            htlc,
+            htlc.sender.clone(),
+            fill.amount.0,
+            0,
+            PayoutKind::FillRefund { fill_id: fill.id },
+            previous_status,
+        )
+    }
+
+    // Takes a settled HTLC out of `active_htlcs` once no fill of it is
+    // pending; until then sweeps still have to find its fills
+    fn unindex_if_done(&mut self, htlc: &FusionHTLC) {
+        let settled = htlc.status == HTLCStatus::Completed || htlc.status == HTLCStatus::Refunded;
+        if settled && htlc.pending_fills == 0 {
+            self.active_htlcs.remove(&htlc_number(&htlc.id));
+        }
+    }
+
+    // What a refund returns to the sender. Parts already taken by fillers are
+    // refunded fill by fill.
+    fn refund_amount(htlc: &FusionHTLC) -> u128 {
+        if htlc.allow_partial_fills {
+            htlc.remaining_amount.0
+        } else {
+            htlc.total_amount.0
+        }
+    }
+
+    fn current_stage(htlc: &FusionHTLC) -> Stage {
+        htlc.timelocks.stage_at(htlc_core::now_seconds())
+    }
+
+    // Whether a withdrawal window the taker had to itself, or shared only
+    // with the HTLC's resolvers, is over
+    pub(crate) fn taker_window_passed(htlc: &FusionHTLC, current_time: u64) -> bool {
+        let Timelocks { finality_time, taker_deadline, public_deadline, .. } = &htlc.timelocks;
+        (taker_deadline.0 > finality_time.0 && current_time >= taker_deadline.0)
+            || (public_deadline.0 > finality_time.0 && current_time >= public_deadline.0)
+    }
+
+    // Who a safety deposit of a settled HTLC goes to: back to its depositor,
+    // unless that is the taker that let its window pass
+    pub(crate) fn deposit_recipient(htlc: &FusionHTLC, deposit: &SafetyDeposit) -> AccountId {
+        match &htlc.settled_by {
+            Some(settler) if htlc.taker_lapsed && htlc.receiver.as_ref() == Some(&deposit.depositor) => settler.clone(),
+            _ => deposit.depositor.clone(),
+        }
+    }
+
+    // Pins `taker` as the receiver of an open order until the exclusivity
+    // window ends; after that the next resolver to deposit takes it over.
+    // A taker that lost the order is no longer one of its resolvers, but its
+    // safety deposit stays with the order.
+    fn take_order(&mut self, htlc: &mut FusionHTLC, taker: &AccountId, current_time: u64) {
+        let taken_until = U64(current_time + self.resolver_config.take_exclusivity_seconds);
+        if let Some(previous) = htlc.receiver.replace(taker.clone()).filter(|previous| previous != taker) {
+            htlc.resolvers.retain(|resolver| *resolver != previous);
+            Self::unindex_htlc(&mut self.resolver_htlcs, &previous, &htlc.id);
+            if previous != htlc.sender {
+                Self::unindex_htlc(&mut self.user_htlcs, &previous, &htlc.id);
+            }
+        }
+        htlc.taken_until = Some(taken_until);
+        self.add_user_htlc(taker, &htlc.id);
+
+        events::OrderTaken { htlc_id: htlc.id.clone(), taker: taker.clone(), taken_until }.emit();
+    }
+
+    fn add_user_htlc(&mut self, user: &AccountId, htlc_id: &str) {
+        Self::index_htlc(&mut self.user_htlcs, |user_hash| StorageKey::UserHTLCNumbers { user_hash }, user, htlc_id);
+    }
+
+    // Adds an HTLC to an account's entry in one of the per-account indexes;
+    // `key` builds the entry's storage prefix from the account hash
+    fn index_htlc(index: &mut HTLCIndex, key: fn(Vec<u8>) -> StorageKey, account_id: &AccountId, htlc_id: &str) {
+        let mut numbers =
+            index.get(account_id).unwrap_or_else(|| TreeMap::new(key(env::sha256(account_id.as_bytes()))));
+        numbers.insert(&htlc_number(htlc_id), &());
+        index.insert(account_id, &numbers);
+    }
+
+    fn unindex_htlc(index: &mut HTLCIndex, account_id: &AccountId, htlc_id: &str) {
+        if let Some(mut numbers) = index.get(account_id) {
+            numbers.remove(&htlc_number(htlc_id));
+            if numbers.is_empty() {
+                index.remove(account_id);
+            } else {
+                index.insert(account_id, &numbers);
+            }
+        }
+    }
+
+    // Stores a deposit and makes its depositor one of the HTLC's resolvers;
+    // the caller writes `htlc` back
+    fn add_safety_deposit(&mut self, htlc: &mut FusionHTLC, deposit: &SafetyDeposit) {
+        self.safety_deposits.insert(&deposit.id, deposit);
+        let mut deposit_ids = self.htlc_deposits.get(&htlc.id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::HTLCDeposits { htlc_id_hash: env::sha256(htlc.id.as_bytes()) })
+        });
+        deposit_ids.insert(&deposit.id);
+        self.htlc_deposits.insert(&htlc.id, &deposit_ids);
+
+        if !htlc.resolvers.contains(&deposit.depositor) {
+            htlc.resolvers.push(deposit.depositor.clone());
+            Self::index_htlc(
+                &mut self.resolver_htlcs,
+                |account_hash| StorageKey::ResolverHTLCs { account_hash },
+                &deposit.depositor,
+                &htlc.id,
+            );
+        }
+    }
+
+    fn remove_safety_deposit(&mut self, deposit_id: &String, deposit: &SafetyDeposit) {
+        self.safety_deposits.remove(deposit_id);
+        if let Some(mut deposit_ids) = self.htlc_deposits.get(&deposit.htlc_id) {
+            deposit_ids.remove(deposit_id);
+            self.htlc_deposits.insert(&deposit.htlc_id, &deposit_ids);
+        }
+        self.release_storage(&deposit.depositor, deposit.storage_bytes);
+    }
+
+    fn get_fill(&self, htlc_id: &str, fill_id: &str) -> PartialFill {
+        self.partial_fills.get(&fill_id.to_string())
+            .filter(|fill| fill.htlc_id == htlc_id)
+            .expect("Fill not found")
+    }
+
+    fn decode_hash(value: &str) -> merkle::Hash {
+        hex::decode(value)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .expect("Invalid hash")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+    use sha2::{Digest, Sha256};
+
+    use near_contract_standards::storage_management::StorageManagement;
+
+    pub(crate) fn register_storage(contract: &mut FusionPlusContract, account_id: AccountId) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(account_id)
+            .attached_deposit(NearToken::from_near(1))
+            .build());
+        contract.storage_deposit(None, None);
+    }
+
+    // Secrets, secret hashes and Merkle leaves for an order of `parts` parts
+    fn part_secrets(parts: u32) -> (Vec<String>, Vec<String>, Vec<merkle::Hash>) {
+        let secrets: Vec<String> = (0..=parts).map(|i| hex::encode([i as u8 + 1; 32])).collect();
+        let hashes: Vec<String> = secrets
+            .iter()
+            .map(|s| hex::encode(Sha256::digest(hex::decode(s).unwrap())))
+            .collect();
+        let leaves = hashes
+            .iter()
+            .enumerate()
+            .map(|(i, h)| merkle::leaf(HashAlgorithm::Sha256, i as u32, &hex::decode(h).unwrap()))
+            .collect();
+        (secrets, hashes, leaves)
+    }
+
+    fn proof_hex(leaves: &[merkle::Hash], index: usize) -> Vec<String> {
+        merkle::proof(HashAlgorithm::Sha256, leaves, index).iter().map(hex::encode).collect()
+    }
+
+    #[test]
+    fn test_create_and_withdraw_htlc() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+
+        let context = VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build();
+        testing_env!(context);
+
+        let secret = "mysecret";
+        let mut hasher = Sha256::new();
+        hasher.update(secret.as_bytes());
+        let hashlock = hex::encode(hasher.finalize());
+
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hashlock.clone(),
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+
+        assert!(contract.get_htlc(htlc_id.clone()).is_some());
+        assert!(contract.can_withdraw(htlc_id.clone()));
+
+        // Switch to receiver
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(1_000_000_000_000_000_000 + 1_800_000_000_000)
+            .build());
 
         contract.withdraw(htlc_id.clone(), hex::encode(secret));
         
         let htlc = contract.get_htlc(htlc_id).unwrap();
         assert_eq!(htlc.status, HTLCStatus::Completed);
+        assert!(contract.get_active_htlcs(None, None).htlcs.is_empty());
+        assert_eq!(contract.get_user_htlcs(accounts(2), None, None).htlcs.len(), 1);
     }
 
     #[test]
-    fn test_partial_fills() {
-        let context = VMContextBuilder::new()
+    fn test_keccak256_hashlock() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        testing_env!(VMContextBuilder::new()
             .current_account_id(accounts(0))
             .predecessor_account_id(accounts(1))
-            .attached_deposit(NearToken::from_near(10))
+            .attached_deposit(NearToken::from_near(1))
             .block_timestamp(1_000_000_000_000_000_000)
-            .build();
-        testing_env!(context);
+            .build());
+
+        let hashlock = hex::encode(env::keccak256(&[5u8; 32]));
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock,
+            timelock_seconds: 3600,
+            hash_algorithm: HashAlgorithm::Keccak256,
+            ..Default::default()
+        });
+        assert_eq!(contract.get_htlc(htlc_id.clone()).unwrap().hash_algorithm, HashAlgorithm::Keccak256);
+
+        at_seconds(accounts(2), 60);
+        contract.withdraw(htlc_id.clone(), hex::encode([5u8; 32]));
+        assert_eq!(contract.get_htlc(htlc_id).unwrap().status, HTLCStatus::Completed);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid secret")]
+    fn test_keccak256_hashlock_rejects_sha256_hashlock() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+
+        // A SHA-256 hashlock does not open under Keccak-256
+        let hashlock = hex::encode(Sha256::digest([5u8; 32]));
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock,
+            timelock_seconds: 3600,
+            hash_algorithm: HashAlgorithm::Keccak256,
+            ..Default::default()
+        });
+
+        at_seconds(accounts(2), 60);
+        contract.withdraw(htlc_id, hex::encode([5u8; 32]));
+    }
+
+    fn staged_htlc(contract: &mut FusionPlusContract, hashlock: String, require_safety_deposit: bool) -> String {
+        register_storage(contract, accounts(1));
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+
+        contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock,
+            timelock_seconds: 0,
+            require_safety_deposit,
+            stage_durations: Some(StageDurations {
+                finality_delay: 600,
+                taker_exclusive_duration: 1200,
+                private_resolver_duration: 1200,
+                public_resolver_duration: 1200,
+                private_cancellation_duration: 1200,
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn at_seconds(predecessor: AccountId, seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .block_timestamp(1_000_000_000_000_000_000 + seconds * 1_000_000_000)
+            .build());
+    }
 
+    #[test]
+    fn test_staged_public_withdraw_and_cancel() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
         let mut contract = FusionPlusContract::new();
-        let hashlock = hex::encode([1u8; 32]);
+        let secret = hex::encode([9u8; 32]);
+        let hashlock = hex::encode(Sha256::digest([9u8; 32]));
+
+        let htlc_id = staged_htlc(&mut contract, hashlock.clone(), false);
+        at_seconds(accounts(5), 0);
+        assert_eq!(contract.get_htlc_stage(htlc_id.clone()), Some(Stage::Pending));
+        assert!(!contract.can_withdraw(htlc_id.clone()));
+
+        // Any account can settle for the receiver once the public period opens
+        at_seconds(accounts(5), 3000);
+        assert_eq!(contract.get_htlc_stage(htlc_id.clone()), Some(Stage::PublicSettlement));
+        contract.withdraw(htlc_id.clone(), secret);
+        assert_eq!(contract.get_htlc(htlc_id).unwrap().status, HTLCStatus::Completed);
+
+        // Any account can cancel for the sender once public cancellation opens
+        let htlc_id = staged_htlc(&mut contract, hex::encode([8u8; 32]), false);
+        at_seconds(accounts(5), 4300);
+        assert_eq!(contract.get_htlc_stage(htlc_id.clone()), Some(Stage::PrivateCancellation));
+        assert!(contract.can_refund(htlc_id.clone()));
+
+        at_seconds(accounts(5), 5400);
+        contract.refund(htlc_id.clone());
+        assert_eq!(contract.get_htlc(htlc_id).unwrap().status, HTLCStatus::Refunded);
+    }
 
-        let htlc_id = contract.create_htlc(
-            accounts(2),
+    #[test]
+    #[should_panic(expected = "Not authorized to withdraw at this stage")]
+    fn test_staged_withdraw_is_exclusive_to_taker() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_id = staged_htlc(&mut contract, hex::encode(Sha256::digest([9u8; 32])), false);
+
+        at_seconds(accounts(5), 1000);
+        contract.withdraw(htlc_id, hex::encode([9u8; 32]));
+    }
+
+    // HTLC for an implicit receiver and that receiver's signature letting
+    // anyone withdraw it to accounts(4) for a tip of 1000 yocto
+    fn signed_withdraw(contract: &mut FusionPlusContract, signer: [u8; 32]) -> (String, String) {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let receiver_key = SigningKey::from_bytes(&[7u8; 32]);
+        let receiver: AccountId = hex::encode(receiver_key.verifying_key().to_bytes()).parse().unwrap();
+        register_storage(contract, accounts(1));
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let hashlock = hex::encode(Sha256::digest([9u8; 32]));
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(receiver),
             hashlock,
-            3600,
-            true,
-            Some(U128(NearToken::from_near(1).as_yoctonear())),
-            false,
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+
+        let authorization = WithdrawAuthorization {
+            contract_id: accounts(0),
+            htlc_id: htlc_id.clone(),
+            payout: accounts(4),
+            tip: 1000,
+            expiry: 1_000_000_000 + 600,
+        };
+        let signature = SigningKey::from_bytes(&signer).sign(&near_sdk::borsh::to_vec(&authorization).unwrap());
+        (htlc_id, hex::encode(signature.to_bytes()))
+    }
+
+    #[test]
+    fn test_withdraw_for_with_receiver_signature() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let (htlc_id, signature) = signed_withdraw(&mut contract, [7u8; 32]);
+
+        at_seconds(accounts(3), 60);
+        contract.withdraw_for(htlc_id.clone(), hex::encode([9u8; 32]), accounts(4), U128(1000), U64(1_000_000_600), signature);
+
+        let htlc = contract.get_htlc(htlc_id).unwrap();
+        assert_eq!(htlc.status, HTLCStatus::Completed);
+        assert_eq!(htlc.settled_by, Some(accounts(3)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid signature")]
+    fn test_withdraw_for_rejects_other_signer() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let (htlc_id, signature) = signed_withdraw(&mut contract, [8u8; 32]);
+
+        at_seconds(accounts(3), 60);
+        contract.withdraw_for(htlc_id, hex::encode([9u8; 32]), accounts(4), U128(1000), U64(1_000_000_600), signature);
+    }
+
+    // The taker and another resolver post safety deposits, then the taker
+    // lets the order slip into the public period
+    fn deposit_and_settle_publicly(contract: &mut FusionPlusContract) -> (String, String) {
+        let htlc_id = staged_htlc(contract, hex::encode(Sha256::digest([9u8; 32])), true);
+        let mut deposit_ids = vec![];
+        for resolver in [accounts(2), accounts(3)] {
+            register_storage(contract, resolver.clone());
+            testing_env!(VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(resolver)
+                .attached_deposit(NearToken::from_millinear(50))
+                .block_timestamp(1_000_000_000_000_000_000)
+                .build());
+            deposit_ids.push(contract.create_safety_deposit(htlc_id.clone()));
+        }
+
+        at_seconds(accounts(5), 3000);
+        contract.withdraw(htlc_id, hex::encode([9u8; 32]));
+        (deposit_ids[0].clone(), deposit_ids[1].clone())
+    }
+
+    #[test]
+    fn test_public_settler_claims_lapsed_taker_deposit() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let (taker_deposit, _) = deposit_and_settle_publicly(&mut contract);
+
+        at_seconds(accounts(5), 3000);
+        contract.claim_safety_deposit(taker_deposit.clone());
+        assert!(contract.safety_deposits.get(&taker_deposit).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Not entitled to the deposit")]
+    fn test_lapsed_taker_cannot_reclaim_deposit() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let (taker_deposit, _) = deposit_and_settle_publicly(&mut contract);
+
+        at_seconds(accounts(2), 3000);
+        contract.claim_safety_deposit(taker_deposit);
+    }
+
+    #[test]
+    fn test_other_resolver_gets_deposit_back() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let (_, resolver_deposit) = deposit_and_settle_publicly(&mut contract);
+        let deposit = contract.safety_deposits.get(&resolver_deposit).unwrap();
+
+        at_seconds(accounts(3), 3000);
+        contract.claim_safety_deposit(resolver_deposit.clone());
+        assert!(get_logs().iter().any(|log| log.contains(r#""event":"deposit_claimed""#)
+            && log.contains(&format!(r#""claimed_by":"{}""#, accounts(3)))));
+
+        // Held until the transfer lands, put back if it fails
+        testing_env!(
+            VMContextBuilder::new().current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![near_sdk::PromiseResult::Failed],
         );
+        assert!(!contract.resolve_deposit_payout(deposit));
+        assert!(contract.safety_deposits.get(&resolver_deposit).is_some());
+        assert!(get_logs()[0].contains(r#""event":"deposit_payout_failed""#));
+    }
+
+    #[test]
+    #[should_panic(expected = "HTLC not settled")]
+    fn test_safety_deposit_locked_until_settlement() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_id = staged_htlc(&mut contract, hex::encode([8u8; 32]), true);
+        register_storage(&mut contract, accounts(3));
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(NearToken::from_millinear(50))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let deposit_id = contract.create_safety_deposit(htlc_id);
+
+        contract.claim_safety_deposit(deposit_id);
+    }
+
+    #[test]
+    fn test_keccak256_partial_fill_tree() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        for account_id in [accounts(1), accounts(3)] {
+            register_storage(&mut contract, account_id);
+        }
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(4))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let secrets: Vec<[u8; 32]> = (0..=2u8).map(|i| [i + 1; 32]).collect();
+        let hashes: Vec<Vec<u8>> = secrets.iter().map(|s| env::keccak256(s)).collect();
+        let leaves: Vec<merkle::Hash> = hashes
+            .iter()
+            .enumerate()
+            .map(|(i, h)| merkle::leaf(HashAlgorithm::Keccak256, i as u32, h))
+            .collect();
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode(merkle::root(HashAlgorithm::Keccak256, &leaves)),
+            hash_algorithm: HashAlgorithm::Keccak256,
+            timelock_seconds: 3600,
+            allow_partial_fills: true,
+            parts_count: Some(2),
+            min_fill_amount: Some(U128(NearToken::from_near(2).as_yoctonear())),
+            ..Default::default()
+        });
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let fill_id = contract.create_partial_fill(
+            htlc_id.clone(),
+            U128(NearToken::from_near(2).as_yoctonear()),
+            0,
+            hex::encode(&hashes[0]),
+            merkle::proof(HashAlgorithm::Keccak256, &leaves, 0).iter().map(hex::encode).collect(),
+        );
+        at_seconds(accounts(3), 60);
+        contract.withdraw_partial(htlc_id.clone(), fill_id, hex::encode(secrets[0]));
+        assert_eq!(contract.get_partial_fills(htlc_id)[0].status, FillStatus::Completed);
+    }
+
+    #[test]
+    fn test_partial_fills() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        for account_id in [accounts(1), accounts(3), accounts(4)] {
+            register_storage(&mut contract, account_id);
+        }
 
-        // Create partial fill
+        let context = VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(10))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build();
+        testing_env!(context);
+        let (secrets, hashes, leaves) = part_secrets(4);
+
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode(merkle::root(HashAlgorithm::Sha256, &leaves)),
+            timelock_seconds: 3600,
+            allow_partial_fills: true,
+            parts_count: Some(4),
+            min_fill_amount: Some(U128(NearToken::from_near(1).as_yoctonear())),
+            ..Default::default()
+        });
+
+        // 30% reaches the second quarter, so secret 1 is used
         testing_env!(VMContextBuilder::new()
             .current_account_id(accounts(0))
             .predecessor_account_id(accounts(3))
-            .attached_deposit(NearToken::from_near(3))
             .block_timestamp(1_000_000_000_000_000_000)
             .build());
 
         let fill_id = contract.create_partial_fill(
             htlc_id.clone(),
             U128(NearToken::from_near(3).as_yoctonear()),
+            1,
+            hashes[1].clone(),
+            proof_hex(&leaves, 1),
         );
 
         let fills = contract.get_partial_fills(htlc_id.clone());
         assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].id, fill_id);
         assert_eq!(fills[0].amount.0, NearToken::from_near(3).as_yoctonear());
+
+        // A second resolver completes the order with the extra secret 4
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(4))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+
+        let last_fill_id = contract.create_partial_fill(
+            htlc_id.clone(),
+            U128(NearToken::from_near(7).as_yoctonear()),
+            4,
+            hashes[4].clone(),
+            proof_hex(&leaves, 4),
+        );
+        assert_eq!(contract.get_htlc(htlc_id.clone()).unwrap().remaining_amount.0, 0);
+
+        // Each filler unlocks only its own part
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(1_000_000_000_000_000_000 + 1_800_000_000_000)
+            .build());
+        contract.withdraw_partial(htlc_id.clone(), fill_id, secrets[1].clone());
+        assert_eq!(contract.get_htlc(htlc_id.clone()).unwrap().status, HTLCStatus::PartiallyFilled);
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(4))
+            .block_timestamp(1_000_000_000_000_000_000 + 1_800_000_000_000)
+            .build());
+        contract.withdraw_partial(htlc_id.clone(), last_fill_id, secrets[4].clone());
+        assert_eq!(contract.get_htlc(htlc_id).unwrap().status, HTLCStatus::Completed);
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[should_panic(expected = "Invalid secret")]
+    fn test_partial_fill_rejects_other_part_secret() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        register_storage(&mut contract, accounts(3));
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(10))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let (secrets, hashes, leaves) = part_secrets(4);
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode(merkle::root(HashAlgorithm::Sha256, &leaves)),
+            timelock_seconds: 3600,
+            allow_partial_fills: true,
+            parts_count: Some(4),
+            ..Default::default()
+        });
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let fill_id = contract.create_partial_fill(
+            htlc_id.clone(),
+            U128(NearToken::from_near(2).as_yoctonear()),
+            0,
+            hashes[0].clone(),
+            proof_hex(&leaves, 0),
+        );
+
+        contract.withdraw_partial(htlc_id, fill_id, secrets[1].clone());
+    }
+
+    #[test]
+    fn test_maker_cancels_unfilled_order() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(10))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let (_, _, leaves) = part_secrets(4);
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode(merkle::root(HashAlgorithm::Sha256, &leaves)),
+            timelock_seconds: 86400,
+            allow_partial_fills: true,
+            parts_count: Some(4),
+            ..Default::default()
+        });
+
+        // The maker takes back all 10 NEAR long before the timelock
+        at_seconds(accounts(1), 120);
+        contract.cancel_order(htlc_id.clone());
+        let htlc = contract.get_htlc(htlc_id.clone()).unwrap();
+        assert_eq!(htlc.status, HTLCStatus::Refunded);
+        assert_eq!(htlc.remaining_amount.0, 0);
+        assert!(get_logs().iter().any(|log| log.contains(&format!(
+            r#""event":"order_cancelled","data":[{{"htlc_id":"{}","refunded_to":"{}","amount":"{}"}}]"#,
+            htlc_id,
+            accounts(1),
+            NearToken::from_near(10).as_yoctonear()
+        ))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Order already partially filled")]
+    fn test_cancel_rejected_once_filled() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        register_storage(&mut contract, accounts(3));
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(10))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let (_, hashes, leaves) = part_secrets(4);
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode(merkle::root(HashAlgorithm::Sha256, &leaves)),
+            timelock_seconds: 86400,
+            allow_partial_fills: true,
+            parts_count: Some(4),
+            ..Default::default()
+        });
+
+        at_seconds(accounts(3), 60);
+        contract.create_partial_fill(
+            htlc_id.clone(),
+            U128(NearToken::from_near(3).as_yoctonear()),
+            1,
+            hashes[1].clone(),
+            proof_hex(&leaves, 1),
+        );
+
+        at_seconds(accounts(1), 120);
+        contract.cancel_order(htlc_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Order already taken by a resolver")]
+    fn test_cancel_rejected_once_resolver_deposited() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_id = staged_htlc(&mut contract, hex::encode([7u8; 32]), true);
+        register_storage(&mut contract, accounts(3));
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(NearToken::from_millinear(50))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        contract.create_safety_deposit(htlc_id.clone());
+
+        at_seconds(accounts(1), 60);
+        contract.cancel_order(htlc_id);
+    }
+    // Open 1 NEAR order with no receiver, for the secret [6; 32]
+    fn open_order(contract: &mut FusionPlusContract) -> String {
+        register_storage(contract, accounts(1));
+        register_storage(contract, accounts(3));
+        register_storage(contract, accounts(4));
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let hashlock = hex::encode(Sha256::digest([6u8; 32]));
+        contract.create_htlc(CreateHTLCArgs {
+            receiver: None,
+            hashlock,
+            timelock_seconds: 86400,
+            ..Default::default()
+        })
+    }
+
+    fn take_at(contract: &mut FusionPlusContract, htlc_id: &str, resolver: AccountId, seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(resolver)
+            .attached_deposit(NearToken::from_millinear(50))
+            .block_timestamp(1_000_000_000_000_000_000 + seconds * 1_000_000_000)
+            .build());
+        contract.create_safety_deposit(htlc_id.to_string());
+    }
+
+    #[test]
+    fn test_open_order_taken_by_first_deposit() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_id = open_order(&mut contract);
+        let htlc = contract.get_htlc(htlc_id.clone()).unwrap();
+        assert!(htlc.open_order);
+        assert_eq!(htlc.receiver, None);
+        assert_eq!(htlc.safety_deposit_amount, U128(NearToken::from_millinear(50).as_yoctonear()));
+
+        take_at(&mut contract, &htlc_id, accounts(3), 0);
+        let htlc = contract.get_htlc(htlc_id.clone()).unwrap();
+        assert_eq!(htlc.receiver, Some(accounts(3)));
+        assert_eq!(htlc.taken_until, Some(U64(1_000_000_000 + 1800)));
+        assert!(get_logs().iter().any(|log| log.contains(&format!(
+            r#""event":"order_taken","data":[{{"htlc_id":"{}","taker":"{}","taken_until":"{}"}}]"#,
+            htlc_id,
+            accounts(3),
+            1_000_000_000 + 1800
+        ))));
+
+        at_seconds(accounts(3), 600);
+        contract.withdraw(htlc_id.clone(), hex::encode([6u8; 32]));
+        let htlc = contract.get_htlc(htlc_id).unwrap();
+        assert_eq!(htlc.status, HTLCStatus::Completed);
+        assert_eq!(htlc.settled_by, Some(accounts(3)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Order taken by another resolver")]
+    fn test_open_order_exclusive_to_taker() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_id = open_order(&mut contract);
+
+        take_at(&mut contract, &htlc_id, accounts(3), 0);
+        take_at(&mut contract, &htlc_id, accounts(4), 1799);
+    }
+
+    #[test]
+    fn test_lapsed_open_order_goes_back_to_pool() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_id = open_order(&mut contract);
+        take_at(&mut contract, &htlc_id, accounts(3), 0);
+
+        // accounts(3) let the window pass, so accounts(4) takes over
+        take_at(&mut contract, &htlc_id, accounts(4), 1800);
+        let htlc = contract.get_htlc(htlc_id.clone()).unwrap();
+        assert_eq!(htlc.receiver, Some(accounts(4)));
+        assert_eq!(htlc.taken_until, Some(U64(1_000_000_000 + 3600)));
+        assert_eq!(htlc.resolvers, vec![accounts(4)]);
+        assert!(contract.get_user_htlcs(accounts(3), None, None).htlcs.is_empty());
+        assert!(contract.resolver_htlcs.get(&accounts(3)).is_none());
+
+        at_seconds(accounts(4), 2000);
+        contract.withdraw(htlc_id.clone(), hex::encode([6u8; 32]));
+        assert_eq!(contract.get_htlc(htlc_id).unwrap().settled_by, Some(accounts(4)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Order not taken")]
+    fn test_open_order_needs_taker_to_withdraw() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_id = open_order(&mut contract);
+
+        at_seconds(accounts(3), 600);
+        contract.withdraw(htlc_id, hex::encode([6u8; 32]));
+    }
+}
@@ -0,0 +1,273 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, is_promise_success, near_bindgen, require, AccountId, Gas, Promise};
+
+use super::*;
+
+const MAX_PROTOCOL_FEE_BPS: u16 = 1_000; // 10%
+const BPS_DENOMINATOR: u128 = 10_000;
+const GAS_FOR_RESOLVE_FEE_WITHDRAWAL: Gas = Gas::from_tgas(10);
+pub(crate) const DEFAULT_RETENTION_SECONDS: u64 = 30 * 24 * 3600;
+// Long past any payout callback, so a pruned HTLC is never rolled back
+const MIN_RETENTION_SECONDS: u64 = 3600;
+const MAX_RETENTION_SECONDS: u64 = 365 * 24 * 3600;
+
+// `bps` basis points of `amount`, rounded down. Amounts of tokens with a
+// large supply would overflow `amount * bps`, so those are split first.
+pub(crate) fn bps_share(amount: u128, bps: u16) -> u128 {
+    let bps = bps as u128;
+    match amount.checked_mul(bps) {
+        Some(product) => product / BPS_DENOMINATOR,
+        None => amount / BPS_DENOMINATOR * bps + amount % BPS_DENOMINATOR * bps / BPS_DENOMINATOR,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractConfig {
+    pub owner_id: AccountId,
+    pub pending_owner_id: Option<AccountId>,
+    pub paused: bool,
+    pub protocol_fee_bps: u16,
+    pub retention_seconds: u64,
+}
+
+#[near_bindgen]
+impl FusionPlusContract {
+    // First step of an ownership transfer; the new owner has to accept.
+    // Proposing again replaces the pending owner.
+    pub fn transfer_ownership(&mut self, new_owner_id: AccountId) {
+        self.assert_owner();
+        self.pending_owner_id = Some(new_owner_id);
+    }
+
+    pub fn accept_ownership(&mut self) {
+        let new_owner_id = env::predecessor_account_id();
+        require!(self.pending_owner_id.as_ref() == Some(&new_owner_id), "Not the pending owner");
+
+        let previous_owner_id = std::mem::replace(&mut self.owner_id, new_owner_id.clone());
+        self.pending_owner_id = None;
+        events::OwnershipTransferred { previous_owner_id, new_owner_id }.emit();
+    }
+
+    // Emergency switch: stops new HTLCs and fills. Withdrawals, refunds and
+    // safety deposit claims keep working so no funds get stuck.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.assert_owner();
+        self.paused = paused;
+        events::PauseChanged { paused }.emit();
+    }
+
+    // Fee in basis points taken from withdrawals settled after the change
+    pub fn set_protocol_fee(&mut self, protocol_fee_bps: u16) {
+        self.assert_owner();
+        require!(protocol_fee_bps <= MAX_PROTOCOL_FEE_BPS, "Protocol fee too high");
+        self.protocol_fee_bps = protocol_fee_bps;
+        events::ProtocolFeeChanged { protocol_fee_bps }.emit();
+    }
+
+    // How long settled HTLCs stay in state before `prune_htlcs` may remove
+    // them. Applies to HTLCs already settled as well.
+    pub fn set_retention_period(&mut self, retention_seconds: u64) {
+        self.assert_owner();
+        require!(
+            (MIN_RETENTION_SECONDS..=MAX_RETENTION_SECONDS).contains(&retention_seconds),
+            "Invalid retention period"
+        );
+        self.retention_seconds = retention_seconds;
+        events::RetentionPeriodChanged { retention_seconds }.emit();
+    }
+
+    // Pays accrued fees of one token (None for NEAR) to the owner. Only fees
+    // of payouts that already went through are counted, so escrowed funds
+    // cannot be withdrawn.
+    pub fn withdraw_fees(&mut self, token_id: Option<AccountId>, amount: Option<U128>) -> Promise {
+        self.assert_owner();
+        let accrued = self.accrued_fees.get(&token_id).unwrap_or(0);
+        let amount = amount.map_or(accrued, |amount| amount.0);
+        require!(amount > 0, "Nothing to withdraw");
+        require!(amount <= accrued, "Amount exceeds accrued fees");
+        self.accrued_fees.insert(&token_id, &(accrued - amount));
+
+        events::FeesWithdrawn { token_id: token_id.clone(), receiver_id: self.owner_id.clone(), amount: U128(amount) }
+            .emit();
+        self.transfer(token_id.as_ref(), self.owner_id.clone(), amount, "Fusion+ fees".to_string()).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_FEE_WITHDRAWAL)
+                .resolve_fee_withdrawal(token_id, U128(amount)),
+        )
+    }
+
+    // Puts the fees back if the transfer to the owner failed
+    #[private]
+    pub fn resolve_fee_withdrawal(&mut self, token_id: Option<AccountId>, amount: U128) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        self.accrue_fee(&token_id, amount.0);
+        self.credit_tokens(token_id.as_ref(), amount.0);
+        false
+    }
+
+    pub fn get_owner_id(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    pub fn get_config(&self) -> ContractConfig {
+        ContractConfig {
+            owner_id: self.owner_id.clone(),
+            pending_owner_id: self.pending_owner_id.clone(),
+            paused: self.paused,
+            protocol_fee_bps: self.protocol_fee_bps,
+            retention_seconds: self.retention_seconds,
+        }
+    }
+
+    pub fn get_accrued_fees(&self) -> Vec<(Option<AccountId>, U128)> {
+        self.accrued_fees.iter().map(|(token_id, amount)| (token_id, U128(amount))).collect()
+    }
+}
+
+impl FusionPlusContract {
+    pub(crate) fn assert_owner(&self) {
+        require!(env::predecessor_account_id() == self.owner_id, "Not the owner");
+    }
+
+    pub(crate) fn assert_not_paused(&self) {
+        require!(!self.paused, "Contract paused");
+    }
+
+    pub(crate) fn protocol_fee(&self, amount: u128) -> u128 {
+        bps_share(amount, self.protocol_fee_bps)
+    }
+
+    pub(crate) fn accrue_fee(&mut self, token_id: &Option<AccountId>, fee: u128) {
+        if fee > 0 {
+            let accrued = self.accrued_fees.get(token_id).unwrap_or(0);
+            self.accrued_fees.insert(token_id, &(accrued + fee));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+    use sha2::{Digest, Sha256};
+
+    use crate::fusion_plus::tests::register_storage;
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn as_account(account_id: AccountId) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(account_id)
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(START)
+            .build());
+    }
+
+    #[test]
+    fn test_two_step_ownership_transfer() {
+        as_account(accounts(0));
+        let mut contract = FusionPlusContract::new();
+        contract.transfer_ownership(accounts(1));
+        assert_eq!(contract.get_owner_id(), accounts(0));
+
+        as_account(accounts(1));
+        contract.accept_ownership();
+        assert_eq!(contract.get_owner_id(), accounts(1));
+        assert!(contract.get_config().pending_owner_id.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract paused")]
+    fn test_pause_blocks_new_htlcs() {
+        as_account(accounts(0));
+        let mut contract = FusionPlusContract::new();
+        contract.set_paused(true);
+        register_storage(&mut contract, accounts(1));
+
+        as_account(accounts(1));
+        contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode([1u8; 32]),
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract paused")]
+    fn test_pause_blocks_new_safety_deposits() {
+        as_account(accounts(0));
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        register_storage(&mut contract, accounts(3));
+        as_account(accounts(1));
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode([1u8; 32]),
+            timelock_seconds: 3600,
+            require_safety_deposit: true,
+            ..Default::default()
+        });
+
+        as_account(accounts(0));
+        contract.set_paused(true);
+        as_account(accounts(3));
+        contract.create_safety_deposit(htlc_id);
+    }
+
+    #[test]
+    fn test_bps_share_does_not_overflow() {
+        assert_eq!(bps_share(10_000, 30), 30);
+        assert_eq!(bps_share(u128::MAX, 10_000), u128::MAX);
+        assert_eq!(bps_share(u128::MAX, 1_000), u128::MAX / 10);
+    }
+
+    #[test]
+    fn test_withdrawal_fee_accrues_once_payout_lands() {
+        as_account(accounts(0));
+        let mut contract = FusionPlusContract::new();
+        contract.set_protocol_fee(30);
+        register_storage(&mut contract, accounts(1));
+
+        as_account(accounts(1));
+        let hashlock = hex::encode(Sha256::digest([6u8; 32]));
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock,
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+
+        // Pausing does not block settlement
+        as_account(accounts(0));
+        contract.set_paused(true);
+        as_account(accounts(2));
+        contract.withdraw(htlc_id.clone(), hex::encode([6u8; 32]));
+        assert!(contract.get_accrued_fees().is_empty());
+
+        testing_env!(
+            VMContextBuilder::new().current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        let fee = NearToken::from_near(1).as_yoctonear() * 30 / 10_000;
+        contract.resolve_payout(htlc_id, PayoutKind::Withdraw, HTLCStatus::Active, U128(fee));
+        assert_eq!(contract.get_accrued_fees(), vec![(None, U128(fee))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount exceeds accrued fees")]
+    fn test_fee_withdrawal_is_capped_at_accrued_fees() {
+        as_account(accounts(0));
+        let mut contract = FusionPlusContract::new();
+        contract.withdraw_fees(None, Some(U128(1)));
+    }
+}
@@ -0,0 +1,330 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, require, AccountId, NearToken, Promise, PromiseOrValue, StorageUsage};
+
+use super::*;
+
+const MAX_BATCH_ITEMS: usize = 10; // Bounded by the gas of the merged payout callbacks
+// Most storage one HTLC can take: the longest account ids, a partial order
+// and the first HTLC in its token. Checked per item before it is written.
+const MAX_HTLC_STORAGE_BYTES: StorageUsage = 2_500;
+
+// One HTLC of `batch_create_htlc`, funded with `amount` of the attached deposit
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchCreateHTLC {
+    pub amount: U128,
+    #[serde(flatten)]
+    pub args: CreateHTLCArgs,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchWithdraw {
+    pub htlc_id: String,
+    pub secret: String,
+}
+
+// Payouts of one batch, merged per receiver and token
+#[derive(Default)]
+struct BatchPayouts {
+    groups: Vec<(AccountId, Option<AccountId>, u128, Vec<Settlement>)>,
+}
+
+impl BatchPayouts {
+    fn add(&mut self, receiver_id: AccountId, token_id: Option<AccountId>, amount: u128, settlement: Settlement) {
+        match self.groups.iter_mut().find(|(r, t, _, _)| *r == receiver_id && *t == token_id) {
+            Some((_, _, total, settlements)) => {
+                *total += amount;
+                settlements.push(settlement);
+            }
+            None => self.groups.push((receiver_id, token_id, amount, vec![settlement])),
+        }
+    }
+
+    // Joint promise over one transfer per group, None without any
+    fn into_promise(self, contract: &mut FusionPlusContract) -> Option<Promise> {
+        self.groups
+            .into_iter()
+            .map(|(receiver_id, token_id, amount, settlements)| {
+                contract.internal_batch_payout(token_id.as_ref(), receiver_id, amount, settlements)
+            })
+            .reduce(|joint, payout| joint.and(payout))
+    }
+}
+
+#[near_bindgen]
+impl FusionPlusContract {
+    // Create several NEAR HTLCs in one call. The attached deposit must equal
+    // the sum of the items' amounts. Items that fail validation, or that the
+    // sender's storage balance can no longer cover, are skipped with a
+    // `batch_item_failed` event and their amounts returned in a single
+    // transfer. Returns the new HTLC ids, None for skipped items.
+    #[payable]
+    pub fn batch_create_htlc(&mut self, items: Vec<BatchCreateHTLC>) -> Vec<Option<String>> {
+        self.assert_not_paused();
+        let sender = env::predecessor_account_id();
+        let total = items.iter().try_fold(0u128, |total, item| total.checked_add(item.amount.0));
+        require!(!items.is_empty(), "Empty batch");
+        require!(items.len() <= MAX_BATCH_ITEMS, "Too many items");
+        require!(total == Some(env::attached_deposit().as_yoctonear()), "Attached deposit must equal the batch total");
+
+        let mut unused = 0;
+        let htlc_ids = items
+            .into_iter()
+            .enumerate()
+            .map(|(index, item)| match self.check_batch_item(&sender, &item) {
+                Ok(()) => Some(self.internal_create_htlc(sender.clone(), None, item.amount.0, item.args)),
+                Err(reason) => {
+                    unused += item.amount.0;
+                    Self::batch_item_failed(index, None, reason);
+                    None
+                }
+            })
+            .collect();
+
+        if unused > 0 {
+            Promise::new(sender).transfer(NearToken::from_yoctonear(unused));
+        }
+        htlc_ids
+    }
+
+    // Withdraw several HTLCs, with the same rules as `withdraw` per item.
+    // Items that fail are skipped with a `batch_item_failed` event; payouts
+    // to the same receiver in the same token are merged into one transfer.
+    pub fn batch_withdraw(&mut self, items: Vec<BatchWithdraw>) -> Promise {
+        require!(items.len() <= MAX_BATCH_ITEMS, "Too many items");
+        let withdrawer = env::predecessor_account_id();
+        let mut payouts = BatchPayouts::default();
+
+        for (index, BatchWithdraw { htlc_id, secret }) in items.into_iter().enumerate() {
+            match self.check_withdraw(&htlc_id, &secret, &withdrawer) {
+                Ok(htlc) => {
+                    let (htlc, previous_status) = self.settle_withdraw(htlc, secret, withdrawer.clone(), &withdrawer);
+                    let fee = self.protocol_fee(htlc.total_amount.0);
+                    payouts.add(
+                        htlc.receiver.expect("Order not taken"),
+                        htlc.token_id,
+                        htlc.total_amount.0 - fee,
+                        Settlement { htlc_id, kind: PayoutKind::Withdraw, previous_status, fee: U128(fee) },
+                    );
+                }
+                Err(reason) => Self::batch_item_failed(index, Some(htlc_id), reason),
+            }
+        }
+
+        payouts.into_promise(self).unwrap_or_else(|| env::panic_str("All batch items failed"))
+    }
+
+    // Refund several HTLCs, with the same rules as `refund` per item. Orders
+    // fully taken by fills settle without a transfer.
+    pub fn batch_refund(&mut self, htlc_ids: Vec<String>) -> PromiseOrValue<bool> {
+        require!(htlc_ids.len() <= MAX_BATCH_ITEMS, "Too many items");
+        let refunder = env::predecessor_account_id();
+        let mut payouts = BatchPayouts::default();
+        let mut settled = 0;
+
+        for (index, htlc_id) in htlc_ids.into_iter().enumerate() {
+            match self.check_refund(&htlc_id, &refunder) {
+                Ok(htlc) => {
+                    let (htlc, amount, previous_status) = self.settle_refund(htlc, refunder.clone());
+                    settled += 1;
+                    if amount == 0 {
+                        self.slash_for_lapse(&htlc_id, None);
+                        continue;
+                    }
+                    payouts.add(
+                        htlc.sender,
+                        htlc.token_id,
+                        amount,
+                        Settlement { htlc_id, kind: PayoutKind::Refund, previous_status, fee: U128(0) },
+                    );
+                }
+                Err(reason) => Self::batch_item_failed(index, Some(htlc_id), reason),
+            }
+        }
+
+        require!(settled > 0, "All batch items failed");
+        match payouts.into_promise(self) {
+            Some(promise) => promise.into(),
+            None => PromiseOrValue::Value(true),
+        }
+    }
+}
+
+impl FusionPlusContract {
+    // Earlier items have already locked their storage, so each item is
+    // checked against what is left
+    fn check_batch_item(&self, sender: &AccountId, item: &BatchCreateHTLC) -> Result<(), &'static str> {
+        self.check_create_args(sender, item.amount.0, &item.args)?;
+        if !self.can_cover_storage(sender, MAX_HTLC_STORAGE_BYTES) {
+            return Err("Insufficient storage balance");
+        }
+        Ok(())
+    }
+
+    fn batch_item_failed(index: usize, htlc_id: Option<String>, reason: &str) {
+        events::BatchItemFailed { index: index as u32, htlc_id, reason: reason.to_string() }.emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+
+    use crate::fusion_plus::tests::register_storage;
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn item(secret: u8, amount: u128) -> BatchCreateHTLC {
+        BatchCreateHTLC {
+            amount: U128(amount),
+            args: CreateHTLCArgs {
+                receiver: Some(accounts(2)),
+                hashlock: htlc_core::hash_secret(&hex::encode([secret; 32]), HashAlgorithm::Sha256),
+                timelock_seconds: 3600,
+                allow_partial_fills: false,
+                parts_count: None,
+                min_fill_amount: None,
+                require_safety_deposit: false,
+                stage_durations: None,
+                hash_algorithm: HashAlgorithm::Sha256,
+            },
+        }
+    }
+
+    fn batch_of_three(contract: &mut FusionPlusContract) -> Vec<Option<String>> {
+        register_storage(contract, accounts(1));
+        let mut bad = item(3, 3);
+        bad.args.timelock_seconds = 60;
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_yoctonear(6))
+            .block_timestamp(START)
+            .build());
+        contract.batch_create_htlc(vec![item(1, 1), item(2, 2), bad])
+    }
+
+    #[test]
+    fn test_batch_create_skips_invalid_items() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_ids = batch_of_three(&mut contract);
+
+        assert!(htlc_ids[0].is_some() && htlc_ids[1].is_some());
+        assert!(htlc_ids[2].is_none());
+        assert_eq!(contract.get_htlc(htlc_ids[1].clone().unwrap()).unwrap().total_amount.0, 2);
+        assert!(get_logs().iter().any(|log| log.contains(r#""event":"batch_item_failed","data":[{"index":2,"htlc_id":null,"reason":"Invalid timelock"}]"#)));
+    }
+
+    #[test]
+    fn test_batch_withdraw_reports_bad_secret_and_merges_payouts() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_ids: Vec<String> = batch_of_three(&mut contract).into_iter().flatten().collect();
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(START + 60_000_000_000)
+            .build());
+        contract.batch_withdraw(vec![
+            BatchWithdraw { htlc_id: htlc_ids[0].clone(), secret: hex::encode([1u8; 32]) },
+            BatchWithdraw { htlc_id: htlc_ids[1].clone(), secret: hex::encode([9u8; 32]) },
+        ]);
+        assert_eq!(contract.get_htlc(htlc_ids[0].clone()).unwrap().status, HTLCStatus::Completed);
+        assert_eq!(contract.get_htlc(htlc_ids[1].clone()).unwrap().status, HTLCStatus::Active);
+        assert!(get_logs().iter().any(|log| log.contains(r#""index":1"#) && log.contains("Invalid secret")));
+
+        // A failed merged transfer rolls back every HTLC it covered
+        testing_env!(
+            VMContextBuilder::new().current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let settlements = vec![Settlement {
+            htlc_id: htlc_ids[0].clone(),
+            kind: PayoutKind::Withdraw,
+            previous_status: HTLCStatus::Active,
+            fee: U128(0),
+        }];
+        assert!(!contract.resolve_batch_payout(settlements));
+        assert_eq!(contract.get_htlc(htlc_ids[0].clone()).unwrap().status, HTLCStatus::Active);
+    }
+
+    #[test]
+    fn test_batch_create_skips_items_beyond_storage_balance() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        // Enough for one HTLC at most
+        let deposit = contract.storage_balance_bounds().min.as_yoctonear()
+            + env::storage_byte_cost().as_yoctonear() * MAX_HTLC_STORAGE_BYTES as u128;
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_yoctonear(deposit))
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_yoctonear(3))
+            .block_timestamp(START)
+            .build());
+        let htlc_ids = contract.batch_create_htlc(vec![item(1, 1), item(2, 2)]);
+
+        assert!(htlc_ids[0].is_some() && htlc_ids[1].is_none());
+        assert!(get_logs().iter().any(|log| log.contains(r#""index":1"#) && log.contains("Insufficient storage balance")));
+    }
+
+    #[test]
+    fn test_htlc_storage_within_batch_estimate() {
+        let longest = |prefix: &str| -> AccountId { format!("{}{}", prefix, "a".repeat(64 - prefix.len())).parse().unwrap() };
+        testing_env!(VMContextBuilder::new().current_account_id(longest("c")).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, longest("s"));
+
+        let htlc_id = contract.internal_create_htlc(
+            longest("s"),
+            Some(longest("t")),
+            1_000_000,
+            CreateHTLCArgs {
+                receiver: Some(longest("r")),
+                hashlock: hex::encode([1u8; 32]),
+                timelock_seconds: 3600,
+                allow_partial_fills: true,
+                parts_count: Some(MAX_PARTS_COUNT),
+                min_fill_amount: Some(U128(1)),
+                ..Default::default()
+            },
+        );
+        assert!(contract.get_htlc(htlc_id).unwrap().storage_bytes <= MAX_HTLC_STORAGE_BYTES);
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many items")]
+    fn test_batch_refund_is_capped() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        contract.batch_refund(vec!["htlc_0".to_string(); MAX_BATCH_ITEMS + 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "All batch items failed")]
+    fn test_batch_refund_with_no_valid_items() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_ids: Vec<String> = batch_of_three(&mut contract).into_iter().flatten().collect();
+
+        // Still inside the withdrawal window
+        contract.batch_refund(htlc_ids);
+    }
+}
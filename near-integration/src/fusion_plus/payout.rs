@@ -0,0 +1,407 @@
+use near_contract_standards::fungible_token::core::ext_ft_core;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, is_promise_success, near_bindgen, AccountId, Gas, NearToken, Promise};
+
+use super::*;
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(10);
+const GAS_FOR_RESOLVE_PAYOUT: Gas = Gas::from_tgas(10);
+const ONE_YOCTO: NearToken = NearToken::from_yoctonear(1);
+
+// Which settlement a payout belongs to, so a failed transfer can be undone
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PayoutKind {
+    Withdraw,
+    RelayedWithdraw { relayer: AccountId, tip: U128 }, // `tip` is paid once the payout lands
+    Refund,
+    FillWithdraw { fill_id: String },
+    FillRefund { fill_id: String },
+    Cancel, // The maker took back an order no resolver had taken
+}
+
+// One settlement covered by a payout; a batch transfer covers several
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Settlement {
+    pub htlc_id: String,
+    pub kind: PayoutKind,
+    pub previous_status: HTLCStatus,
+    pub fee: U128, // Protocol fee held back, accrued once the payout lands
+}
+
+impl PayoutKind {
+    pub fn fill_id(&self) -> Option<&str> {
+        match self {
+            PayoutKind::Withdraw | PayoutKind::RelayedWithdraw { .. } | PayoutKind::Refund | PayoutKind::Cancel => None,
+            PayoutKind::FillWithdraw { fill_id } | PayoutKind::FillRefund { fill_id } => Some(fill_id),
+        }
+    }
+
+    pub fn is_withdrawal(&self) -> bool {
+        !matches!(self, PayoutKind::Refund | PayoutKind::FillRefund { .. } | PayoutKind::Cancel)
+    }
+}
+
+#[near_bindgen]
+impl FusionPlusContract {
+    // Callback for every payout, modelled on `ft_resolve_transfer`. Restores
+    // the HTLC (and fill) to its pre-settlement state if the NEAR transfer or
+    // `ft_transfer` failed, e.g. because the receiving account was deleted.
+    #[private]
+    pub fn resolve_payout(
+        &mut self,
+        htlc_id: String,
+        kind: PayoutKind,
+        previous_status: HTLCStatus,
+        fee: U128,
+    ) -> bool {
+        let success = is_promise_success();
+        self.finish_payout(Settlement { htlc_id, kind, previous_status, fee }, success);
+        success
+    }
+
+    // Callback for a safety deposit payout. The depositor's storage is
+    // released once the transfer landed; if it failed the deposit is put
+    // back so it can be claimed again.
+    #[private]
+    pub fn resolve_deposit_payout(&mut self, deposit: SafetyDeposit) -> bool {
+        let success = is_promise_success();
+        if success {
+            self.remove_safety_deposit(&deposit.id, &deposit);
+        } else {
+            self.safety_deposits.insert(&deposit.id, &deposit);
+            events::DepositPayoutFailed { deposit_id: deposit.id, htlc_id: deposit.htlc_id }.emit();
+        }
+        success
+    }
+
+    // Callback for a merged batch transfer; every settlement it paid for is
+    // finished or rolled back together
+    #[private]
+    pub fn resolve_batch_payout(&mut self, settlements: Vec<Settlement>) -> bool {
+        let success = is_promise_success();
+        for settlement in settlements {
+            self.finish_payout(settlement, success);
+        }
+        success
+    }
+}
+
+impl FusionPlusContract {
+    fn finish_payout(&mut self, settlement: Settlement, success: bool) {
+        let Settlement { htlc_id, kind, previous_status, fee } = settlement;
+        if success {
+            // Only now, so a payout that is rolled back can neither pay the
+            // tip twice nor leave fees behind that the owner could withdraw
+            let token_id = self.htlcs.get(&htlc_id).and_then(|htlc| htlc.token_id);
+            self.accrue_fee(&token_id, fee.0);
+            match kind {
+                PayoutKind::RelayedWithdraw { relayer, tip } if tip.0 > 0 => {
+                    self.transfer(token_id.as_ref(), relayer, tip.0, format!("Fusion+ {} tip", htlc_id));
+                }
+                // The order lapsed; its taker answers for it
+                PayoutKind::Refund => self.slash_for_lapse(&htlc_id, None),
+                PayoutKind::FillRefund { fill_id } => self.slash_for_lapse(&htlc_id, Some(&fill_id)),
+                _ => {}
+            }
+            return;
+        }
+
+        let mut htlc = self.htlcs.get(&htlc_id).expect("HTLC not found");
+        // Only reopen the HTLC if this settlement closed it. A fill payout
+        // that fails after the rest of the order was refunded must not
+        // reopen the refunded remainder.
+        let closed_by_payout = match kind {
+            PayoutKind::Withdraw | PayoutKind::RelayedWithdraw { .. } | PayoutKind::Refund | PayoutKind::Cancel => true,
+            PayoutKind::FillWithdraw { .. } => htlc.status == HTLCStatus::Completed,
+            PayoutKind::FillRefund { .. } => false,
+        };
+        let reopened = match htlc.status {
+            HTLCStatus::Completed | HTLCStatus::Refunded if closed_by_payout => {
+                let settled_as = std::mem::replace(&mut htlc.status, previous_status);
+                htlc.settled_by = None;
+                htlc.settled_at = None;
+                htlc.taker_lapsed = false;
+                self.active_htlcs.insert(&htlc_number(&htlc_id), &());
+                Some(settled_as)
+            }
+            _ => None,
+        };
+
+        let withdrawal = kind.is_withdrawal();
+        let tip = match &kind {
+            PayoutKind::RelayedWithdraw { tip, .. } => tip.0,
+            _ => 0,
+        };
+        let (fill_id, amount) = match kind {
+            PayoutKind::Withdraw | PayoutKind::RelayedWithdraw { .. } => {
+                htlc.secret = None;
+                (None, htlc.total_amount.0)
+            }
+            PayoutKind::Refund => (None, Self::refund_amount(&htlc)),
+            // Cancelled orders have no fills, so all of it comes back
+            PayoutKind::Cancel => {
+                htlc.remaining_amount = htlc.total_amount;
+                (None, htlc.total_amount.0)
+            }
+            PayoutKind::FillWithdraw { fill_id } | PayoutKind::FillRefund { fill_id } => {
+                let fill = self.restore_fill(&htlc_id, &fill_id);
+                htlc.pending_fills += 1;
+                // Pending again, so sweeps must find it even if settled
+                self.active_htlcs.insert(&htlc_number(&htlc_id), &());
+                (Some(fill_id), fill.amount.0)
+            }
+        };
+        self.htlcs.insert(&htlc_id, &htlc);
+        // The tokens that were sent came back
+        self.credit_tokens(htlc.token_id.as_ref(), amount - fee.0 - tip);
+
+        // Undo what the settlement added to the token's statistics
+        self.update_token_stats(&htlc.token_id, |stats| {
+            match reopened {
+                Some(HTLCStatus::Completed) => stats.completed_htlcs = stats.completed_htlcs.saturating_sub(1),
+                Some(_) => stats.refunded_htlcs = stats.refunded_htlcs.saturating_sub(1),
+                None => {}
+            }
+            if reopened.is_some() {
+                stats.active_htlcs += 1;
+            }
+            let volume = if withdrawal { &mut stats.withdrawn_volume } else { &mut stats.refunded_volume };
+            volume.0 = volume.0.saturating_sub(amount);
+        });
+
+        events::PayoutFailed { htlc_id, fill_id }.emit();
+    }
+
+    // Pays `amount` of NEAR or the HTLC's NEP-141 token, holding back `fee`.
+    // Storage and the fee are settled by `resolve_payout` once the payout can
+    // no longer be rolled back.
+    pub(crate) fn internal_payout(
+        &mut self,
+        htlc: &FusionHTLC,
+        receiver_id: AccountId,
+        amount: u128,
+        fee: u128,
+        kind: PayoutKind,
+        previous_status: HTLCStatus,
+    ) -> Promise {
+        let memo = format!("Fusion+ {}", htlc.id);
+        self.transfer(htlc.token_id.as_ref(), receiver_id, amount, memo).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_PAYOUT)
+                .resolve_payout(htlc.id.clone(), kind, previous_status, U128(fee)),
+        )
+    }
+
+    // Pays a safety deposit of a settled HTLC to whoever it is due. The
+    // deposit stays indexed under its HTLC, which keeps the HTLC from being
+    // pruned, until `resolve_deposit_payout` knows how the transfer went.
+    pub(crate) fn internal_deposit_payout(&mut self, htlc: &FusionHTLC, deposit: SafetyDeposit) -> Promise {
+        let recipient = Self::deposit_recipient(htlc, &deposit);
+        self.safety_deposits.remove(&deposit.id);
+
+        events::DepositClaimed {
+            deposit_id: deposit.id.clone(),
+            htlc_id: deposit.htlc_id.clone(),
+            claimed_by: recipient.clone(),
+            amount: deposit.amount,
+        }.emit();
+
+        Promise::new(recipient).transfer(NearToken::from_yoctonear(deposit.amount.0)).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_PAYOUT)
+                .resolve_deposit_payout(deposit),
+        )
+    }
+
+    // Merged payout of a batch to one receiver in one token
+    pub(crate) fn internal_batch_payout(
+        &mut self,
+        token_id: Option<&AccountId>,
+        receiver_id: AccountId,
+        amount: u128,
+        settlements: Vec<Settlement>,
+    ) -> Promise {
+        let gas = Gas::from_gas(GAS_FOR_RESOLVE_PAYOUT.as_gas() * settlements.len() as u64);
+        self.transfer(token_id, receiver_id, amount, "Fusion+ batch".to_string())
+            .then(Self::ext(env::current_account_id()).with_static_gas(gas).resolve_batch_payout(settlements))
+    }
+
+    // Sends NEAR or a NEP-141 token, taking tokens off the ledger
+    pub(crate) fn transfer(
+        &mut self,
+        token_id: Option<&AccountId>,
+        receiver_id: AccountId,
+        amount: u128,
+        memo: String,
+    ) -> Promise {
+        self.debit_tokens(token_id, amount);
+        match token_id {
+            None => Promise::new(receiver_id).transfer(NearToken::from_yoctonear(amount)),
+            Some(token_id) => ext_ft_core::ext(token_id.clone())
+                .with_attached_deposit(ONE_YOCTO)
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .ft_transfer(receiver_id, U128(amount), Some(memo)),
+        }
+    }
+
+    fn restore_fill(&mut self, htlc_id: &str, fill_id: &str) -> PartialFill {
+        let mut fill = self.get_fill(htlc_id, fill_id);
+        fill.status = FillStatus::Pending;
+        fill.secret = None;
+        self.partial_fills.insert(&fill.id, &fill);
+        fill
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+    use sha2::{Digest, Sha256};
+
+    use crate::fusion_plus::tests::register_storage;
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn payout_result(result: PromiseResult) {
+        testing_env!(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .block_timestamp(START + 7_200_000_000_000)
+                .build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+    }
+
+    fn refunded_htlc(contract: &mut FusionPlusContract) -> String {
+        register_storage(contract, accounts(1));
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(START)
+            .build());
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode([1u8; 32]),
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(START + 7_200_000_000_000)
+            .build());
+        contract.refund(htlc_id.clone());
+        htlc_id
+    }
+
+    #[test]
+    fn test_failed_native_refund_is_rolled_back() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_id = refunded_htlc(&mut contract);
+        assert_eq!(contract.get_htlc(htlc_id.clone()).unwrap().status, HTLCStatus::Refunded);
+
+        // The sender's account was deleted before the transfer landed
+        payout_result(PromiseResult::Failed);
+        assert!(!contract.resolve_payout(htlc_id.clone(), PayoutKind::Refund, HTLCStatus::Active, U128(0)));
+
+        let htlc = contract.get_htlc(htlc_id.clone()).unwrap();
+        assert_eq!(htlc.status, HTLCStatus::Active);
+        assert!(htlc.settled_by.is_none());
+        assert!(htlc.storage_bytes > 0);
+        assert!(contract.can_refund(htlc_id));
+        assert!(get_logs()[0].contains(r#""event":"payout_failed""#));
+    }
+
+    #[test]
+    fn test_failed_fill_payout_keeps_refunded_order_closed() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        register_storage(&mut contract, accounts(3));
+
+        let secrets: Vec<[u8; 32]> = (1..=3).map(|i| [i; 32]).collect();
+        let leaves: Vec<merkle::Hash> =
+            secrets.iter().enumerate().map(|(i, s)| merkle::leaf(HashAlgorithm::Sha256, i as u32, &Sha256::digest(s))).collect();
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(2))
+            .block_timestamp(START)
+            .build());
+        let hashlock = hex::encode(merkle::root(HashAlgorithm::Sha256, &leaves));
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock,
+            timelock_seconds: 3600,
+            allow_partial_fills: true,
+            parts_count: Some(2),
+            ..Default::default()
+        });
+
+        // Half the order is filled and withdrawn in time
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(START + 60_000_000_000)
+            .build());
+        let half = U128(NearToken::from_near(1).as_yoctonear());
+        let proof = merkle::proof(HashAlgorithm::Sha256, &leaves, 0).iter().map(hex::encode).collect();
+        let secret_hash = hex::encode(Sha256::digest(secrets[0]));
+        let fill_id = contract.create_partial_fill(htlc_id.clone(), half, 0, secret_hash, proof);
+        contract.withdraw_partial(htlc_id.clone(), fill_id.clone(), hex::encode(secrets[0]));
+
+        // The rest is refunded before the fill's payout fails
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(START + 7_200_000_000_000)
+            .build());
+        contract.refund(htlc_id.clone());
+        payout_result(PromiseResult::Failed);
+        let kind = PayoutKind::FillWithdraw { fill_id: fill_id.clone() };
+        contract.resolve_payout(htlc_id.clone(), kind, HTLCStatus::PartiallyFilled, U128(0));
+
+        // The refunded remainder stays refunded; only the fill is back
+        let htlc = contract.get_htlc(htlc_id.clone()).unwrap();
+        assert_eq!(htlc.status, HTLCStatus::Refunded);
+        assert_eq!(htlc.pending_fills, 1);
+        assert!(!contract.can_refund(htlc_id.clone()));
+        assert_eq!(contract.get_token_stats(None).active_htlcs, 0);
+        let fill = contract.get_fill(&htlc_id, &fill_id);
+        assert_eq!(fill.status, FillStatus::Pending);
+
+        // The maker can still reclaim the part
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(START + 7_200_000_000_000)
+            .build());
+        contract.refund_partial_fill(htlc_id.clone(), fill_id);
+        assert_eq!(contract.get_htlc(htlc_id).unwrap().pending_fills, 0);
+    }
+
+    #[test]
+    fn test_successful_payout_keeps_storage_until_pruned() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        let htlc_id = refunded_htlc(&mut contract);
+
+        payout_result(PromiseResult::Successful(vec![]));
+        assert!(contract.resolve_payout(htlc_id.clone(), PayoutKind::Refund, HTLCStatus::Active, U128(0)));
+        let htlc = contract.get_htlc(htlc_id).unwrap();
+        assert!(htlc.storage_bytes > 0);
+        assert_eq!(htlc.settled_at, Some(U64(START / 1_000_000_000 + 7200)));
+    }
+}
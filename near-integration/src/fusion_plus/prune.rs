@@ -0,0 +1,250 @@
+use near_sdk::{near_bindgen, require, StorageUsage};
+
+use super::*;
+
+const MAX_PRUNE: usize = 50; // HTLCs per call
+const RECORD_OVERHEAD_BYTES: StorageUsage = 40; // Charged by the protocol for every storage record
+
+#[near_bindgen]
+impl FusionPlusContract {
+    // Removes settled HTLCs whose last settlement is older than the retention
+    // period, whoever calls. Each one is logged in full with `htlc_pruned`,
+    // then the HTLC, its fills and its index entries are deleted and the
+    // storage they staked goes back to the sender and fillers. The hashlock
+    // stays taken, so the sender pays for its entry for good. HTLCs that
+    // cannot be pruned yet are skipped; returns how many were pruned.
+    pub fn prune_htlcs(&mut self, htlc_ids: Vec<String>) -> u32 {
+        require!(htlc_ids.len() <= MAX_PRUNE, "Too many HTLCs");
+        let mut pruned = 0;
+        for htlc_id in htlc_ids {
+            if let Some(htlc) = self.htlcs.get(&htlc_id).filter(|htlc| self.is_prunable(htlc)) {
+                self.prune_htlc(htlc);
+                pruned += 1;
+            }
+        }
+        pruned
+    }
+
+    pub fn can_prune(&self, htlc_id: String) -> bool {
+        self.htlcs.get(&htlc_id).is_some_and(|htlc| self.is_prunable(&htlc))
+    }
+}
+
+impl FusionPlusContract {
+    // Settled with nothing left to pay out: no pending fill and no safety
+    // deposit still held
+    fn is_prunable(&self, htlc: &FusionHTLC) -> bool {
+        let settled_at = htlc.settled_at.unwrap_or(htlc.created_at).0;
+        matches!(htlc.status, HTLCStatus::Completed | HTLCStatus::Refunded)
+            && htlc.pending_fills == 0
+            && self.htlc_deposits.get(&htlc.id).is_none_or(|deposit_ids| deposit_ids.is_empty())
+            && htlc_core::now_seconds() >= settled_at + self.retention_seconds
+    }
+
+    fn prune_htlc(&mut self, htlc: FusionHTLC) {
+        let fills = self.get_partial_fills(htlc.id.clone());
+        for fill in &fills {
+            self.partial_fills.remove(&fill.id);
+            Self::unindex_htlc(&mut self.filler_htlcs, &fill.filler, &htlc.id);
+            self.release_storage(&fill.filler, fill.storage_bytes);
+        }
+        if let Some(mut fill_ids) = self.htlc_fills.remove(&htlc.id) {
+            fill_ids.clear();
+        }
+        if let Some(mut deposit_ids) = self.htlc_deposits.remove(&htlc.id) {
+            deposit_ids.clear();
+        }
+
+        Self::unindex_htlc(&mut self.user_htlcs, &htlc.sender, &htlc.id);
+        if let Some(receiver) = &htlc.receiver {
+            Self::unindex_htlc(&mut self.user_htlcs, receiver, &htlc.id);
+        }
+        for resolver in &htlc.resolvers {
+            Self::unindex_htlc(&mut self.resolver_htlcs, resolver, &htlc.id);
+        }
+        self.htlcs.remove(&htlc.id);
+        let kept = Self::hashlock_entry_bytes(&htlc).min(htlc.storage_bytes);
+        self.release_storage(&htlc.sender, htlc.storage_bytes - kept);
+        self.consume_storage(&htlc.sender, kept);
+
+        events::HtlcPruned { htlc, fills }.emit();
+    }
+
+    // Size of the `secret_to_htlc` entry that outlives a pruned HTLC
+    pub(crate) fn hashlock_entry_bytes(htlc: &FusionHTLC) -> StorageUsage {
+        let prefix = borsh::to_vec(&StorageKey::SecretToHTLC).expect("Failed to serialize key").len();
+        let key = borsh::to_vec(&htlc.hashlock).expect("Failed to serialize hashlock").len();
+        let value = borsh::to_vec(&htlc.id).expect("Failed to serialize HTLC id").len();
+        (prefix + key + value) as StorageUsage + RECORD_OVERHEAD_BYTES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::json_types::U128;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+    use sha2::{Digest, Sha256};
+
+    use crate::fusion_plus::tests::register_storage;
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn at(account_id: AccountId, seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(account_id)
+            .block_timestamp(START + seconds * 1_000_000_000)
+            .build());
+    }
+
+    fn payout_landed(seconds: u64) {
+        testing_env!(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .block_timestamp(START + seconds * 1_000_000_000)
+                .build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+    }
+
+    fn available(contract: &FusionPlusContract, account_id: AccountId) -> u128 {
+        contract.storage_balance_of(account_id).unwrap().available.as_yoctonear()
+    }
+
+    // A 1 NEAR HTLC from accounts(1) to accounts(2), withdrawn at 60s
+    fn withdrawn_htlc(contract: &mut FusionPlusContract) -> String {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(START)
+            .build());
+        let hashlock = hex::encode(Sha256::digest([3u8; 32]));
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock,
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+
+        at(accounts(2), 60);
+        contract.withdraw(htlc_id.clone(), hex::encode([3u8; 32]));
+        payout_landed(60);
+        contract.resolve_payout(htlc_id.clone(), PayoutKind::Withdraw, HTLCStatus::Active, U128(0));
+        htlc_id
+    }
+
+    #[test]
+    fn test_prune_after_retention_releases_storage() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        let before = available(&contract, accounts(1));
+        let total = contract.storage_balance_of(accounts(1)).unwrap().total.as_yoctonear();
+        let htlc_id = withdrawn_htlc(&mut contract);
+
+        // The stake stays locked while the settled HTLC is retained
+        assert!(available(&contract, accounts(1)) < before);
+        at(accounts(3), 60 + DEFAULT_RETENTION_SECONDS - 1);
+        assert_eq!(contract.prune_htlcs(vec![htlc_id.clone()]), 0);
+
+        at(accounts(3), 60 + DEFAULT_RETENTION_SECONDS);
+        assert!(contract.can_prune(htlc_id.clone()));
+        let htlc = contract.get_htlc(htlc_id.clone()).unwrap();
+        assert_eq!(contract.prune_htlcs(vec![htlc_id.clone()]), 1);
+
+        assert!(contract.get_htlc(htlc_id.clone()).is_none());
+        assert!(contract.get_user_htlcs(accounts(1), None, None).htlcs.is_empty());
+        assert!(contract.get_user_htlcs(accounts(2), None, None).htlcs.is_empty());
+        // Everything but the hashlock's entry, which the sender paid for
+        // out of its balance
+        let kept = env::storage_byte_cost().as_yoctonear() * FusionPlusContract::hashlock_entry_bytes(&htlc) as u128;
+        assert_eq!(available(&contract, accounts(1)), before - kept);
+        assert_eq!(contract.storage_balance_of(accounts(1)).unwrap().total.as_yoctonear(), total - kept);
+        let log = &get_logs()[0];
+        assert!(log.contains(r#""event":"htlc_pruned""#));
+        assert!(log.contains(&format!(r#""id":"{}""#, htlc_id)));
+
+        // The hashlock is still taken, by an entry of the size charged
+        assert!(contract.get_htlc_by_hashlock(htlc.hashlock.clone()).is_none());
+        let usage = env::storage_usage();
+        assert!(contract.secret_to_htlc.remove(&htlc.hashlock).is_some());
+        assert_eq!(usage - env::storage_usage(), FusionPlusContract::hashlock_entry_bytes(&htlc));
+    }
+
+    #[test]
+    fn test_active_htlc_is_not_pruned() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(START)
+            .build());
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode([1u8; 32]),
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+
+        at(accounts(3), 2 * DEFAULT_RETENTION_SECONDS);
+        assert!(!contract.can_prune(htlc_id.clone()));
+        assert_eq!(contract.prune_htlcs(vec![htlc_id.clone()]), 0);
+        assert!(contract.get_htlc(htlc_id).is_some());
+    }
+
+    #[test]
+    fn test_prune_releases_filler_storage() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        register_storage(&mut contract, accounts(3));
+        let filler_before = available(&contract, accounts(3));
+
+        let secrets: Vec<[u8; 32]> = (1..=2).map(|i| [i; 32]).collect();
+        let leaves: Vec<merkle::Hash> =
+            secrets.iter().enumerate().map(|(i, s)| merkle::leaf(HashAlgorithm::Sha256, i as u32, &Sha256::digest(s))).collect();
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(START)
+            .build());
+        let hashlock = hex::encode(merkle::root(HashAlgorithm::Sha256, &leaves));
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock,
+            timelock_seconds: 3600,
+            allow_partial_fills: true,
+            parts_count: Some(1),
+            ..Default::default()
+        });
+
+        // One fill takes the whole order, with the extra secret, and
+        // completes it
+        at(accounts(3), 60);
+        let proof = merkle::proof(HashAlgorithm::Sha256, &leaves, 1).iter().map(hex::encode).collect();
+        let secret_hash = hex::encode(Sha256::digest(secrets[1]));
+        let amount = U128(NearToken::from_near(1).as_yoctonear());
+        let fill_id = contract.create_partial_fill(htlc_id.clone(), amount, 1, secret_hash, proof);
+        contract.withdraw_partial(htlc_id.clone(), fill_id.clone(), hex::encode(secrets[1]));
+        payout_landed(60);
+        contract.resolve_payout(htlc_id.clone(), PayoutKind::FillWithdraw { fill_id }, HTLCStatus::Active, U128(0));
+        assert!(available(&contract, accounts(3)) < filler_before);
+
+        at(accounts(4), 60 + DEFAULT_RETENTION_SECONDS);
+        assert_eq!(contract.prune_htlcs(vec![htlc_id.clone()]), 1);
+        assert!(contract.get_partial_fills(htlc_id).is_empty());
+        assert_eq!(available(&contract, accounts(3)), filler_before);
+    }
+}
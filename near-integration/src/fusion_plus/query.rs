@@ -0,0 +1,359 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, AccountId};
+
+use super::*;
+
+const MAX_PAGE_SIZE: u32 = 100;
+// HTLCs looked at per call, matching or not, so a selective filter over a
+// large index stays within the view gas limit
+const MAX_SCAN: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TokenFilter {
+    Native,
+    Token(AccountId),
+}
+
+// Every set field has to match. Account filters and the active statuses are
+// served from an index; the rest narrows what that index yields.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde", default)]
+pub struct HTLCFilter {
+    pub status: Option<HTLCStatus>,
+    pub token: Option<TokenFilter>,
+    pub sender: Option<AccountId>,
+    pub receiver: Option<AccountId>,
+    pub resolver: Option<AccountId>, // Posted a safety deposit
+    pub filler: Option<AccountId>,   // Took a partial fill
+    pub expires_after: Option<U64>,  // `timelock` at or after, in seconds
+    pub expires_before: Option<U64>, // `timelock` before, in seconds
+}
+
+// HTLCs in creation order. `next_cursor` is passed back as `from_cursor` for
+// the next page and is None once the index is exhausted; a page can be short
+// or empty while `next_cursor` is still set.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HTLCPage {
+    pub htlcs: Vec<FusionHTLC>,
+    pub next_cursor: Option<U64>,
+}
+
+// An HTLC with every escrow its hashlock is tied to: the fills made under
+// its part secrets and the safety deposits still held for it
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HashlockEscrows {
+    pub htlc: FusionHTLC,
+    pub fills: Vec<PartialFill>,
+    pub safety_deposits: Vec<SafetyDeposit>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenStats {
+    pub token_id: Option<AccountId>, // None for NEAR
+    pub htlcs_created: u64,
+    pub active_htlcs: u64,
+    pub completed_htlcs: u64,
+    pub refunded_htlcs: u64,
+    pub volume: U128,           // Everything escrowed
+    pub withdrawn_volume: U128, // Paid to takers, fees included
+    pub refunded_volume: U128,  // Returned to makers
+}
+
+impl HTLCFilter {
+    fn matches(&self, contract: &FusionPlusContract, htlc: &FusionHTLC) -> bool {
+        let token_matches = match &self.token {
+            None => true,
+            Some(TokenFilter::Native) => htlc.token_id.is_none(),
+            Some(TokenFilter::Token(token_id)) => htlc.token_id.as_ref() == Some(token_id),
+        };
+        token_matches
+            && self.status.as_ref().is_none_or(|status| &htlc.status == status)
+            && self.sender.as_ref().is_none_or(|sender| &htlc.sender == sender)
+            && self.receiver.as_ref().is_none_or(|receiver| htlc.receiver.as_ref() == Some(receiver))
+            && self.resolver.as_ref().is_none_or(|resolver| htlc.resolvers.contains(resolver))
+            && self.filler.as_ref().is_none_or(|filler| {
+                contract.filler_htlcs.get(filler).is_some_and(|numbers| numbers.contains_key(&htlc_number(&htlc.id)))
+            })
+            && self.expires_after.is_none_or(|after| htlc.timelock.0 >= after.0)
+            && self.expires_before.is_none_or(|before| htlc.timelock.0 < before.0)
+    }
+}
+
+#[near_bindgen]
+impl FusionPlusContract {
+    // Filtered HTLCs, oldest first. The cursor is the sequence number of the
+    // last HTLC looked at, so pages stay stable while HTLCs are created or
+    // settled in between calls.
+    pub fn query_htlcs(&self, filter: HTLCFilter, from_cursor: Option<U64>, limit: Option<u32>) -> HTLCPage {
+        let limit = limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE) as usize;
+        let after = from_cursor.map_or(0, |cursor| cursor.0);
+
+        let mut htlcs = Vec::new();
+        let mut last = after;
+        for (scanned, number) in self.candidate_numbers(&filter, after).into_iter().enumerate() {
+            if htlcs.len() == limit || scanned == MAX_SCAN {
+                return HTLCPage { htlcs, next_cursor: Some(U64(last)) };
+            }
+            last = number;
+            if let Some(htlc) = self.htlcs.get(&htlc_id(number)) {
+                if filter.matches(self, &htlc) {
+                    htlcs.push(htlc);
+                }
+            }
+        }
+        HTLCPage { htlcs, next_cursor: None }
+    }
+
+    // A hashlock belongs to one HTLC at most, so this is the whole of what
+    // revealing its secret unlocks
+    pub fn get_escrows_by_hashlock(&self, hashlock: String) -> Option<HashlockEscrows> {
+        let htlc = self.get_htlc_by_hashlock(hashlock)?;
+        let safety_deposits = self
+            .htlc_deposits
+            .get(&htlc.id)
+            .map(|deposit_ids| deposit_ids.iter().filter_map(|id| self.safety_deposits.get(&id)).collect())
+            .unwrap_or_default();
+        Some(HashlockEscrows { fills: self.get_partial_fills(htlc.id.clone()), safety_deposits, htlc })
+    }
+
+    pub fn get_token_stats(&self, token_id: Option<AccountId>) -> TokenStats {
+        self.token_stats.get(&token_id).unwrap_or(TokenStats { token_id, ..Default::default() })
+    }
+
+    // Statistics of every token that has seen an HTLC
+    pub fn get_stats(&self) -> Vec<TokenStats> {
+        self.token_stats.values().collect()
+    }
+}
+
+impl FusionPlusContract {
+    // Sequence numbers after `after` the filter can match, ascending and no
+    // more than one scan's worth. Walks the narrowest index the filter allows
+    // from the cursor and falls back to every id ever issued.
+    fn candidate_numbers(&self, filter: &HTLCFilter, after: u64) -> Vec<u64> {
+        let index = if let Some(account) = filter.sender.as_ref().or(filter.receiver.as_ref()) {
+            Some(self.user_htlcs.get(account))
+        } else if let Some(resolver) = &filter.resolver {
+            Some(self.resolver_htlcs.get(resolver))
+        } else if let Some(filler) = &filter.filler {
+            Some(self.filler_htlcs.get(filler))
+        } else {
+            None
+        };
+
+        match index {
+            Some(numbers) => numbers
+                .map(|numbers| numbers.iter_from(after).map(|(number, ())| number).take(MAX_SCAN + 1).collect())
+                .unwrap_or_default(),
+            None if matches!(filter.status, Some(HTLCStatus::Active | HTLCStatus::PartiallyFilled)) => {
+                self.active_htlcs.iter_from(after).map(|(number, ())| number).take(MAX_SCAN + 1).collect()
+            }
+            None => (after + 1..self.next_htlc_id).take(MAX_SCAN + 1).collect(),
+        }
+    }
+
+    // A page of every HTLC in `numbers` after the cursor
+    pub(crate) fn index_page(&self, numbers: &TreeMap<u64, ()>, from_cursor: Option<U64>, limit: Option<u32>) -> HTLCPage {
+        let limit = limit.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+        let after = from_cursor.map_or(0, |cursor| cursor.0);
+
+        let mut page: Vec<u64> = numbers.iter_from(after).map(|(number, ())| number).take(limit + 1).collect();
+        let next_cursor = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(|number| U64(*number))
+        } else {
+            None
+        };
+        HTLCPage { htlcs: page.into_iter().filter_map(|number| self.htlcs.get(&htlc_id(number))).collect(), next_cursor }
+    }
+
+    pub(crate) fn update_token_stats(&mut self, token_id: &Option<AccountId>, update: impl FnOnce(&mut TokenStats)) {
+        let mut stats = self.get_token_stats(token_id.clone());
+        update(&mut stats);
+        self.token_stats.insert(token_id, &stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, NearToken};
+    use sha2::{Digest, Sha256};
+
+    use crate::fusion_plus::tests::register_storage;
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn as_account(account_id: AccountId, seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(account_id)
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(START + seconds * 1_000_000_000)
+            .build());
+    }
+
+    // HTLCs from accounts(1) to accounts(2), one per timelock
+    fn htlcs(contract: &mut FusionPlusContract, timelocks: &[u64]) -> Vec<String> {
+        register_storage(contract, accounts(1));
+        as_account(accounts(1), 0);
+        timelocks
+            .iter()
+            .enumerate()
+            .map(|(i, timelock)| {
+                let hashlock = hex::encode(Sha256::digest([i as u8; 32]));
+                contract.create_htlc(CreateHTLCArgs {
+                    receiver: Some(accounts(2)),
+                    hashlock,
+                    timelock_seconds: *timelock,
+                    require_safety_deposit: true,
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    fn ids(page: &HTLCPage) -> Vec<String> {
+        page.htlcs.iter().map(|htlc| htlc.id.clone()).collect()
+    }
+
+    #[test]
+    fn test_cursor_pages_are_stable_across_settlement() {
+        as_account(accounts(0), 0);
+        let mut contract = FusionPlusContract::new();
+        let created = htlcs(&mut contract, &[3600, 3600, 3600, 3600, 3600]);
+        let filter = HTLCFilter { status: Some(HTLCStatus::Active), ..Default::default() };
+
+        let first = contract.query_htlcs(filter.clone(), None, Some(2));
+        assert_eq!(ids(&first), created[..2]);
+
+        // Settling an already listed HTLC takes it out of `active_htlcs` but
+        // does not move the next page
+        as_account(accounts(2), 60);
+        contract.withdraw(created[0].clone(), hex::encode([0u8; 32]));
+        let second = contract.query_htlcs(filter.clone(), first.next_cursor, Some(2));
+        assert_eq!(ids(&second), created[2..4]);
+
+        let last = contract.query_htlcs(filter, second.next_cursor, Some(2));
+        assert_eq!(ids(&last), created[4..]);
+        assert!(last.next_cursor.is_none());
+    }
+
+    #[test]
+    fn test_filter_by_role_expiry_and_resolver() {
+        as_account(accounts(0), 0);
+        let mut contract = FusionPlusContract::new();
+        let created = htlcs(&mut contract, &[3600, 7200, 86400]);
+
+        register_storage(&mut contract, accounts(3));
+        as_account(accounts(3), 0);
+        contract.create_safety_deposit(created[1].clone());
+
+        let as_receiver = HTLCFilter { receiver: Some(accounts(2)), ..Default::default() };
+        assert_eq!(contract.query_htlcs(as_receiver, None, None).htlcs.len(), 3);
+        let as_sender = HTLCFilter { sender: Some(accounts(2)), ..Default::default() };
+        assert!(contract.query_htlcs(as_sender, None, None).htlcs.is_empty());
+
+        // Expiring within the next two hours
+        let now = START / 1_000_000_000;
+        let expiring = HTLCFilter { expires_before: Some(U64(now + 7201)), ..Default::default() };
+        assert_eq!(ids(&contract.query_htlcs(expiring, None, None)), created[..2]);
+
+        // Account indexes are walked from the cursor too
+        let first = contract.query_htlcs(HTLCFilter { receiver: Some(accounts(2)), ..Default::default() }, None, Some(2));
+        assert_eq!(ids(&first), created[..2]);
+        let rest = contract.query_htlcs(
+            HTLCFilter { receiver: Some(accounts(2)), ..Default::default() },
+            first.next_cursor,
+            Some(2),
+        );
+        assert_eq!(ids(&rest), created[2..]);
+
+        let by_resolver = HTLCFilter { resolver: Some(accounts(3)), ..Default::default() };
+        assert_eq!(ids(&contract.query_htlcs(by_resolver, None, None)), created[1..2]);
+        let other_token = HTLCFilter { token: Some(TokenFilter::Token(accounts(4))), ..Default::default() };
+        assert!(contract.query_htlcs(other_token, None, None).htlcs.is_empty());
+    }
+
+    #[test]
+    fn test_user_and_active_pages_follow_the_cursor() {
+        as_account(accounts(0), 0);
+        let mut contract = FusionPlusContract::new();
+        let created = htlcs(&mut contract, &[3600, 3600, 3600]);
+
+        let first = contract.get_user_htlcs(accounts(2), None, Some(2));
+        assert_eq!(ids(&first), created[..2]);
+        // Settling a listed HTLC does not shift the next page
+        as_account(accounts(2), 60);
+        contract.withdraw(created[0].clone(), hex::encode([0u8; 32]));
+        let rest = contract.get_user_htlcs(accounts(2), first.next_cursor, Some(2));
+        assert_eq!(ids(&rest), created[2..]);
+        assert!(rest.next_cursor.is_none());
+
+        let active = contract.get_active_htlcs(None, Some(1));
+        assert_eq!(ids(&active), created[1..2]);
+        assert_eq!(ids(&contract.get_active_htlcs(active.next_cursor, None)), created[2..]);
+        assert!(contract.get_user_htlcs(accounts(4), None, None).htlcs.is_empty());
+    }
+
+    #[test]
+    fn test_token_stats_track_settlement() {
+        as_account(accounts(0), 0);
+        let mut contract = FusionPlusContract::new();
+        let created = htlcs(&mut contract, &[3600, 3600]);
+
+        as_account(accounts(2), 60);
+        contract.withdraw(created[0].clone(), hex::encode([0u8; 32]));
+
+        let stats = contract.get_token_stats(None);
+        let one_near = NearToken::from_near(1).as_yoctonear();
+        assert_eq!(stats.htlcs_created, 2);
+        assert_eq!(stats.active_htlcs, 1);
+        assert_eq!(stats.completed_htlcs, 1);
+        assert_eq!(stats.volume, U128(2 * one_near));
+        assert_eq!(stats.withdrawn_volume, U128(one_near));
+        assert_eq!(contract.get_stats(), vec![stats]);
+    }
+
+    #[test]
+    fn test_escrows_by_hashlock() {
+        as_account(accounts(0), 0);
+        let mut contract = FusionPlusContract::new();
+        let created = htlcs(&mut contract, &[3600, 3600]);
+        register_storage(&mut contract, accounts(3));
+        as_account(accounts(3), 0);
+        let deposit_id = contract.create_safety_deposit(created[1].clone());
+
+        let hashlock = hex::encode(Sha256::digest([1u8; 32]));
+        let escrows = contract.get_escrows_by_hashlock(hashlock.clone()).unwrap();
+        assert_eq!(escrows.htlc.id, created[1]);
+        assert!(escrows.fills.is_empty());
+        assert_eq!(escrows.safety_deposits.len(), 1);
+        assert_eq!(escrows.safety_deposits[0].id, deposit_id);
+        assert!(contract.get_escrows_by_hashlock(hex::encode([9u8; 32])).is_none());
+
+        // The hashlock stays taken after its HTLC settled
+        as_account(accounts(2), 60);
+        contract.withdraw(created[1].clone(), hex::encode([1u8; 32]));
+        as_account(accounts(1), 120);
+        let reused = CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock,
+            timelock_seconds: 3600,
+            allow_partial_fills: false,
+            parts_count: None,
+            min_fill_amount: None,
+            require_safety_deposit: false,
+            stage_durations: None,
+            hash_algorithm: HashAlgorithm::Sha256,
+        };
+        assert_eq!(contract.check_create_args(&accounts(1), 1, &reused), Err("Hashlock already used"));
+    }
+}
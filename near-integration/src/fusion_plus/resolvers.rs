@@ -0,0 +1,442 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, is_promise_success, near_bindgen, require, AccountId, Gas, Promise};
+
+use super::admin::bps_share;
+use super::*;
+
+const MAX_SLASH_BPS: u16 = 5_000; // 50%
+// Longer than any HTLC can stay open, so stake cannot leave ahead of an
+// order its resolver took before unstaking
+const UNSTAKE_DELAY: u64 = htlc_core::MAX_TIMELOCK;
+const GAS_FOR_RESOLVE_STAKE_WITHDRAWAL: Gas = Gas::from_tgas(10);
+const GAS_FOR_RESOLVE_SLASH: Gas = Gas::from_tgas(10);
+const DEFAULT_TAKE_EXCLUSIVITY: u64 = 1800; // 30 minutes
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ResolverConfig {
+    pub stake_token: Option<AccountId>, // None for NEAR
+    pub min_stake: U128,
+    pub slash_bps: u16, // Share of the stake a lapsed order costs
+    pub enforce: bool,  // Only whitelisted, staked resolvers may fill or post safety deposits
+    pub take_exclusivity_seconds: u64, // How long the taker of an open order has it to itself
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            stake_token: None,
+            min_stake: U128(0),
+            slash_bps: 0,
+            enforce: false,
+            take_exclusivity_seconds: DEFAULT_TAKE_EXCLUSIVITY,
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ResolverStatus {
+    Registered,
+    Whitelisted,
+    Delisted,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ResolverStats {
+    pub fills: u64,    // Partial fills taken and full orders withdrawn
+    pub volume: U128,  // Amount of those, in the orders' own tokens
+    pub failures: u64, // Orders and fills that lapsed into a refund
+    pub slashed: U128, // In the stake token
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Resolver {
+    pub account_id: AccountId,
+    pub status: ResolverStatus,
+    pub stake: U128,
+    pub unstaking: U128, // Part of `stake` on its way out, still slashable
+    pub unstake_available_at: U64,
+    pub stats: ResolverStats,
+    pub storage_bytes: u64,
+}
+
+#[near_bindgen]
+impl FusionPlusContract {
+    pub fn register_resolver(&mut self) {
+        let account_id = env::predecessor_account_id();
+        require!(self.resolvers.get(&account_id).is_none(), "Resolver already registered");
+        let initial_storage = env::storage_usage();
+
+        let resolver = Resolver {
+            account_id: account_id.clone(),
+            status: ResolverStatus::Registered,
+            stake: U128(0),
+            unstaking: U128(0),
+            unstake_available_at: U64(0),
+            stats: ResolverStats::default(),
+            storage_bytes: 0,
+        };
+        self.resolvers.insert(&account_id, &resolver);
+        let storage_bytes = self.charge_storage(&account_id, initial_storage);
+        self.resolvers.insert(&account_id, &Resolver { storage_bytes, ..resolver });
+
+        events::ResolverRegistered { account_id }.emit();
+    }
+
+    // Adds the attached NEAR to the caller's stake. Token stakes arrive
+    // through `ft_transfer_call` with {"action":"stake"}.
+    #[payable]
+    pub fn stake(&mut self) {
+        require!(self.resolver_config.stake_token.is_none(), "Stake is in a fungible token");
+        let amount = env::attached_deposit().as_yoctonear();
+        self.internal_stake(env::predecessor_account_id(), amount);
+    }
+
+    // Starts the unstaking delay for `amount` of the caller's stake;
+    // requesting again adds to it and restarts the delay
+    pub fn unstake(&mut self, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        let mut resolver = self.resolvers.get(&account_id).expect("Resolver not registered");
+        require!(amount.0 > 0, "Amount must be greater than 0");
+        require!(amount.0 <= resolver.stake.0 - resolver.unstaking.0, "Amount exceeds stake");
+
+        resolver.unstaking = U128(resolver.unstaking.0 + amount.0);
+        resolver.unstake_available_at = U64(htlc_core::now_seconds() + UNSTAKE_DELAY);
+        self.resolvers.insert(&account_id, &resolver);
+    }
+
+    // Pays out unstaked funds once the delay has passed
+    pub fn withdraw_stake(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let mut resolver = self.resolvers.get(&account_id).expect("Resolver not registered");
+        require!(resolver.unstaking.0 > 0, "Nothing to withdraw");
+        require!(htlc_core::now_seconds() >= resolver.unstake_available_at.0, "Stake still locked");
+
+        let amount = resolver.unstaking.0;
+        resolver.stake = U128(resolver.stake.0 - amount);
+        resolver.unstaking = U128(0);
+        self.resolvers.insert(&account_id, &resolver);
+        self.total_resolver_stake -= amount;
+        events::ResolverStakeChanged { account_id: account_id.clone(), stake: resolver.stake }.emit();
+
+        let token_id = self.resolver_config.stake_token.clone();
+        self.transfer(token_id.as_ref(), account_id.clone(), amount, "Fusion+ stake".to_string()).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_STAKE_WITHDRAWAL)
+                .resolve_stake_withdrawal(account_id, U128(amount)),
+        )
+    }
+
+    // Puts the stake back if the transfer to the resolver failed
+    #[private]
+    pub fn resolve_stake_withdrawal(&mut self, account_id: AccountId, amount: U128) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        if let Some(mut resolver) = self.resolvers.get(&account_id) {
+            resolver.stake = U128(resolver.stake.0 + amount.0);
+            self.resolvers.insert(&account_id, &resolver);
+            events::ResolverStakeChanged { account_id, stake: resolver.stake }.emit();
+        }
+        self.total_resolver_stake += amount.0;
+        let token_id = self.resolver_config.stake_token.clone();
+        self.credit_tokens(token_id.as_ref(), amount.0);
+        false
+    }
+
+    // Puts the slashed stake back if the transfer to the maker failed
+    #[private]
+    pub fn resolve_slash(&mut self, account_id: AccountId, amount: U128) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        if let Some(mut resolver) = self.resolvers.get(&account_id) {
+            resolver.stake = U128(resolver.stake.0 + amount.0);
+            resolver.stats.slashed = U128(resolver.stats.slashed.0 - amount.0);
+            self.resolvers.insert(&account_id, &resolver);
+            events::ResolverStakeChanged { account_id, stake: resolver.stake }.emit();
+        }
+        self.total_resolver_stake += amount.0;
+        let token_id = self.resolver_config.stake_token.clone();
+        self.credit_tokens(token_id.as_ref(), amount.0);
+        false
+    }
+
+    pub fn whitelist_resolver(&mut self, account_id: AccountId) {
+        self.set_resolver_status(account_id, ResolverStatus::Whitelisted);
+    }
+
+    pub fn delist_resolver(&mut self, account_id: AccountId) {
+        self.set_resolver_status(account_id, ResolverStatus::Delisted);
+    }
+
+    // The stake token can only change while nothing is staked
+    pub fn set_resolver_config(&mut self, config: ResolverConfig) {
+        self.assert_owner();
+        require!(config.slash_bps <= MAX_SLASH_BPS, "Slash share too high");
+        require!(
+            (1..=htlc_core::MAX_TIMELOCK).contains(&config.take_exclusivity_seconds),
+            "Invalid exclusivity window"
+        );
+        require!(
+            config.stake_token == self.resolver_config.stake_token || self.total_resolver_stake == 0,
+            "Resolvers still have stake"
+        );
+        self.resolver_config = config;
+    }
+
+    pub fn get_resolver_config(&self) -> ResolverConfig {
+        self.resolver_config.clone()
+    }
+
+    pub fn get_resolver(&self, account_id: AccountId) -> Option<Resolver> {
+        self.resolvers.get(&account_id)
+    }
+
+    pub fn get_resolvers(&self, offset: u64, limit: u64) -> Vec<Resolver> {
+        self.resolvers.values().skip(offset as usize).take(limit as usize).collect()
+    }
+
+    // Whether the account may fill orders and post safety deposits
+    pub fn is_authorized_resolver(&self, account_id: AccountId) -> bool {
+        !self.resolver_config.enforce
+            || self.resolvers.get(&account_id).is_some_and(|resolver| {
+                resolver.status == ResolverStatus::Whitelisted
+                    && resolver.stake.0 - resolver.unstaking.0 >= self.resolver_config.min_stake.0
+            })
+    }
+}
+
+impl FusionPlusContract {
+    pub(crate) fn internal_stake(&mut self, account_id: AccountId, amount: u128) {
+        let mut resolver = self.resolvers.get(&account_id).expect("Resolver not registered");
+        require!(amount > 0, "Amount must be greater than 0");
+        resolver.stake = U128(resolver.stake.0 + amount);
+        self.resolvers.insert(&account_id, &resolver);
+        self.total_resolver_stake += amount;
+
+        events::ResolverStakeChanged { account_id, stake: resolver.stake }.emit();
+    }
+
+    pub(crate) fn assert_authorized_resolver(&self, account_id: &AccountId) {
+        require!(self.is_authorized_resolver(account_id.clone()), "Not an authorized resolver");
+    }
+
+    // Counts a fill or withdrawn order towards a registered resolver
+    pub(crate) fn record_resolver_fill(&mut self, account_id: &AccountId, amount: u128) {
+        if let Some(mut resolver) = self.resolvers.get(account_id) {
+            resolver.stats.fills += 1;
+            resolver.stats.volume = U128(resolver.stats.volume.0 + amount);
+            self.resolvers.insert(account_id, &resolver);
+        }
+    }
+
+    // Called once the refund of a lapsed order (or of one fill) went
+    // through. Only the taker answers for it: the order's receiver if it let
+    // its withdrawal window pass, or the fill's filler. It pays `slash_bps`
+    // of its stake to the maker, put back by `resolve_slash` if that fails.
+    pub(crate) fn slash_for_lapse(&mut self, htlc_id: &String, fill_id: Option<&str>) {
+        let Some(htlc) = self.htlcs.get(htlc_id) else {
+            return;
+        };
+        let taker = match fill_id {
+            Some(fill_id) => Self::taker_window_passed(&htlc, htlc_core::now_seconds())
+                .then(|| self.get_fill(htlc_id, fill_id).filler),
+            None => htlc.receiver.clone().filter(|_| htlc.taker_lapsed),
+        };
+        let Some(account_id) = taker else {
+            return;
+        };
+        let Some(mut resolver) = self.resolvers.get(&account_id) else {
+            return;
+        };
+
+        let amount = bps_share(resolver.stake.0, self.resolver_config.slash_bps);
+        resolver.stake = U128(resolver.stake.0 - amount);
+        resolver.unstaking = U128(resolver.unstaking.0.min(resolver.stake.0));
+        resolver.stats.failures += 1;
+        resolver.stats.slashed = U128(resolver.stats.slashed.0 + amount);
+        self.resolvers.insert(&account_id, &resolver);
+        if amount == 0 {
+            return;
+        }
+
+        self.total_resolver_stake -= amount;
+        events::ResolverSlashed {
+            account_id: account_id.clone(),
+            htlc_id: htlc_id.clone(),
+            maker: htlc.sender.clone(),
+            amount: U128(amount),
+        }.emit();
+        let token_id = self.resolver_config.stake_token.clone();
+        self.transfer(token_id.as_ref(), htlc.sender.clone(), amount, format!("Fusion+ {} slash", htlc_id)).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_SLASH)
+                .resolve_slash(account_id, U128(amount)),
+        );
+    }
+
+    fn set_resolver_status(&mut self, account_id: AccountId, status: ResolverStatus) {
+        self.assert_owner();
+        let mut resolver = self.resolvers.get(&account_id).expect("Resolver not registered");
+        let whitelisted = status == ResolverStatus::Whitelisted;
+        resolver.status = status;
+        self.resolvers.insert(&account_id, &resolver);
+        events::ResolverStatusChanged { account_id, whitelisted }.emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, NearToken, PromiseResult, RuntimeFeesConfig};
+    use sha2::{Digest, Sha256};
+
+    use crate::fusion_plus::tests::register_storage;
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn as_account(account_id: AccountId, seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(account_id)
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(START + seconds * 1_000_000_000)
+            .build());
+    }
+
+    // A whitelisted resolver, accounts(3), with 1 NEAR at stake
+    fn contract_with_resolver() -> FusionPlusContract {
+        as_account(accounts(0), 0);
+        let mut contract = FusionPlusContract::new();
+        contract.set_resolver_config(ResolverConfig {
+            min_stake: U128(NearToken::from_near(1).as_yoctonear()),
+            slash_bps: 1_000,
+            enforce: true,
+            ..Default::default()
+        });
+        register_storage(&mut contract, accounts(1));
+        register_storage(&mut contract, accounts(3));
+
+        as_account(accounts(3), 0);
+        contract.register_resolver();
+        contract.stake();
+        as_account(accounts(0), 0);
+        contract.whitelist_resolver(accounts(3));
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "Not an authorized resolver")]
+    fn test_enforced_registry_rejects_unknown_resolvers() {
+        let mut contract = contract_with_resolver();
+        as_account(accounts(1), 0);
+        let hashlock = hex::encode(Sha256::digest([1u8; 32]));
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock,
+            timelock_seconds: 3600,
+            require_safety_deposit: true,
+            ..Default::default()
+        });
+
+        as_account(accounts(4), 0);
+        contract.create_safety_deposit(htlc_id);
+    }
+
+    // An order taken by accounts(3), with a safety deposit from accounts(4)
+    // as well, that lapses into a refund
+    fn refund_lapsed_order(contract: &mut FusionPlusContract) {
+        register_storage(contract, accounts(4));
+        as_account(accounts(4), 0);
+        contract.register_resolver();
+        contract.stake();
+        as_account(accounts(0), 0);
+        contract.whitelist_resolver(accounts(4));
+
+        as_account(accounts(1), 0);
+        let hashlock = hex::encode(Sha256::digest([1u8; 32]));
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(3)),
+            hashlock,
+            timelock_seconds: 3600,
+            require_safety_deposit: true,
+            ..Default::default()
+        });
+        for resolver in [accounts(3), accounts(4)] {
+            as_account(resolver, 0);
+            contract.create_safety_deposit(htlc_id.clone());
+        }
+
+        as_account(accounts(1), 7200);
+        contract.refund(htlc_id.clone());
+        testing_env!(
+            VMContextBuilder::new().current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.resolve_payout(htlc_id, PayoutKind::Refund, HTLCStatus::Active, U128(0));
+    }
+
+    #[test]
+    fn test_lapsed_order_slashes_taker_to_maker() {
+        let mut contract = contract_with_resolver();
+        refund_lapsed_order(&mut contract);
+
+        let resolver = contract.get_resolver(accounts(3)).unwrap();
+        let one_near = NearToken::from_near(1).as_yoctonear();
+        assert_eq!(resolver.stake, U128(one_near - one_near / 10));
+        assert_eq!(resolver.stats.failures, 1);
+        assert_eq!(resolver.stats.slashed, U128(one_near / 10));
+        // Below the minimum stake, so no longer authorized
+        assert!(!contract.is_authorized_resolver(accounts(3)));
+
+        // The other resolver only posted a deposit
+        let other = contract.get_resolver(accounts(4)).unwrap();
+        assert_eq!(other.stake, U128(one_near));
+        assert_eq!(other.stats.failures, 0);
+    }
+
+    #[test]
+    fn test_failed_slash_restores_stake() {
+        let mut contract = contract_with_resolver();
+        refund_lapsed_order(&mut contract);
+        let total_stake = contract.total_resolver_stake;
+        let slashed = NearToken::from_near(1).as_yoctonear() / 10;
+
+        testing_env!(
+            VMContextBuilder::new().current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        assert!(!contract.resolve_slash(accounts(3), U128(slashed)));
+
+        let resolver = contract.get_resolver(accounts(3)).unwrap();
+        assert_eq!(resolver.stake, U128(NearToken::from_near(1).as_yoctonear()));
+        assert_eq!(resolver.stats.slashed, U128(0));
+        assert_eq!(contract.total_resolver_stake, total_stake + slashed);
+        assert!(contract.is_authorized_resolver(accounts(3)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Stake still locked")]
+    fn test_unstake_waits_out_the_delay() {
+        let mut contract = contract_with_resolver();
+        as_account(accounts(3), 0);
+        contract.unstake(U128(NearToken::from_near(1).as_yoctonear()));
+        assert!(!contract.is_authorized_resolver(accounts(3)));
+
+        as_account(accounts(3), UNSTAKE_DELAY - 1);
+        contract.withdraw_stake();
+    }
+}
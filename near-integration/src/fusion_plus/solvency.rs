@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId};
+
+use super::*;
+
+// What the contract owes in one asset against what it holds. `held` is the
+// account balance less the storage stake for NEAR and the ledger of received
+// tokens for a NEP-141 token. At most one of `surplus` and `deficit` is
+// non-zero.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetSolvency {
+    pub token_id: Option<AccountId>, // None for NEAR
+    pub escrowed: U128,              // Unfilled amounts of open HTLCs
+    pub pending_fills: U128,
+    pub safety_deposits: U128,  // NEAR only
+    pub resolver_stake: U128,
+    pub accrued_fees: U128,
+    pub storage_balances: U128, // NEAR only, the unlocked NEP-145 balances
+    pub obligations: U128,      // Sum of the above
+    pub held: U128,
+    pub surplus: U128,
+    pub deficit: U128,
+}
+
+#[near_bindgen]
+impl FusionPlusContract {
+    // Per-asset report of obligations against holdings, NEAR first. Only
+    // HTLCs in `active_htlcs` can still owe anything, so settled ones are
+    // never loaded; meant for view calls by monitoring.
+    pub fn verify_solvency(&self) -> Vec<AssetSolvency> {
+        let mut assets = BTreeMap::new();
+
+        for (number, ()) in self.active_htlcs.iter() {
+            let htlc = self.htlcs.get(&htlc_id(number)).expect("Active index out of sync");
+            if matches!(htlc.status, HTLCStatus::Active | HTLCStatus::PartiallyFilled) {
+                asset(&mut assets, &htlc.token_id).escrowed.0 += Self::refund_amount(&htlc);
+            }
+            if htlc.pending_fills == 0 {
+                continue;
+            }
+            let fill_ids = self.htlc_fills.get(&htlc.id).map(|ids| ids.to_vec()).unwrap_or_default();
+            for fill in fill_ids.iter().filter_map(|fill_id| self.partial_fills.get(fill_id)) {
+                if fill.status == FillStatus::Pending {
+                    asset(&mut assets, &htlc.token_id).pending_fills.0 += fill.amount.0;
+                }
+            }
+        }
+
+        let native = asset(&mut assets, &None);
+        native.safety_deposits.0 = self.safety_deposits.values().map(|deposit| deposit.amount.0).sum();
+        native.storage_balances = U128(self.storage_available);
+        asset(&mut assets, &self.resolver_config.stake_token).resolver_stake = U128(self.total_resolver_stake);
+        for (token_id, amount) in self.accrued_fees.iter() {
+            asset(&mut assets, &token_id).accrued_fees = U128(amount);
+        }
+        for (token_id, _) in self.token_ledger.iter() {
+            asset(&mut assets, &Some(token_id));
+        }
+
+        let storage_stake = env::storage_byte_cost().as_yoctonear() * env::storage_usage() as u128;
+        assets
+            .into_values()
+            .map(|mut report| {
+                report.held = U128(match &report.token_id {
+                    None => env::account_balance().as_yoctonear().saturating_sub(storage_stake),
+                    Some(token_id) => self.token_ledger.get(token_id).unwrap_or(0),
+                });
+                report.obligations = U128(
+                    report.escrowed.0
+                        + report.pending_fills.0
+                        + report.safety_deposits.0
+                        + report.resolver_stake.0
+                        + report.accrued_fees.0
+                        + report.storage_balances.0,
+                );
+                report.surplus = U128(report.held.0.saturating_sub(report.obligations.0));
+                report.deficit = U128(report.obligations.0.saturating_sub(report.held.0));
+                report
+            })
+            .collect()
+    }
+}
+
+fn asset<'a>(
+    assets: &'a mut BTreeMap<Option<AccountId>, AssetSolvency>,
+    token_id: &Option<AccountId>,
+) -> &'a mut AssetSolvency {
+    assets
+        .entry(token_id.clone())
+        .or_insert_with(|| AssetSolvency { token_id: token_id.clone(), ..Default::default() })
+}
+
+impl FusionPlusContract {
+    pub(crate) fn credit_tokens(&mut self, token_id: Option<&AccountId>, amount: u128) {
+        if let Some(token_id) = token_id {
+            let held = self.token_ledger.get(token_id).unwrap_or(0);
+            self.token_ledger.insert(token_id, &(held + amount));
+        }
+    }
+
+    // Saturates, so a ledger short of a payout never blocks it; the deficit
+    // shows up in `verify_solvency` instead
+    pub(crate) fn debit_tokens(&mut self, token_id: Option<&AccountId>, amount: u128) {
+        if let Some(token_id) = token_id {
+            let held = self.token_ledger.get(token_id).unwrap_or(0);
+            self.token_ledger.insert(token_id, &held.saturating_sub(amount));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+    use sha2::{Digest, Sha256};
+
+    use crate::fusion_plus::tests::register_storage;
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn as_account(account_id: AccountId, balance: NearToken) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(account_id)
+            .account_balance(balance)
+            .block_timestamp(START)
+            .build());
+    }
+
+    // A 1 NEAR HTLC and a 1_000_000 token HTLC (token accounts(4)) from accounts(1)
+    fn funded_contract() -> (FusionPlusContract, String) {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(START)
+            .build());
+        contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode([1u8; 32]),
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+
+        as_account(accounts(4), NearToken::from_near(10));
+        let msg = format!(
+            r#"{{"action":"create_htlc","receiver":"{}","hashlock":"{}","timelock_seconds":3600}}"#,
+            accounts(2),
+            hex::encode(Sha256::digest([2u8; 32]))
+        );
+        contract.ft_on_transfer(accounts(1), U128(1_000_000), msg);
+        let token_htlc = format!("htlc_{}", contract.next_htlc_id - 1);
+        (contract, token_htlc)
+    }
+
+    #[test]
+    fn test_solvency_report_per_asset() {
+        let (contract, _) = funded_contract();
+        as_account(accounts(0), NearToken::from_near(100));
+        let report = contract.verify_solvency();
+        assert_eq!(report.len(), 2);
+
+        let native = &report[0];
+        let storage = contract.storage_balance_of(accounts(1)).unwrap().available.as_yoctonear();
+        assert_eq!(native.token_id, None);
+        assert_eq!(native.escrowed.0, NearToken::from_near(1).as_yoctonear());
+        assert_eq!(native.storage_balances.0, storage);
+        assert_eq!(native.obligations.0, NearToken::from_near(1).as_yoctonear() + storage);
+        let held = NearToken::from_near(100).as_yoctonear()
+            - env::storage_byte_cost().as_yoctonear() * env::storage_usage() as u128;
+        assert_eq!(native.held.0, held);
+        assert_eq!(native.surplus.0, held - native.obligations.0);
+        assert_eq!(native.deficit.0, 0);
+
+        let token = &report[1];
+        assert_eq!(token.token_id, Some(accounts(4)));
+        assert_eq!(token.escrowed.0, 1_000_000);
+        assert_eq!(token.held.0, 1_000_000);
+        assert_eq!((token.surplus.0, token.deficit.0), (0, 0));
+    }
+
+    #[test]
+    fn test_native_shortfall_is_a_deficit() {
+        let (contract, _) = funded_contract();
+        // Less than the storage stake plus the escrowed NEAR
+        as_account(accounts(0), NearToken::from_millinear(500));
+        let native = &contract.verify_solvency()[0];
+        assert_eq!(native.surplus.0, 0);
+        assert_eq!(native.deficit.0, native.obligations.0 - native.held.0);
+    }
+
+    #[test]
+    fn test_failed_token_payout_returns_to_ledger() {
+        let (mut contract, htlc_id) = funded_contract();
+        as_account(accounts(2), NearToken::from_near(10));
+        contract.withdraw(htlc_id.clone(), hex::encode([2u8; 32]));
+
+        // In flight: the tokens left the ledger along with the obligation
+        let token = &contract.verify_solvency()[1];
+        assert_eq!((token.escrowed.0, token.held.0), (0, 0));
+
+        testing_env!(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .block_timestamp(START)
+                .build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.resolve_payout(htlc_id, PayoutKind::Withdraw, HTLCStatus::Active, U128(0));
+
+        let token = &contract.verify_solvency()[1];
+        assert_eq!((token.escrowed.0, token.held.0), (1_000_000, 1_000_000));
+        assert_eq!(token.deficit.0, 0);
+    }
+}
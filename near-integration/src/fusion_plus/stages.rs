@@ -0,0 +1,163 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U64;
+use near_sdk::serde::{Deserialize, Serialize};
+
+// Stage durations in seconds, matching `StageDurations` of the Stellar
+// fusion-htlc contract so both legs of a swap can share one timing policy
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StageDurations {
+    pub finality_delay: u64,                // Time until the HTLC is final
+    pub taker_exclusive_duration: u64,      // Taker-only withdrawal
+    pub private_resolver_duration: u64,     // Taker or the HTLC's resolvers
+    pub public_resolver_duration: u64,      // Anyone, funds still go to the taker
+    pub private_cancellation_duration: u64, // Maker or the HTLC's resolvers
+}
+
+impl StageDurations {
+    // Single-timelock HTLC: the taker can withdraw until `timelock_seconds`,
+    // after which only the maker can cancel
+    pub fn from_timelock(timelock_seconds: u64) -> Self {
+        Self {
+            finality_delay: 0,
+            taker_exclusive_duration: timelock_seconds,
+            private_resolver_duration: 0,
+            public_resolver_duration: 0,
+            private_cancellation_duration: u64::MAX,
+        }
+    }
+
+    // Time from creation until cancellation opens
+    pub fn withdrawal_window(&self) -> u64 {
+        self.finality_delay
+            .saturating_add(self.taker_exclusive_duration)
+            .saturating_add(self.private_resolver_duration)
+            .saturating_add(self.public_resolver_duration)
+    }
+}
+
+// Absolute stage boundaries in seconds
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Timelocks {
+    pub finality_time: U64,       // When the HTLC becomes final
+    pub taker_deadline: U64,      // End of the taker's exclusive period
+    pub public_deadline: U64,     // Anyone can settle after this
+    pub cancellation_start: U64,  // Private cancellation period starts
+    pub cancellation_public: U64, // Public cancellation period starts
+}
+
+impl Timelocks {
+    pub fn new(start: u64, durations: &StageDurations) -> Self {
+        let finality_time = start.saturating_add(durations.finality_delay);
+        let taker_deadline = finality_time.saturating_add(durations.taker_exclusive_duration);
+        let public_deadline = taker_deadline.saturating_add(durations.private_resolver_duration);
+        let cancellation_start = public_deadline.saturating_add(durations.public_resolver_duration);
+        let cancellation_public = cancellation_start.saturating_add(durations.private_cancellation_duration);
+
+        Self {
+            finality_time: U64(finality_time),
+            taker_deadline: U64(taker_deadline),
+            public_deadline: U64(public_deadline),
+            cancellation_start: U64(cancellation_start),
+            cancellation_public: U64(cancellation_public),
+        }
+    }
+
+    // Single-timelock HTLCs stay in private cancellation for good
+    pub fn has_public_cancellation(&self) -> bool {
+        self.cancellation_public.0 != u64::MAX
+    }
+
+    pub fn stage_at(&self, current_time: u64) -> Stage {
+        if current_time < self.finality_time.0 {
+            Stage::Pending
+        } else if current_time < self.taker_deadline.0 {
+            Stage::TakerSettlement
+        } else if current_time < self.public_deadline.0 {
+            Stage::PrivateSettlement
+        } else if current_time < self.cancellation_start.0 {
+            Stage::PublicSettlement
+        } else if current_time < self.cancellation_public.0 {
+            Stage::PrivateCancellation
+        } else {
+            Stage::PublicCancellation
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Stage {
+    Pending,             // Before finality_time, nothing can be settled
+    TakerSettlement,     // Only the taker can withdraw
+    PrivateSettlement,   // Taker or the HTLC's resolvers can withdraw
+    PublicSettlement,    // Anyone can withdraw on the taker's behalf
+    PrivateCancellation, // Maker or the HTLC's resolvers can cancel
+    PublicCancellation,  // Anyone can cancel on the maker's behalf
+}
+
+impl Stage {
+    pub fn is_withdrawal(&self) -> bool {
+        matches!(self, Stage::TakerSettlement | Stage::PrivateSettlement | Stage::PublicSettlement)
+    }
+
+    pub fn is_cancellation(&self) -> bool {
+        matches!(self, Stage::PrivateCancellation | Stage::PublicCancellation)
+    }
+
+    // Whether a caller may withdraw in this stage
+    pub fn can_withdraw(&self, is_taker: bool, is_resolver: bool) -> bool {
+        match self {
+            Stage::TakerSettlement => is_taker,
+            Stage::PrivateSettlement => is_taker || is_resolver,
+            Stage::PublicSettlement => true,
+            _ => false,
+        }
+    }
+
+    // Whether a caller may cancel in this stage
+    pub fn can_cancel(&self, is_maker: bool, is_resolver: bool) -> bool {
+        match self {
+            Stage::PrivateCancellation => is_maker || is_resolver,
+            Stage::PublicCancellation => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage_boundaries() {
+        let timelocks = Timelocks::new(
+            1000,
+            &StageDurations {
+                finality_delay: 10,
+                taker_exclusive_duration: 20,
+                private_resolver_duration: 30,
+                public_resolver_duration: 40,
+                private_cancellation_duration: 50,
+            },
+        );
+
+        assert_eq!(timelocks.stage_at(1009), Stage::Pending);
+        assert_eq!(timelocks.stage_at(1010), Stage::TakerSettlement);
+        assert_eq!(timelocks.stage_at(1030), Stage::PrivateSettlement);
+        assert_eq!(timelocks.stage_at(1060), Stage::PublicSettlement);
+        assert_eq!(timelocks.stage_at(1100), Stage::PrivateCancellation);
+        assert_eq!(timelocks.stage_at(1150), Stage::PublicCancellation);
+    }
+
+    #[test]
+    fn test_single_timelock_never_opens_public_cancellation() {
+        let timelocks = Timelocks::new(1000, &StageDurations::from_timelock(3600));
+
+        assert_eq!(timelocks.stage_at(1000), Stage::TakerSettlement);
+        assert_eq!(timelocks.cancellation_start.0, 4600);
+        assert_eq!(timelocks.stage_at(u64::MAX - 1), Stage::PrivateCancellation);
+        assert!(!timelocks.has_public_cancellation());
+    }
+}
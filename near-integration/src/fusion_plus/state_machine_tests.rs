@@ -0,0 +1,482 @@
+// Property-based state-machine tests. Random sequences of creates, fills,
+// withdrawals, refunds, cancels, safety deposits, sweeps and time advances run across
+// several accounts, and the invariants are checked after every step.
+// Payouts stay in flight until a `Deliver` step resolves their callback, so
+// failed transfers and their rollbacks are part of the sequences too.
+//
+// Steps the contract would reject are skipped by the harness, so any panic
+// is a bug. Failing sequences shrink to a minimal reproduction.
+use std::collections::{HashMap, HashSet};
+
+use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+use near_sdk::{test_vm_config, testing_env, MockedBlockchain, PromiseResult, RuntimeFeesConfig};
+use proptest::prelude::*;
+use proptest::sample::Index;
+use sha2::{Digest, Sha256};
+
+use super::*;
+use crate::fusion_plus::tests::register_storage;
+
+const START: u64 = 1_000_000_000_000_000_000;
+const PARTS: u32 = 4;
+const ACCOUNTS: usize = 4; // accounts(1) to accounts(4) take part
+
+#[derive(Debug, Clone)]
+enum Op {
+    // No receiver makes an open order, taken by the first safety deposit
+    Create { sender: usize, receiver: Option<usize>, near: u8, timelock: u64, partial: bool, deposit: bool },
+    Fill { htlc: Index, filler: usize, percent: u8 },
+    Withdraw { htlc: Index, caller: usize },
+    WithdrawFill { fill: Index, caller: usize },
+    Refund { htlc: Index, caller: usize },
+    RefundFill { fill: Index, caller: usize },
+    Cancel { htlc: Index, caller: usize },
+    Deposit { htlc: Index, depositor: usize },
+    Claim { deposit: Index, caller: usize },
+    Sweep { caller: usize },
+    Deliver { payout: Index, success: bool },
+    Advance { seconds: u64 },
+}
+
+fn account() -> impl Strategy<Value = usize> {
+    1..=ACCOUNTS
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        3 => (account(), prop::option::weighted(0.8, account()), 1..=5u8, 3600..=7200u64, any::<bool>(), any::<bool>()).prop_map(
+            |(sender, receiver, near, timelock, partial, deposit)| Op::Create {
+                sender,
+                receiver,
+                near,
+                timelock,
+                partial,
+                deposit,
+            }
+        ),
+        3 => (any::<Index>(), account(), 1..=100u8)
+            .prop_map(|(htlc, filler, percent)| Op::Fill { htlc, filler, percent }),
+        2 => (any::<Index>(), account()).prop_map(|(htlc, caller)| Op::Withdraw { htlc, caller }),
+        2 => (any::<Index>(), account()).prop_map(|(fill, caller)| Op::WithdrawFill { fill, caller }),
+        2 => (any::<Index>(), account()).prop_map(|(htlc, caller)| Op::Refund { htlc, caller }),
+        2 => (any::<Index>(), account()).prop_map(|(fill, caller)| Op::RefundFill { fill, caller }),
+        1 => (any::<Index>(), account()).prop_map(|(htlc, caller)| Op::Cancel { htlc, caller }),
+        1 => (any::<Index>(), account()).prop_map(|(htlc, depositor)| Op::Deposit { htlc, depositor }),
+        1 => (any::<Index>(), account()).prop_map(|(deposit, caller)| Op::Claim { deposit, caller }),
+        1 => account().prop_map(|caller| Op::Sweep { caller }),
+        4 => (any::<Index>(), prop::bool::weighted(0.8))
+            .prop_map(|(payout, success)| Op::Deliver { payout, success }),
+        2 => (1..=4000u64).prop_map(|seconds| Op::Advance { seconds }),
+    ]
+}
+
+// A payout whose `resolve_payout` callback has not run yet
+struct PendingPayout {
+    htlc_id: String,
+    kind: PayoutKind,
+    previous_status: HTLCStatus,
+    amount: u128,
+}
+
+struct Harness {
+    contract: FusionPlusContract,
+    now: u64, // Seconds since START
+    htlcs: Vec<String>,
+    secrets: HashMap<String, Vec<[u8; 32]>>, // One per part plus one; full orders use the first
+    fills: Vec<(String, String)>,            // (htlc_id, fill_id)
+    deposits: Vec<String>,
+    in_flight: Vec<PendingPayout>,
+    claims: Vec<SafetyDeposit>, // Deposit payouts whose `resolve_deposit_payout` has not run yet
+    deposited: u128, // Everything attached for escrow and safety deposits
+    paid: u128,      // Everything delivered back out
+    payouts: HashMap<String, u32>, // Successful payouts per HTLC or fill
+    cancelled: HashSet<String>,    // Cancelled orders, which keep nothing remaining
+}
+
+fn pick<T: Clone>(items: &[T], index: Index) -> Option<T> {
+    (!items.is_empty()).then(|| items[index.index(items.len())].clone())
+}
+
+fn leaves(secrets: &[[u8; 32]]) -> Vec<merkle::Hash> {
+    secrets.iter().enumerate().map(|(i, secret)| merkle::leaf(HashAlgorithm::Sha256, i as u32, &Sha256::digest(secret))).collect()
+}
+
+fn is_open(htlc: &FusionHTLC) -> bool {
+    htlc.status == HTLCStatus::Active || htlc.status == HTLCStatus::PartiallyFilled
+}
+
+fn is_settled(htlc: &FusionHTLC) -> bool {
+    htlc.status == HTLCStatus::Completed || htlc.status == HTLCStatus::Refunded
+}
+
+impl Harness {
+    fn new() -> Self {
+        // `testing_env!` carries storage over, so a case would otherwise
+        // start on top of what the previous one left behind
+        env::set_blockchain_interface(MockedBlockchain::new(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            vec![],
+            Default::default(),
+            Default::default(),
+            None,
+        ));
+        let mut contract = FusionPlusContract::new();
+        for i in 1..=ACCOUNTS {
+            register_storage(&mut contract, accounts(i));
+        }
+        Self {
+            contract,
+            now: 0,
+            htlcs: Vec::new(),
+            secrets: HashMap::new(),
+            fills: Vec::new(),
+            deposits: Vec::new(),
+            in_flight: Vec::new(),
+            claims: Vec::new(),
+            deposited: 0,
+            paid: 0,
+            payouts: HashMap::new(),
+            cancelled: HashSet::new(),
+        }
+    }
+
+    fn context(&self, predecessor: AccountId, deposit: u128) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .attached_deposit(NearToken::from_yoctonear(deposit))
+            .block_timestamp(START + self.now * 1_000_000_000)
+            .build());
+    }
+
+    fn htlc(&self, htlc_id: &str) -> FusionHTLC {
+        self.contract.get_htlc(htlc_id.to_string()).expect("HTLC not found")
+    }
+
+    fn stage(&self, htlc_id: &str) -> Stage {
+        self.contract.get_htlc_stage(htlc_id.to_string()).expect("HTLC not found")
+    }
+
+    fn apply(&mut self, op: Op) {
+        let before: HashMap<String, HTLCStatus> =
+            self.htlcs.iter().map(|id| (id.clone(), self.htlc(id).status)).collect();
+        let held: HashMap<String, SafetyDeposit> = self.contract.safety_deposits.iter().collect();
+
+        match op {
+            Op::Create { sender, receiver, near, timelock, partial, deposit } => {
+                let amount = NearToken::from_near(near as u128).as_yoctonear();
+                let seed = self.htlcs.len() as u8 + 1;
+                let secrets: Vec<[u8; 32]> = (0..=PARTS)
+                    .map(|i| {
+                        let mut secret = [seed; 32];
+                        secret[1] = i as u8;
+                        secret
+                    })
+                    .collect();
+                let hashlock = if partial {
+                    hex::encode(merkle::root(HashAlgorithm::Sha256, &leaves(&secrets)))
+                } else {
+                    hex::encode(Sha256::digest(secrets[0]))
+                };
+
+                self.context(accounts(sender), amount);
+                let htlc_id = self.contract.create_htlc(CreateHTLCArgs {
+                    receiver: receiver.map(accounts),
+                    hashlock,
+                    timelock_seconds: timelock,
+                    allow_partial_fills: partial,
+                    parts_count: partial.then_some(PARTS),
+                    require_safety_deposit: deposit,
+                    ..Default::default()
+                });
+                self.deposited += amount;
+                self.secrets.insert(htlc_id.clone(), secrets);
+                self.htlcs.push(htlc_id);
+            }
+            Op::Fill { htlc, filler, percent } => {
+                let Some(htlc_id) = pick(&self.htlcs, htlc) else { return };
+                let htlc = self.htlc(&htlc_id);
+                let now = START / 1_000_000_000 + self.now;
+                if !htlc.allow_partial_fills || !is_open(&htlc) || now >= htlc.timelock.0 {
+                    return;
+                }
+                let remaining = htlc.remaining_amount.0;
+                let amount = (remaining * percent as u128 / 100).max(htlc.min_fill_amount.0).min(remaining);
+                if amount == 0 {
+                    return;
+                }
+                let filled = htlc.total_amount.0 - remaining;
+                let Some(index) = merkle::expected_secret_index(htlc.total_amount.0, filled, amount, htlc.parts_count)
+                else {
+                    return;
+                };
+
+                let secrets = &self.secrets[&htlc_id];
+                let proof = merkle::proof(HashAlgorithm::Sha256, &leaves(secrets), index as usize).iter().map(hex::encode).collect();
+                let secret_hash = hex::encode(Sha256::digest(secrets[index as usize]));
+                self.context(accounts(filler), 0);
+                let fill_id =
+                    self.contract.create_partial_fill(htlc_id.clone(), U128(amount), index, secret_hash, proof);
+                self.fills.push((htlc_id, fill_id));
+            }
+            Op::Withdraw { htlc, caller } => {
+                let Some(htlc_id) = pick(&self.htlcs, htlc) else { return };
+                let htlc = self.htlc(&htlc_id);
+                let caller = accounts(caller);
+                self.context(caller.clone(), 0);
+                let is_resolver = htlc.resolvers.contains(&caller);
+                let allowed = self.stage(&htlc_id).can_withdraw(htlc.receiver.as_ref() == Some(&caller), is_resolver);
+                if htlc.receiver.is_none() || htlc.allow_partial_fills || !is_open(&htlc) || !allowed {
+                    return;
+                }
+                self.contract.withdraw(htlc_id.clone(), hex::encode(self.secrets[&htlc_id][0]));
+            }
+            Op::WithdrawFill { fill, caller } => {
+                let Some((htlc_id, fill_id)) = pick(&self.fills, fill) else { return };
+                let htlc = self.htlc(&htlc_id);
+                let fill = self.contract.get_fill(&htlc_id, &fill_id);
+                let caller = accounts(caller);
+                self.context(caller.clone(), 0);
+                let is_resolver = htlc.resolvers.contains(&caller);
+                let allowed = self.stage(&htlc_id).can_withdraw(caller == fill.filler, is_resolver);
+                if fill.status != FillStatus::Pending || !allowed {
+                    return;
+                }
+                let secret = hex::encode(self.secrets[&htlc_id][fill.secret_index as usize]);
+                self.contract.withdraw_partial(htlc_id, fill_id, secret);
+            }
+            Op::Refund { htlc, caller } => {
+                let Some(htlc_id) = pick(&self.htlcs, htlc) else { return };
+                let htlc = self.htlc(&htlc_id);
+                let caller = accounts(caller);
+                self.context(caller.clone(), 0);
+                let allowed = self.stage(&htlc_id).can_cancel(caller == htlc.sender, htlc.resolvers.contains(&caller));
+                if is_settled(&htlc) || !allowed {
+                    return;
+                }
+                self.contract.refund(htlc_id);
+            }
+            Op::RefundFill { fill, caller } => {
+                let Some((htlc_id, fill_id)) = pick(&self.fills, fill) else { return };
+                let htlc = self.htlc(&htlc_id);
+                let fill = self.contract.get_fill(&htlc_id, &fill_id);
+                let caller = accounts(caller);
+                self.context(caller.clone(), 0);
+                let allowed = self.stage(&htlc_id).can_cancel(
+                    caller == htlc.sender,
+                    caller == fill.filler || htlc.resolvers.contains(&caller),
+                );
+                if fill.status != FillStatus::Pending || !allowed {
+                    return;
+                }
+                self.contract.refund_partial_fill(htlc_id, fill_id);
+            }
+            Op::Cancel { htlc, caller } => {
+                let Some(htlc_id) = pick(&self.htlcs, htlc) else { return };
+                let htlc = self.htlc(&htlc_id);
+                let caller = accounts(caller);
+                let filled = htlc.remaining_amount != htlc.total_amount || htlc.pending_fills > 0;
+                if caller != htlc.sender || !is_open(&htlc) || !htlc.resolvers.is_empty() || filled {
+                    return;
+                }
+                self.context(caller, 0);
+                self.contract.cancel_order(htlc_id.clone());
+                self.cancelled.insert(htlc_id);
+            }
+            Op::Deposit { htlc, depositor } => {
+                let Some(htlc_id) = pick(&self.htlcs, htlc) else { return };
+                let htlc = self.htlc(&htlc_id);
+                if !is_open(&htlc) || htlc.safety_deposit_amount.0 == 0 {
+                    return;
+                }
+                // An open order is taken before its timelock, and held by
+                // its taker for the exclusivity window
+                let now = START / 1_000_000_000 + self.now;
+                let exclusive = htlc.taken_until.is_some_and(|until| now < until.0);
+                let depositor_takes = htlc.receiver.as_ref() == Some(&accounts(depositor));
+                if htlc.open_order && (if exclusive { !depositor_takes } else { now >= htlc.timelock.0 }) {
+                    return;
+                }
+                self.context(accounts(depositor), htlc.safety_deposit_amount.0);
+                let deposit_id = self.contract.create_safety_deposit(htlc_id);
+                self.deposited += htlc.safety_deposit_amount.0;
+                self.deposits.push(deposit_id);
+            }
+            Op::Claim { deposit, caller } => {
+                let Some(deposit_id) = pick(&self.deposits, deposit) else { return };
+                // Deposits of swept HTLCs are already paid out
+                let Some(deposit) = self.contract.safety_deposits.get(&deposit_id) else { return };
+                let htlc = self.htlc(&deposit.htlc_id);
+                let caller = accounts(caller);
+                if !is_settled(&htlc) || FusionPlusContract::deposit_recipient(&htlc, &deposit) != caller {
+                    return;
+                }
+                self.context(caller, 0);
+                self.contract.claim_safety_deposit(deposit_id);
+            }
+            Op::Sweep { caller } => {
+                self.context(accounts(caller), 0);
+                self.contract.sweep_expired(None);
+            }
+            Op::Deliver { payout, success } => {
+                let pending = self.in_flight.len() + self.claims.len();
+                if pending == 0 {
+                    return;
+                }
+                let index = payout.index(pending);
+                if index < self.in_flight.len() {
+                    let payout = self.in_flight.remove(index);
+                    self.deliver(payout, success);
+                } else {
+                    let deposit = self.claims.remove(index - self.in_flight.len());
+                    self.deliver_claim(deposit, success);
+                }
+                return;
+            }
+            Op::Advance { seconds } => {
+                self.now += seconds;
+                return;
+            }
+        }
+        self.record_events(&before, &held);
+    }
+
+    // Turns the events of the last call into payouts in flight and
+    // transfers that left right away
+    fn record_events(&mut self, before: &HashMap<String, HTLCStatus>, held: &HashMap<String, SafetyDeposit>) {
+        for log in get_logs() {
+            let Some(json) = log.strip_prefix("EVENT_JSON:") else { continue };
+            let event: serde_json::Value = serde_json::from_str(json).unwrap();
+            let data = &event["data"][0];
+            let amount = |key: &str| data[key].as_str().map_or(0, |value| value.parse::<u128>().unwrap());
+            let fill_id = || data["fill_id"].as_str().unwrap().to_string();
+
+            let kind = match event["event"].as_str().unwrap() {
+                "htlc_withdrawn" => PayoutKind::Withdraw,
+                // A fully filled order settles without a payout
+                "htlc_refunded" if amount("amount") == 0 => continue,
+                "htlc_refunded" => PayoutKind::Refund,
+                "order_cancelled" => PayoutKind::Cancel,
+                "fill_withdrawn" => PayoutKind::FillWithdraw { fill_id: fill_id() },
+                "fill_refunded" => PayoutKind::FillRefund { fill_id: fill_id() },
+                "deposit_claimed" => {
+                    self.claims.push(held[data["deposit_id"].as_str().unwrap()].clone());
+                    continue;
+                }
+                _ => continue,
+            };
+            let htlc_id = data["htlc_id"].as_str().unwrap().to_string();
+            self.in_flight.push(PendingPayout {
+                previous_status: before[&htlc_id].clone(),
+                htlc_id,
+                kind,
+                amount: amount("amount"),
+            });
+        }
+    }
+
+    fn deliver(&mut self, payout: PendingPayout, success: bool) {
+        let result = if success { PromiseResult::Successful(vec![]) } else { PromiseResult::Failed };
+        testing_env!(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .block_timestamp(START + self.now * 1_000_000_000)
+                .build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+        let PendingPayout { htlc_id, kind, previous_status, amount } = payout;
+        let key = kind.fill_id().map_or_else(|| htlc_id.clone(), str::to_string);
+        if !success && matches!(kind, PayoutKind::Cancel) {
+            self.cancelled.remove(&htlc_id);
+        }
+        self.contract.resolve_payout(htlc_id, kind, previous_status, U128(0));
+
+        if success {
+            self.paid += amount;
+            let count = self.payouts.entry(key.clone()).or_default();
+            *count += 1;
+            assert!(*count <= 1, "{} paid out twice", key);
+        }
+    }
+
+    fn deliver_claim(&mut self, deposit: SafetyDeposit, success: bool) {
+        let result = if success { PromiseResult::Successful(vec![]) } else { PromiseResult::Failed };
+        testing_env!(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .block_timestamp(START + self.now * 1_000_000_000)
+                .build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+        let amount = deposit.amount.0;
+        self.contract.resolve_deposit_payout(deposit);
+        if success {
+            self.paid += amount;
+        }
+    }
+
+    fn check_invariants(&self) {
+        let mut escrow = 0;
+        let mut open = 0;
+        for htlc_id in &self.htlcs {
+            let htlc = self.htlc(htlc_id);
+            let fills = self.contract.get_partial_fills(htlc_id.clone());
+            let pending: Vec<&PartialFill> = fills.iter().filter(|fill| fill.status == FillStatus::Pending).collect();
+
+            if is_open(&htlc) {
+                escrow += htlc.remaining_amount.0;
+                open += 1;
+            }
+            escrow += pending.iter().map(|fill| fill.amount.0).sum::<u128>();
+
+            let expected = if self.cancelled.contains(htlc_id) { 0 } else { htlc.total_amount.0 };
+            assert_eq!(
+                htlc.remaining_amount.0 + fills.iter().map(|fill| fill.amount.0).sum::<u128>(),
+                expected,
+                "{}: remaining plus fills",
+                htlc_id
+            );
+            assert_eq!(htlc.pending_fills as usize, pending.len(), "{}: pending fills", htlc_id);
+            assert_eq!(
+                self.contract.active_htlcs.contains_key(&htlc_number(htlc_id)),
+                is_open(&htlc) || htlc.pending_fills > 0,
+                "{}: active index",
+                htlc_id
+            );
+        }
+        escrow += self.contract.safety_deposits.values().map(|deposit| deposit.amount.0).sum::<u128>();
+        let in_flight: u128 = self.in_flight.iter().map(|payout| payout.amount).sum::<u128>()
+            + self.claims.iter().map(|deposit| deposit.amount.0).sum::<u128>();
+
+        assert_eq!(self.deposited - self.paid, escrow + in_flight, "contract-held funds");
+        let stats = self.contract.get_token_stats(None);
+        assert_eq!(stats.active_htlcs, open, "active HTLC count");
+        assert_eq!(stats.htlcs_created, self.htlcs.len() as u64);
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_random_operations_keep_invariants(ops in prop::collection::vec(op(), 1..40)) {
+        let mut harness = Harness::new();
+        for op in ops {
+            harness.apply(op);
+            harness.check_invariants();
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, require, AccountId, NearToken, Promise, StorageUsage};
+
+use super::*;
+
+// Bytes taken by an account's own `storage_accounts` entry
+const ACCOUNT_STORAGE_BYTES: StorageUsage = 200;
+
+// NEP-145 balance of an account; `locked` covers the storage its HTLCs,
+// fills and deposits occupy until they are pruned or claimed
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct StorageAccount {
+    pub total: u128,
+    pub locked: u128,
+}
+
+impl StorageAccount {
+    fn to_balance(&self) -> StorageBalance {
+        StorageBalance {
+            total: NearToken::from_yoctonear(self.total),
+            available: NearToken::from_yoctonear(self.total - self.locked),
+        }
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for FusionPlusContract {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let amount = env::attached_deposit().as_yoctonear();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let min_balance = self.storage_balance_bounds().min.as_yoctonear();
+
+        let account = match self.storage_accounts.get(&account_id) {
+            Some(mut account) => {
+                if registration_only == Some(true) {
+                    // Already registered, return the whole deposit
+                    if amount > 0 {
+                        Promise::new(env::predecessor_account_id())
+                            .transfer(NearToken::from_yoctonear(amount));
+                    }
+                    return account.to_balance();
+                }
+                account.total += amount;
+                self.storage_available += amount;
+                account
+            }
+            None => {
+                require!(amount >= min_balance, "Deposit below the minimum storage balance");
+                let total = if registration_only == Some(true) {
+                    if amount > min_balance {
+                        Promise::new(env::predecessor_account_id())
+                            .transfer(NearToken::from_yoctonear(amount - min_balance));
+                    }
+                    min_balance
+                } else {
+                    amount
+                };
+                self.storage_available += total - min_balance;
+                StorageAccount { total, locked: min_balance }
+            }
+        };
+
+        self.storage_accounts.insert(&account_id, &account);
+        account.to_balance()
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account = self.storage_accounts.get(&account_id).expect("Account not registered");
+
+        let available = account.total - account.locked;
+        let amount = amount.map(|a| a.as_yoctonear()).unwrap_or(available);
+        require!(amount <= available, "Amount exceeds available storage balance");
+
+        account.total -= amount;
+        self.storage_accounts.insert(&account_id, &account);
+        self.storage_available = self.storage_available.saturating_sub(amount);
+        if amount > 0 {
+            Promise::new(account_id).transfer(NearToken::from_yoctonear(amount));
+        }
+        account.to_balance()
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        require!(force != Some(true), "Force unregister is not supported");
+        let account_id = env::predecessor_account_id();
+        let min_balance = self.storage_balance_bounds().min.as_yoctonear();
+
+        match self.storage_accounts.get(&account_id) {
+            Some(account) => {
+                require!(account.locked <= min_balance, "Account still has HTLCs in storage");
+                self.storage_accounts.remove(&account_id);
+                self.storage_available = self.storage_available.saturating_sub(account.total - account.locked);
+                Promise::new(account_id).transfer(NearToken::from_yoctonear(account.total));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: env::storage_byte_cost().saturating_mul(ACCOUNT_STORAGE_BYTES as u128),
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts.get(&account_id).map(|account| account.to_balance())
+    }
+}
+
+impl FusionPlusContract {
+    // Locks the cost of everything written since `initial_storage` against
+    // `account_id`'s storage balance and returns the bytes charged
+    pub(crate) fn charge_storage(
+        &mut self,
+        account_id: &AccountId,
+        initial_storage: StorageUsage,
+    ) -> StorageUsage {
+        let bytes = env::storage_usage().saturating_sub(initial_storage);
+        let cost = env::storage_byte_cost().as_yoctonear() * bytes as u128;
+
+        let mut account = self.storage_accounts.get(account_id).expect("Account not registered for storage");
+        require!(account.total - account.locked >= cost, "Insufficient storage balance");
+        account.locked += cost;
+        self.storage_accounts.insert(account_id, &account);
+        self.storage_available = self.storage_available.saturating_sub(cost);
+        bytes
+    }
+
+    // Whether `account_id` has the balance to lock `bytes` more of storage
+    pub(crate) fn can_cover_storage(&self, account_id: &AccountId, bytes: StorageUsage) -> bool {
+        let cost = env::storage_byte_cost().as_yoctonear() * bytes as u128;
+        self.storage_accounts.get(account_id).is_some_and(|account| account.total - account.locked >= cost)
+    }
+
+    pub(crate) fn release_storage(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        if bytes == 0 {
+            return;
+        }
+        if let Some(mut account) = self.storage_accounts.get(account_id) {
+            let cost = env::storage_byte_cost().as_yoctonear() * bytes as u128;
+            let released = cost.min(account.locked);
+            account.locked -= released;
+            self.storage_accounts.insert(account_id, &account);
+            self.storage_available += released;
+        }
+    }
+
+    // Settles the locked cost of `bytes` that stay in storage for good out
+    // of `account_id`'s balance; the contract keeps it to pay for them
+    pub(crate) fn consume_storage(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        if let Some(mut account) = self.storage_accounts.get(account_id) {
+            let cost = (env::storage_byte_cost().as_yoctonear() * bytes as u128).min(account.locked);
+            account.locked -= cost;
+            account.total -= cost;
+            self.storage_accounts.insert(account_id, &account);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+
+    use crate::fusion_plus::tests::register_storage;
+
+    fn available(contract: &FusionPlusContract, account_id: AccountId) -> u128 {
+        contract.storage_balance_of(account_id).unwrap().available.as_yoctonear()
+    }
+
+    #[test]
+    fn test_create_htlc_charges_and_pruning_releases_storage() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        let before = available(&contract, accounts(1));
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode([1u8; 32]),
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+
+        let storage_bytes = contract.get_htlc(htlc_id.clone()).unwrap().storage_bytes;
+        assert!(storage_bytes > 0);
+        assert_eq!(
+            available(&contract, accounts(1)),
+            before - env::storage_byte_cost().as_yoctonear() * storage_bytes as u128
+        );
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(1_000_000_000_000_000_000 + 7_200_000_000_000)
+            .build());
+        contract.refund(htlc_id.clone());
+
+        // Still locked once the refund transfer went through
+        testing_env!(
+            VMContextBuilder::new().current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.resolve_payout(htlc_id.clone(), PayoutKind::Refund, HTLCStatus::Active, U128(0));
+        assert!(available(&contract, accounts(1)) < before);
+
+        // Released when the settled HTLC is pruned, but for the entry that
+        // keeps its hashlock taken
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(1_000_000_000_000_000_000 + (7200 + DEFAULT_RETENTION_SECONDS) * 1_000_000_000)
+            .build());
+        let hashlock_bytes = FusionPlusContract::hashlock_entry_bytes(&contract.get_htlc(htlc_id.clone()).unwrap());
+        contract.prune_htlcs(vec![htlc_id]);
+        assert_eq!(
+            available(&contract, accounts(1)),
+            before - env::storage_byte_cost().as_yoctonear() * hashlock_bytes as u128
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Account not registered for storage")]
+    fn test_create_htlc_requires_storage_registration() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        let mut contract = FusionPlusContract::new();
+        contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode([1u8; 32]),
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+    }
+}
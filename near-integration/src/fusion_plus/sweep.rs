@@ -0,0 +1,289 @@
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, AccountId, Gas};
+
+use super::*;
+
+const MAX_SWEEP_PAYOUTS: u32 = 20;
+const MAX_SWEEP_SCAN: usize = 100; // Active HTLCs looked at per call
+// A payout with its `resolve_payout` or `resolve_deposit_payout` callback
+const GAS_PER_SWEEP_PAYOUT: Gas = Gas::from_tgas(15);
+
+#[near_bindgen]
+impl FusionPlusContract {
+    // Refunds expired HTLCs and their pending fills to the maker, whoever
+    // calls. Walks `active_htlcs` from where the last sweep stopped, wrapping
+    // around at the end, issues at
+    // most `limit` payouts and stops earlier when gas runs low, so keepers
+    // call it again until it returns 0. Only HTLCs open to public
+    // cancellation are swept, and single-timelock ones once they expired.
+    // The keeper settles every HTLC it refunds, so the deposits the taker
+    // forfeited go to the keeper and the others back to their depositors.
+    pub fn sweep_expired(&mut self, limit: Option<u32>) -> u32 {
+        let keeper = env::predecessor_account_id();
+        let limit = limit.unwrap_or(MAX_SWEEP_PAYOUTS).min(MAX_SWEEP_PAYOUTS);
+        let mut payouts = 0;
+        let cursor = self.sweep_cursor;
+        let numbers: Vec<u64> = self
+            .active_htlcs
+            .iter_from(cursor)
+            .chain(self.active_htlcs.iter().take_while(|(number, ())| *number <= cursor))
+            .map(|(number, ())| number)
+            .take(MAX_SWEEP_SCAN)
+            .collect();
+
+        for number in numbers {
+            if payouts == limit || !Self::has_gas_for_payout() {
+                break;
+            }
+            payouts += self.sweep_htlc(&htlc_id(number), &keeper, limit - payouts);
+            // An HTLC the budget cut short is looked at again first
+            if payouts == limit && self.active_htlcs.contains_key(&number) {
+                break;
+            }
+            self.sweep_cursor = number;
+        }
+        payouts
+    }
+}
+
+impl FusionPlusContract {
+    // Refunds one HTLC if anyone may cancel it, pending fills first. Returns
+    // the payouts issued; an HTLC left half done is finished by a later sweep.
+    fn sweep_htlc(&mut self, htlc_id: &String, keeper: &AccountId, budget: u32) -> u32 {
+        let mut htlc = self.htlcs.get(htlc_id).expect("HTLC not found");
+        if !Self::is_sweepable(&htlc) {
+            return 0;
+        }
+
+        let mut payouts = 0;
+        let fill_ids = self.htlc_fills.get(htlc_id).map(|fill_ids| fill_ids.to_vec()).unwrap_or_default();
+        for fill_id in fill_ids {
+            if htlc.pending_fills == 0 {
+                break;
+            }
+            if payouts == budget || !Self::has_gas_for_payout() {
+                return payouts;
+            }
+            let fill = self.get_fill(htlc_id, &fill_id);
+            if fill.status == FillStatus::Pending {
+                self.settle_fill_refund(&mut htlc, fill);
+                payouts += 1;
+            }
+        }
+        // A refunded order stays indexed only for its pending fills
+        if htlc.status == HTLCStatus::Refunded || payouts == budget || !Self::has_gas_for_payout() {
+            return payouts;
+        }
+
+        let (htlc, refund_amount, previous_status) = self.settle_refund(htlc, keeper.clone());
+        if refund_amount == 0 {
+            // Every part went to fillers; a zero `ft_transfer` would fail
+            self.slash_for_lapse(htlc_id, None);
+        } else {
+            let sender = htlc.sender.clone();
+            self.internal_payout(&htlc, sender, refund_amount, 0, PayoutKind::Refund, previous_status);
+            payouts += 1;
+        }
+        self.pay_out_deposits(&htlc, keeper);
+        payouts
+    }
+
+    // Anyone may cancel in public cancellation; a single-timelock HTLC
+    // never gets there, so it is swept as soon as it expired
+    fn is_sweepable(htlc: &FusionHTLC) -> bool {
+        match Self::current_stage(htlc) {
+            Stage::PublicCancellation => true,
+            Stage::PrivateCancellation => !htlc.timelocks.has_public_cancellation(),
+            _ => false,
+        }
+    }
+
+    // Pays out the safety deposits of a swept HTLC the way
+    // `claim_safety_deposit` would, so the keeper only gets what the taker
+    // forfeited. Deposits left once gas runs low, and those made before the
+    // deposit index existed, stay claimable.
+    fn pay_out_deposits(&mut self, htlc: &FusionHTLC, keeper: &AccountId) {
+        let deposit_ids = self.htlc_deposits.get(&htlc.id).map(|ids| ids.to_vec()).unwrap_or_default();
+        let mut reward = 0;
+        for deposit_id in deposit_ids {
+            if !Self::has_gas_for_payout() {
+                break;
+            }
+            let Some(deposit) = self.safety_deposits.get(&deposit_id) else {
+                continue;
+            };
+            if Self::deposit_recipient(htlc, &deposit) != deposit.depositor {
+                reward += deposit.amount.0;
+            }
+            self.internal_deposit_payout(htlc, deposit);
+        }
+        events::HtlcSwept { htlc_id: htlc.id.clone(), keeper: keeper.clone(), reward: U128(reward) }.emit();
+    }
+
+    fn has_gas_for_payout() -> bool {
+        env::prepaid_gas().as_gas().saturating_sub(env::used_gas().as_gas()) >= GAS_PER_SWEEP_PAYOUT.as_gas()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseOrValue};
+    use sha2::{Digest, Sha256};
+
+    use crate::fusion_plus::tests::register_storage;
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn as_account(account_id: AccountId, seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(account_id)
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(START + seconds * 1_000_000_000)
+            .build());
+    }
+
+    fn htlc(contract: &mut FusionPlusContract, seed: u8, timelock_seconds: u64) -> String {
+        as_account(accounts(1), 0);
+        let hashlock = hex::encode(Sha256::digest([seed; 32]));
+        contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock,
+            timelock_seconds,
+            require_safety_deposit: true,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_sweep_refunds_expired_htlcs_and_rewards_keeper() {
+        as_account(accounts(0), 0);
+        let mut contract = FusionPlusContract::new();
+        for account_id in [accounts(1), accounts(2), accounts(3)] {
+            register_storage(&mut contract, account_id);
+        }
+        let expired = htlc(&mut contract, 1, 3600);
+        let live = htlc(&mut contract, 2, 86400);
+
+        // The taker and another resolver post deposits
+        as_account(accounts(2), 0);
+        let taker_deposit = contract.create_safety_deposit(expired.clone());
+        as_account(accounts(3), 0);
+        let resolver_deposit = contract.create_safety_deposit(expired.clone());
+        let amount = contract.safety_deposits.get(&taker_deposit).unwrap().amount;
+
+        // A single-timelock HTLC is swept as soon as it expired
+        as_account(accounts(5), 7200);
+        assert_eq!(contract.sweep_expired(None), 1);
+
+        let swept = contract.get_htlc(expired.clone()).unwrap();
+        assert_eq!(swept.status, HTLCStatus::Refunded);
+        assert_eq!(swept.settled_by, Some(accounts(5)));
+        assert_eq!(contract.get_htlc(live).unwrap().status, HTLCStatus::Active);
+        assert_eq!(contract.safety_deposits.len(), 0);
+
+        // The keeper only gets the deposit the taker forfeited
+        let logs = get_logs();
+        for (deposit_id, recipient) in [(taker_deposit, accounts(5)), (resolver_deposit, accounts(3))] {
+            assert!(logs.iter().any(|log| log.contains(&format!(
+                r#""deposit_id":"{}","htlc_id":"{}","claimed_by":"{}""#,
+                deposit_id, expired, recipient
+            ))));
+        }
+        assert!(logs.iter().any(|log| log.contains(&format!(
+            r#""event":"htlc_swept","data":[{{"htlc_id":"{}","keeper":"{}","reward":"{}"}}]"#,
+            expired,
+            accounts(5),
+            amount.0
+        ))));
+
+        // Nothing left to do
+        assert_eq!(contract.sweep_expired(None), 0);
+    }
+
+    #[test]
+    fn test_sweep_waits_for_public_cancellation() {
+        as_account(accounts(0), 0);
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        as_account(accounts(1), 0);
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode(Sha256::digest([1u8; 32])),
+            stage_durations: Some(StageDurations {
+                finality_delay: 0,
+                taker_exclusive_duration: 1200,
+                private_resolver_duration: 1200,
+                public_resolver_duration: 1200,
+                private_cancellation_duration: 1200,
+            }),
+            ..Default::default()
+        });
+
+        // Only the maker and resolvers may cancel yet
+        as_account(accounts(5), 4000);
+        assert_eq!(contract.sweep_expired(None), 0);
+        assert_eq!(contract.get_htlc(htlc_id.clone()).unwrap().status, HTLCStatus::Active);
+
+        as_account(accounts(5), 4800);
+        assert_eq!(contract.sweep_expired(None), 1);
+        assert_eq!(contract.get_htlc(htlc_id).unwrap().status, HTLCStatus::Refunded);
+    }
+
+    #[test]
+    fn test_sweep_is_bounded_and_resumes() {
+        as_account(accounts(0), 0);
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        for seed in 0..3 {
+            htlc(&mut contract, seed, 3600);
+        }
+
+        as_account(accounts(5), 7200);
+        assert_eq!(contract.sweep_expired(Some(2)), 2);
+        assert_eq!(contract.get_active_htlcs(None, None).htlcs.len(), 1);
+        assert_eq!(contract.sweep_expired(Some(2)), 1);
+        assert!(contract.get_active_htlcs(None, None).htlcs.is_empty());
+    }
+
+    #[test]
+    fn test_refunded_order_stays_indexed_for_pending_fills() {
+        as_account(accounts(0), 0);
+        let mut contract = FusionPlusContract::new();
+        register_storage(&mut contract, accounts(1));
+        register_storage(&mut contract, accounts(3));
+
+        let secrets: Vec<[u8; 32]> = (1..=2).map(|i| [i; 32]).collect();
+        let leaves: Vec<merkle::Hash> =
+            secrets.iter().enumerate().map(|(i, s)| merkle::leaf(HashAlgorithm::Sha256, i as u32, &Sha256::digest(s))).collect();
+        as_account(accounts(1), 0);
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode(merkle::root(HashAlgorithm::Sha256, &leaves)),
+            timelock_seconds: 3600,
+            allow_partial_fills: true,
+            parts_count: Some(1),
+            ..Default::default()
+        });
+
+        // One fill takes the whole order and is never withdrawn
+        as_account(accounts(3), 60);
+        let proof = merkle::proof(HashAlgorithm::Sha256, &leaves, 1).iter().map(hex::encode).collect();
+        let secret_hash = hex::encode(Sha256::digest(secrets[1]));
+        let amount = U128(NearToken::from_near(1).as_yoctonear());
+        let fill_id = contract.create_partial_fill(htlc_id.clone(), amount, 1, secret_hash, proof);
+
+        // Nothing is left to send the maker, so no transfer is made
+        as_account(accounts(1), 7200);
+        assert!(matches!(contract.refund(htlc_id.clone()), PromiseOrValue::Value(true)));
+        assert_eq!(contract.get_htlc(htlc_id.clone()).unwrap().status, HTLCStatus::Refunded);
+        assert!(contract.active_htlcs.contains_key(&htlc_number(&htlc_id)));
+
+        as_account(accounts(5), 7200);
+        assert_eq!(contract.sweep_expired(None), 1);
+        assert_eq!(contract.get_fill(&htlc_id, &fill_id).status, FillStatus::Refunded);
+        assert!(!contract.active_htlcs.contains_key(&htlc_number(&htlc_id)));
+    }
+}
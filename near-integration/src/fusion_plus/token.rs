@@ -0,0 +1,129 @@
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, require, AccountId, PromiseOrValue};
+
+use super::*;
+
+// `msg` accepted by `ft_on_transfer`, e.g.
+// {"action":"create_htlc","receiver":"bob.near","hashlock":"..","timelock_seconds":3600}
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "action", rename_all = "snake_case")]
+pub enum FtMessage {
+    CreateHtlc(CreateHTLCArgs),
+    Stake, // Resolver stake, when the registry stakes in this token
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for FusionPlusContract {
+    // Escrow NEP-141 tokens sent with `ft_transfer_call`. Any panic here makes
+    // the token contract refund the full amount to the sender.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token_id = env::predecessor_account_id();
+        let message: FtMessage = serde_json::from_str(&msg).expect("Invalid message");
+        self.credit_tokens(Some(&token_id), amount.0);
+
+        match message {
+            FtMessage::CreateHtlc(args) => {
+                self.internal_create_htlc(sender_id, Some(token_id), amount.0, args);
+            }
+            FtMessage::Stake => {
+                require!(self.resolver_config.stake_token == Some(token_id), "Wrong stake token");
+                self.internal_stake(sender_id, amount.0);
+            }
+        }
+
+        // All tokens were used
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+    use sha2::{Digest, Sha256};
+
+    use crate::fusion_plus::tests::register_storage;
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn token_htlc(contract: &mut FusionPlusContract, hashlock: &str) -> String {
+        register_storage(contract, accounts(1));
+
+        // The token contract (accounts(4)) forwards the sender's deposit
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(4))
+            .block_timestamp(START)
+            .build());
+
+        let msg = format!(
+            r#"{{"action":"create_htlc","receiver":"{}","hashlock":"{}","timelock_seconds":3600}}"#,
+            accounts(2),
+            hashlock
+        );
+        match contract.ft_on_transfer(accounts(1), U128(1_000_000), msg) {
+            PromiseOrValue::Value(unused) => assert_eq!(unused.0, 0),
+            PromiseOrValue::Promise(_) => panic!("Expected a value"),
+        }
+        format!("htlc_{}", contract.next_htlc_id - 1)
+    }
+
+    #[test]
+    fn test_ft_on_transfer_creates_token_htlc() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+
+        let htlc_id = token_htlc(&mut contract, &hex::encode([7u8; 32]));
+
+        let htlc = contract.get_htlc(htlc_id).unwrap();
+        assert_eq!(htlc.sender, accounts(1));
+        assert_eq!(htlc.token_id, Some(accounts(4)));
+        assert_eq!(htlc.total_amount.0, 1_000_000);
+    }
+
+    #[test]
+    fn test_failed_token_withdraw_is_rolled_back() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+
+        let secret = "token_secret";
+        let mut hasher = Sha256::new();
+        hasher.update(secret.as_bytes());
+        let htlc_id = token_htlc(&mut contract, &hex::encode(hasher.finalize()));
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(START)
+            .build());
+        contract.withdraw(htlc_id.clone(), hex::encode(secret));
+        assert_eq!(contract.get_htlc(htlc_id.clone()).unwrap().status, HTLCStatus::Completed);
+
+        // ft_transfer failed, e.g. the receiver is not registered with the token
+        testing_env!(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .block_timestamp(START)
+                .build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        assert!(!contract.resolve_payout(htlc_id.clone(), PayoutKind::Withdraw, HTLCStatus::Active, U128(0)));
+
+        let htlc = contract.get_htlc(htlc_id.clone()).unwrap();
+        assert_eq!(htlc.status, HTLCStatus::Active);
+        assert!(htlc.secret.is_none());
+        assert!(contract.can_withdraw(htlc_id));
+    }
+}
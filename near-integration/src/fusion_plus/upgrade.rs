@@ -0,0 +1,513 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::Vector;
+use near_sdk::{env, near_bindgen, require, Gas, NearToken, Promise};
+
+use super::*;
+
+const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+const V0_RECORDS_KEY: &[u8] = b"V0_RECORDS";
+const CURRENT_STATE_VERSION: u8 = 1;
+const GAS_FOR_MIGRATE: Gas = Gas::from_tgas(100);
+const MAX_MIGRATE: u32 = 50; // Records per call
+const GAS_PER_MIGRATED_RECORD: Gas = Gas::from_tgas(10);
+
+// Every Borsh layout `STATE` has been stored in. The first release kept no
+// version, so a missing `STATE_VERSION` means V0; later layouts are picked
+// by the version byte stored there.
+pub enum VersionedContract {
+    V0(Box<FusionPlusContractV0>),
+    V1(Box<FusionPlusContract>),
+}
+
+// State of the first release, before ownership, stages, NEP-141 deposits,
+// storage staking, resolvers and every index but the user and active lists
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FusionPlusContractV0 {
+    htlcs: UnorderedMap<String, FusionHTLCV0>,
+    partial_fills: LookupMap<String, Vector<PartialFillV0>>, // htlc_id -> fills
+    safety_deposits: UnorderedMap<String, SafetyDepositV0>,
+    secret_to_htlc: LookupMap<String, String>,
+    user_htlcs: LookupMap<AccountId, Vector<String>>,
+    active_htlcs: Vector<String>,
+    next_htlc_id: u64,
+    next_fill_id: u64,
+    next_deposit_id: u64,
+    total_volume: U128,
+    total_htlcs_created: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FusionHTLCV0 {
+    id: String,
+    sender: AccountId,
+    receiver: AccountId,
+    token_id: Option<AccountId>,
+    total_amount: U128,
+    remaining_amount: U128,
+    hashlock: String,
+    timelock: U64,
+    secret: Option<String>,
+    allow_partial_fills: bool,
+    min_fill_amount: U128,
+    safety_deposit_amount: U128,
+    status: HTLCStatus,
+    created_at: U64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PartialFillV0 {
+    id: String,
+    htlc_id: String,
+    filler: AccountId,
+    amount: U128,
+    status: FillStatus,
+    created_at: U64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct SafetyDepositV0 {
+    id: String,
+    htlc_id: String,
+    depositor: AccountId,
+    amount: U128,
+    created_at: U64,
+}
+
+// V0 HTLCs, with their fills, and safety deposits still to be converted,
+// kept under `V0_RECORDS_KEY` until both maps are empty. Records are taken
+// from the end of each map, so removing one never moves another and the
+// map lengths are where the next call resumes.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct V0Records {
+    htlcs: UnorderedMap<String, FusionHTLCV0>,
+    partial_fills: LookupMap<String, Vector<PartialFillV0>>,
+    safety_deposits: UnorderedMap<String, SafetyDepositV0>,
+}
+
+impl V0Records {
+    fn read() -> Option<Self> {
+        env::storage_read(V0_RECORDS_KEY)
+            .map(|bytes| Self::try_from_slice(&bytes).expect("Cannot deserialize V0 records"))
+    }
+
+    fn write(&self) {
+        env::storage_write(V0_RECORDS_KEY, &borsh::to_vec(self).expect("Cannot serialize V0 records"));
+    }
+
+    fn left(&self) -> u64 {
+        self.htlcs.len() + self.safety_deposits.len()
+    }
+}
+
+fn last_key<V: BorshSerialize + BorshDeserialize>(map: &UnorderedMap<String, V>) -> Option<String> {
+    map.len().checked_sub(1).and_then(|index| map.keys_as_vector().get(index))
+}
+
+impl VersionedContract {
+    fn read() -> Self {
+        match env::storage_read(STATE_VERSION_KEY).map(|bytes| bytes[0]) {
+            None => VersionedContract::V0(Box::new(env::state_read().expect("No contract state"))),
+            Some(1) => VersionedContract::V1(Box::new(env::state_read().expect("No contract state"))),
+            Some(_) => env::panic_str("Unknown state version"),
+        }
+    }
+
+    fn into_current(self) -> FusionPlusContract {
+        match self {
+            VersionedContract::V0(old) => {
+                let old = *old;
+                // Only the counters carry over here. V0 had no owner, and
+                // `migrate` runs as the contract account, which becomes it;
+                // `secret_to_htlc` keeps its prefix and layout.
+                let mut contract = FusionPlusContract::new();
+                contract.next_htlc_id = old.next_htlc_id;
+                contract.next_fill_id = old.next_fill_id;
+                contract.next_deposit_id = old.next_deposit_id;
+                // V0 escrowed NEAR only
+                contract.update_token_stats(&None, |stats| {
+                    stats.htlcs_created = old.total_htlcs_created;
+                    stats.volume = old.total_volume;
+                });
+                // The records move to the V1 prefixes through
+                // `migrate_records`; V0's user and active lists are rebuilt
+                // from them and left unused
+                V0Records {
+                    htlcs: old.htlcs,
+                    partial_fills: old.partial_fills,
+                    safety_deposits: old.safety_deposits,
+                }
+                .write();
+                contract
+            }
+            VersionedContract::V1(contract) => *contract,
+        }
+    }
+}
+
+#[near_bindgen]
+impl FusionPlusContract {
+    // Deploys the wasm passed as raw input and migrates the state in the same
+    // batch, so a failed migration reverts the deploy as well
+    pub fn upgrade(&mut self) -> Promise {
+        self.assert_owner();
+        require!(env::storage_read(V0_RECORDS_KEY).is_none(), "Records still to migrate");
+        let code = env::input().expect("Missing contract code");
+
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".to_string(), Vec::new(), NearToken::from_yoctonear(0), GAS_FOR_MIGRATE)
+    }
+
+    // Converts the root state only, so it fits in one call whatever the
+    // number of HTLCs; V0 records follow through `migrate_records`
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let contract = VersionedContract::read().into_current();
+        Self::write_state_version();
+        contract
+    }
+
+    // Converts up to `limit` V0 records to the current layout, HTLCs with
+    // their fills first and then safety deposits, whoever calls. Stops early
+    // when gas runs low and picks up where the last call stopped. A record is
+    // not found by any other method until it is converted. Returns how many
+    // are left.
+    pub fn migrate_records(&mut self, limit: u32) -> U64 {
+        let Some(mut records) = V0Records::read() else {
+            return U64(0);
+        };
+        let mut migrated = 0;
+        while migrated < limit.min(MAX_MIGRATE) && Self::has_gas_for_record() {
+            if let Some(htlc_id) = last_key(&records.htlcs) {
+                let htlc = records.htlcs.remove(&htlc_id).expect("V0 HTLC not found");
+                // Shares its key with the HTLC's V1 fill index, so it goes
+                // before that is written
+                let fills = records
+                    .partial_fills
+                    .remove(&htlc_id)
+                    .map(|mut fills| {
+                        let list = fills.to_vec();
+                        fills.clear();
+                        list
+                    })
+                    .unwrap_or_default();
+                self.migrate_htlc(htlc, fills);
+            } else if let Some(deposit_id) = last_key(&records.safety_deposits) {
+                let deposit = records.safety_deposits.remove(&deposit_id).expect("V0 deposit not found");
+                self.migrate_deposit(deposit);
+            } else {
+                break;
+            }
+            migrated += 1;
+        }
+
+        let left = records.left();
+        if left == 0 {
+            env::storage_remove(V0_RECORDS_KEY);
+        } else {
+            records.write();
+        }
+        U64(left)
+    }
+
+    pub fn get_records_to_migrate(&self) -> U64 {
+        U64(V0Records::read().map_or(0, |records| records.left()))
+    }
+
+    pub fn get_state_version(&self) -> u8 {
+        env::storage_read(STATE_VERSION_KEY).map_or(0, |bytes| bytes[0])
+    }
+}
+
+impl FusionPlusContract {
+    pub(crate) fn write_state_version() {
+        env::storage_write(STATE_VERSION_KEY, &[CURRENT_STATE_VERSION]);
+    }
+
+    fn has_gas_for_record() -> bool {
+        env::prepaid_gas().as_gas().saturating_sub(env::used_gas().as_gas()) >= GAS_PER_MIGRATED_RECORD.as_gas()
+    }
+
+    // V0 HTLCs had a single timelock and one secret, which their fills
+    // shared. They become single-timelock HTLCs whose fills all carry the
+    // hashlock as their secret hash; new fills need a Merkle root, so a V0
+    // order takes none. Nothing was staked for their storage.
+    fn migrate_htlc(&mut self, old: FusionHTLCV0, fills: Vec<PartialFillV0>) {
+        let timelocks =
+            Timelocks::new(old.created_at.0, &StageDurations::from_timelock(old.timelock.0 - old.created_at.0));
+        let settled_by = match old.status {
+            HTLCStatus::Completed => Some(old.receiver.clone()),
+            HTLCStatus::Refunded => Some(old.sender.clone()),
+            _ => None,
+        };
+        let mut htlc = FusionHTLC {
+            id: old.id,
+            sender: old.sender,
+            receiver: Some(old.receiver),
+            open_order: false,
+            taken_until: None,
+            token_id: old.token_id,
+            total_amount: old.total_amount,
+            remaining_amount: old.remaining_amount,
+            hashlock: old.hashlock,
+            hash_algorithm: HashAlgorithm::Sha256,
+            timelock: timelocks.cancellation_start,
+            timelocks,
+            secret: old.secret,
+            allow_partial_fills: old.allow_partial_fills,
+            parts_count: 1,
+            min_fill_amount: old.min_fill_amount,
+            safety_deposit_amount: old.safety_deposit_amount,
+            pending_fills: 0,
+            resolvers: Vec::new(),
+            settled_by,
+            taker_lapsed: false,
+            status: old.status,
+            created_at: old.created_at,
+            settled_at: None,
+            storage_bytes: 0,
+        };
+
+        let mut pending = 0;
+        let mut withdrawn = 0;
+        if htlc.allow_partial_fills {
+            let mut fill_ids =
+                UnorderedSet::new(StorageKey::PartialFills { htlc_id_hash: env::sha256(htlc.id.as_bytes()) });
+            for old_fill in fills {
+                let fill = PartialFill {
+                    secret: (old_fill.status == FillStatus::Completed).then(|| htlc.secret.clone()).flatten(),
+                    id: old_fill.id,
+                    htlc_id: old_fill.htlc_id,
+                    filler: old_fill.filler,
+                    amount: old_fill.amount,
+                    secret_index: 0,
+                    secret_hash: htlc.hashlock.clone(),
+                    status: old_fill.status,
+                    created_at: old_fill.created_at,
+                    storage_bytes: 0,
+                };
+                match fill.status {
+                    FillStatus::Pending => {
+                        htlc.pending_fills += 1;
+                        pending += fill.amount.0;
+                    }
+                    FillStatus::Completed => withdrawn += fill.amount.0,
+                    FillStatus::Refunded => {}
+                }
+                self.partial_fills.insert(&fill.id, &fill);
+                fill_ids.insert(&fill.id);
+                Self::index_htlc(
+                    &mut self.filler_htlcs,
+                    |account_hash| StorageKey::FillerHTLCs { account_hash },
+                    &fill.filler,
+                    &htlc.id,
+                );
+            }
+            self.htlc_fills.insert(&htlc.id, &fill_ids);
+        } else if htlc.status == HTLCStatus::Completed {
+            withdrawn = htlc.total_amount.0;
+        }
+
+        let open = matches!(htlc.status, HTLCStatus::Active | HTLCStatus::PartiallyFilled);
+        if open || htlc.pending_fills > 0 {
+            self.active_htlcs.insert(&htlc_number(&htlc.id), &());
+        }
+        self.add_user_htlc(&htlc.sender, &htlc.id);
+        if let Some(receiver) = htlc.receiver.clone() {
+            self.add_user_htlc(&receiver, &htlc.id);
+        }
+
+        // The ledger has to start out holding what the contract still owes
+        // in each token; V0 had no resolver stakes to add
+        let escrowed = if open { Self::refund_amount(&htlc) } else { 0 };
+        self.credit_tokens(htlc.token_id.as_ref(), escrowed + pending);
+        let refunded = if htlc.status == HTLCStatus::Refunded { Self::refund_amount(&htlc) } else { 0 };
+        self.update_token_stats(&htlc.token_id, |stats| {
+            match htlc.status {
+                HTLCStatus::Active | HTLCStatus::PartiallyFilled => stats.active_htlcs += 1,
+                HTLCStatus::Completed => stats.completed_htlcs += 1,
+                HTLCStatus::Refunded => stats.refunded_htlcs += 1,
+            }
+            stats.withdrawn_volume = U128(stats.withdrawn_volume.0 + withdrawn);
+            stats.refunded_volume = U128(stats.refunded_volume.0 + refunded);
+        });
+        self.htlcs.insert(&htlc.id, &htlc);
+    }
+
+    // Runs once every HTLC is converted, so the deposit's HTLC is there
+    fn migrate_deposit(&mut self, old: SafetyDepositV0) {
+        let deposit = SafetyDeposit {
+            id: old.id,
+            htlc_id: old.htlc_id,
+            depositor: old.depositor,
+            amount: old.amount,
+            created_at: old.created_at,
+            storage_bytes: 0,
+        };
+        let mut htlc = self.htlcs.get(&deposit.htlc_id).expect("HTLC not found");
+        self.add_safety_deposit(&mut htlc, &deposit);
+        self.htlcs.insert(&htlc.id, &htlc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_migrate_keeps_state() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        let mut contract = FusionPlusContract::new();
+        contract.next_htlc_id = 42;
+        env::state_write(&contract);
+
+        let migrated = FusionPlusContract::migrate();
+        assert_eq!(migrated.next_htlc_id, 42);
+        assert_eq!(migrated.get_owner_id(), accounts(0));
+        assert_eq!(migrated.get_state_version(), CURRENT_STATE_VERSION);
+    }
+
+    fn v0_htlc(number: u64, receiver: AccountId, token_id: Option<AccountId>, allow_partial_fills: bool) -> FusionHTLCV0 {
+        FusionHTLCV0 {
+            id: htlc_id(number),
+            sender: accounts(1),
+            receiver,
+            token_id,
+            total_amount: U128(1000),
+            remaining_amount: U128(if allow_partial_fills { 600 } else { 1000 }),
+            hashlock: hex::encode([number as u8; 32]),
+            timelock: U64(1_000_000_000 + 3600),
+            secret: None,
+            allow_partial_fills,
+            min_fill_amount: U128(100),
+            safety_deposit_amount: U128(50),
+            status: if allow_partial_fills { HTLCStatus::PartiallyFilled } else { HTLCStatus::Active },
+            created_at: U64(1_000_000_000),
+        }
+    }
+
+    // Two open V0 HTLCs, one of them a token order with a pending fill, and
+    // a safety deposit on the other
+    fn write_v0_state() {
+        let mut htlcs = UnorderedMap::new(StorageKey::HTLCs);
+        htlcs.insert(&htlc_id(1), &v0_htlc(1, accounts(2), None, false));
+        htlcs.insert(&htlc_id(2), &v0_htlc(2, accounts(2), Some(accounts(5)), true));
+        let mut partial_fills = LookupMap::new(StorageKey::PartialFills { htlc_id_hash: vec![] });
+        let mut fills = Vector::new(b"fills_htlc_2".to_vec());
+        fills.push(&PartialFillV0 {
+            id: "fill_1".to_string(),
+            htlc_id: htlc_id(2),
+            filler: accounts(3),
+            amount: U128(400),
+            status: FillStatus::Pending,
+            created_at: U64(1_000_000_000),
+        });
+        partial_fills.insert(&htlc_id(2), &fills);
+        let mut safety_deposits = UnorderedMap::new(StorageKey::SafetyDeposits);
+        safety_deposits.insert(
+            &"deposit_1".to_string(),
+            &SafetyDepositV0 {
+                id: "deposit_1".to_string(),
+                htlc_id: htlc_id(1),
+                depositor: accounts(4),
+                amount: U128(50),
+                created_at: U64(1_000_000_000),
+            },
+        );
+        env::state_write(&FusionPlusContractV0 {
+            htlcs,
+            partial_fills,
+            safety_deposits,
+            secret_to_htlc: LookupMap::new(StorageKey::SecretToHTLC),
+            user_htlcs: LookupMap::new(StorageKey::UserHTLCs { user_hash: vec![] }),
+            active_htlcs: Vector::new(StorageKey::ActiveHTLCs),
+            next_htlc_id: 3,
+            next_fill_id: 2,
+            next_deposit_id: 2,
+            total_volume: U128(2000),
+            total_htlcs_created: 2,
+        });
+    }
+
+    #[test]
+    fn test_migrate_from_v0() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        write_v0_state();
+
+        let mut migrated = FusionPlusContract::migrate();
+        assert_eq!(migrated.get_owner_id(), accounts(0));
+        assert_eq!(migrated.next_htlc_id, 3);
+        assert_eq!(migrated.get_token_stats(None).volume, U128(2000));
+        assert_eq!(migrated.get_state_version(), CURRENT_STATE_VERSION);
+        assert_eq!(migrated.get_records_to_migrate(), U64(3));
+        assert!(migrated.get_htlc(htlc_id(1)).is_none());
+
+        assert_eq!(migrated.migrate_records(50), U64(0));
+        assert!(env::storage_read(V0_RECORDS_KEY).is_none());
+
+        let htlc = migrated.get_htlc(htlc_id(1)).unwrap();
+        assert_eq!(htlc.receiver, Some(accounts(2)));
+        assert_eq!(htlc.timelocks.taker_deadline, U64(1_000_000_000 + 3600));
+        assert_eq!(htlc.resolvers, vec![accounts(4)]);
+        assert!(migrated.safety_deposits.get(&"deposit_1".to_string()).is_some());
+        assert_eq!(migrated.get_partial_fills(htlc_id(2))[0].secret_hash, hex::encode([2u8; 32]));
+        assert_eq!(migrated.get_htlc(htlc_id(2)).unwrap().pending_fills, 1);
+
+        let page = migrated.get_user_htlcs(accounts(2), None, None);
+        assert_eq!(page.htlcs.iter().map(|htlc| htlc.id.clone()).collect::<Vec<_>>(), vec![htlc_id(1), htlc_id(2)]);
+        assert_eq!(migrated.get_active_htlcs(None, None).htlcs.len(), 2);
+        assert!(migrated.filler_htlcs.get(&accounts(3)).unwrap().contains_key(&2));
+        assert!(migrated.resolver_htlcs.get(&accounts(4)).unwrap().contains_key(&1));
+        assert_eq!(migrated.get_token_stats(None).active_htlcs, 1);
+
+        // The ledger holds the token order's remainder and its pending fill
+        assert_eq!(migrated.token_ledger.get(&accounts(5)), Some(1000));
+    }
+
+    #[test]
+    fn test_migrate_records_resumes_in_batches() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(1_000_000_000_000_000_000)
+            .build());
+        write_v0_state();
+        let mut migrated = FusionPlusContract::migrate();
+
+        // HTLCs go first, from the end of the map
+        assert_eq!(migrated.migrate_records(1), U64(2));
+        assert!(migrated.get_htlc(htlc_id(2)).is_some());
+        assert!(migrated.get_htlc(htlc_id(1)).is_none());
+        assert_eq!(migrated.migrate_records(1), U64(1));
+        assert!(migrated.safety_deposits.get(&"deposit_1".to_string()).is_none());
+        assert_eq!(migrated.migrate_records(1), U64(0));
+        assert!(migrated.safety_deposits.get(&"deposit_1".to_string()).is_some());
+        assert_eq!(migrated.migrate_records(1), U64(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not the owner")]
+    fn test_upgrade_requires_owner() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        let mut contract = FusionPlusContract::new();
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.upgrade();
+    }
+}
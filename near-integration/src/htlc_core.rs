@@ -0,0 +1,186 @@
+// HTLC primitives shared by every contract variant: secret verification,
+// timelock checks and signed withdraw authorizations. Events live in `events`.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, require, AccountId};
+use sha2::{Digest, Sha256};
+
+pub const MIN_TIMELOCK: u64 = 3600; // 1 hour
+pub const MAX_TIMELOCK: u64 = 2592000; // 30 days
+pub const HASHLOCK_HEX_LENGTH: usize = 64; // Hex encoded 32-byte hash
+
+// Hash a hashlock commits to, chosen per HTLC. Keccak-256 is what the EVM
+// escrows and the Soroban contracts use, so one hashlock can guard both legs.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Keccak256,
+}
+
+impl HashAlgorithm {
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            HashAlgorithm::Keccak256 => env::keccak256(data),
+        }
+    }
+}
+
+// Current block time in seconds
+pub fn now_seconds() -> u64 {
+    env::block_timestamp() / 1_000_000_000
+}
+
+// Hex encoded hash of a hex encoded secret
+pub fn hash_secret(secret: &str, algorithm: HashAlgorithm) -> String {
+    let secret_bytes = hex::decode(secret).expect("Invalid hex secret");
+    hex::encode(algorithm.digest(&secret_bytes))
+}
+
+// Non-panicking form of `verify_secret`, for batches that report failures
+// per item
+pub fn check_secret(secret: &str, hashlock: &str, algorithm: HashAlgorithm) -> Result<(), &'static str> {
+    let secret_bytes = hex::decode(secret).map_err(|_| "Invalid hex secret")?;
+    if hex::encode(algorithm.digest(&secret_bytes)) != hashlock {
+        return Err("Invalid secret");
+    }
+    Ok(())
+}
+
+pub fn verify_secret(secret: &str, hashlock: &str, algorithm: HashAlgorithm) {
+    check_secret(secret, hashlock, algorithm).unwrap_or_else(|e| env::panic_str(e));
+}
+
+pub fn is_valid_hashlock(hashlock: &str) -> bool {
+    hashlock.len() == HASHLOCK_HEX_LENGTH
+}
+
+pub fn is_valid_timelock(timelock_seconds: u64) -> bool {
+    (MIN_TIMELOCK..=MAX_TIMELOCK).contains(&timelock_seconds)
+}
+
+pub fn assert_valid_hashlock(hashlock: &str) {
+    require!(is_valid_hashlock(hashlock), "Invalid hashlock length");
+}
+
+pub fn assert_valid_timelock(timelock_seconds: u64) {
+    require!(is_valid_timelock(timelock_seconds), "Invalid timelock");
+}
+
+// Whether an absolute timelock in seconds has passed
+pub fn is_expired(timelock: u64) -> bool {
+    now_seconds() >= timelock
+}
+
+// What a receiver signs so that any relayer can withdraw on its behalf: the
+// Borsh serialization of this struct, signed with the ed25519 key behind the
+// receiver's implicit account. `payout` gets the amount minus `tip`, the
+// relayer gets `tip`; `expiry` is in seconds.
+#[derive(BorshSerialize)]
+pub struct WithdrawAuthorization {
+    pub contract_id: AccountId,
+    pub htlc_id: String,
+    pub payout: AccountId,
+    pub tip: u128,
+    pub expiry: u64,
+}
+
+impl WithdrawAuthorization {
+    // Panics unless `signature` (hex) is the receiver's and has not expired
+    pub fn verify(&self, receiver: &AccountId, signature: &str) {
+        require!(now_seconds() < self.expiry, "Authorization expired");
+        let public_key = implicit_account_key(receiver)
+            .unwrap_or_else(|| env::panic_str("Receiver is not an implicit account"));
+        let signature: [u8; 64] = hex::decode(signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .expect("Invalid signature");
+        let message = borsh::to_vec(self).expect("Failed to serialize authorization");
+        require!(env::ed25519_verify(&signature, &message, &public_key), "Invalid signature");
+    }
+}
+
+// Terms of one per-order escrow. The factory deploys the escrow with them
+// and keeps a copy; neither can change them afterwards.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EscrowTerms {
+    pub order_hash: String, // Hex encoded, unique per maker
+    pub maker: AccountId,
+    pub taker: AccountId,
+    pub amount: U128, // NEAR held for the order, not counting storage
+    pub hashlock: String,
+    pub hash_algorithm: HashAlgorithm,
+    pub timelock: U64, // Refunds open, in seconds
+}
+
+impl EscrowTerms {
+    // Identifies the escrow at its factory and names its account. Order
+    // hashes are namespaced by maker, so no one can take another maker's.
+    pub fn escrow_key(&self) -> String {
+        escrow_key(&self.maker, &self.order_hash)
+    }
+}
+
+pub fn escrow_key(maker: &AccountId, order_hash: &str) -> String {
+    let key = borsh::to_vec(&(maker, order_hash)).expect("Failed to serialize escrow key");
+    hex::encode(Sha256::digest(key))
+}
+
+// How a per-order escrow ended, reported back to its factory
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum EscrowOutcome {
+    Withdrawn,
+    Refunded,
+    DeployFailed,
+}
+
+// The ed25519 key of an implicit account, whose id is the hex encoded key.
+// Named accounts have no key the contract can look up.
+pub fn implicit_account_key(account_id: &AccountId) -> Option<[u8; 32]> {
+    let id = account_id.as_str();
+    if id.len() != 64 || !id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return None;
+    }
+    hex::decode(id).ok()?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_secret_matches_sha256() {
+        assert_eq!(
+            hash_secret(&hex::encode("mysecret"), HashAlgorithm::Sha256),
+            hex::encode(Sha256::digest("mysecret"))
+        );
+    }
+
+    #[test]
+    fn test_hash_secret_keccak256() {
+        assert_eq!(
+            hash_secret("", HashAlgorithm::Keccak256),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+    }
+
+    #[test]
+    fn test_implicit_account_key() {
+        let key = [0xabu8; 32];
+        assert_eq!(implicit_account_key(&hex::encode(key).parse().unwrap()), Some(key));
+        assert_eq!(implicit_account_key(&"alice.near".parse().unwrap()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid timelock")]
+    fn test_timelock_bounds() {
+        assert_valid_timelock(MIN_TIMELOCK);
+        assert_valid_timelock(MAX_TIMELOCK);
+        assert_valid_timelock(MAX_TIMELOCK + 1);
+    }
+}
@@ -1,4 +1,41 @@
+pub mod events;
+pub mod htlc_core;
+pub mod merkle;
+
+// Contract variants, selected with cargo features. Each one exports its own
+// `new`, `withdraw`, `refund`, ... so only one can go into a wasm build.
+#[cfg(feature = "simple")]
 pub mod fusion_htlc_simple;
+#[cfg(feature = "tokens")]
+pub mod fusion_htlc;
+#[cfg(feature = "partial-fills")]
+pub mod fusion_htlc_partial;
+#[cfg(feature = "fusion-plus")]
+pub mod fusion_plus;
+// Per-order escrows and the factory deploying them, two separate wasm builds
+#[cfg(feature = "escrow")]
+pub mod escrow;
+#[cfg(feature = "escrow-factory")]
+pub mod escrow_factory;
+
+#[cfg(all(
+    target_arch = "wasm32",
+    any(
+        all(
+            feature = "simple",
+            any(feature = "tokens", feature = "partial-fills", feature = "fusion-plus", feature = "escrow", feature = "escrow-factory")
+        ),
+        all(
+            feature = "tokens",
+            any(feature = "partial-fills", feature = "fusion-plus", feature = "escrow", feature = "escrow-factory")
+        ),
+        all(feature = "partial-fills", any(feature = "fusion-plus", feature = "escrow", feature = "escrow-factory")),
+        all(feature = "fusion-plus", any(feature = "escrow", feature = "escrow-factory")),
+        all(feature = "escrow", feature = "escrow-factory"),
+    )
+))]
+compile_error!("Enable exactly one of the `simple`, `tokens`, `partial-fills`, `fusion-plus`, `escrow` and `escrow-factory` features for a wasm build");
 
 // Export the simple HTLC contract that builds correctly
-pub use fusion_htlc_simple::*;
\ No newline at end of file
+#[cfg(feature = "simple")]
+pub use fusion_htlc_simple::*;
@@ -0,0 +1,141 @@
+// Merkle commitments for multi-secret partial fills (1inch Fusion+ scheme).
+//
+// An order split into N parts commits to N + 1 secrets. Leaf `i` is
+// H(u64_be(i) || H(secret_i)) and internal nodes hash the sorted pair of
+// children, so proofs do not need left/right flags. H is the order's hash
+// algorithm, so the tree can be checked on the EVM leg with the same root.
+use crate::htlc_core::HashAlgorithm;
+
+pub type Hash = [u8; 32];
+
+fn hash(algorithm: HashAlgorithm, data: &[u8]) -> Hash {
+    algorithm.digest(data).try_into().expect("32-byte digest")
+}
+
+pub fn leaf(algorithm: HashAlgorithm, index: u32, secret_hash: &[u8]) -> Hash {
+    hash(algorithm, &[&(index as u64).to_be_bytes()[..], secret_hash].concat())
+}
+
+fn hash_pair(algorithm: HashAlgorithm, a: &Hash, b: &Hash) -> Hash {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    hash(algorithm, &[&first[..], &second[..]].concat())
+}
+
+pub fn verify_proof(algorithm: HashAlgorithm, root: &Hash, leaf: Hash, proof: &[Hash]) -> bool {
+    let computed = proof.iter().fold(leaf, |node, sibling| hash_pair(algorithm, &node, sibling));
+    &computed == root
+}
+
+// Root over all leaves; an odd node is carried up to the next level unchanged
+pub fn root(algorithm: HashAlgorithm, leaves: &[Hash]) -> Hash {
+    assert!(!leaves.is_empty(), "No leaves");
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| if pair.len() == 2 { hash_pair(algorithm, &pair[0], &pair[1]) } else { pair[0] })
+            .collect();
+    }
+    level[0]
+}
+
+// Sibling path for `leaves[index]`, matching `root`
+pub fn proof(algorithm: HashAlgorithm, leaves: &[Hash], mut index: usize) -> Vec<Hash> {
+    let mut path = Vec::new();
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            path.push(level[sibling]);
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| if pair.len() == 2 { hash_pair(algorithm, &pair[0], &pair[1]) } else { pair[0] })
+            .collect();
+        index /= 2;
+    }
+    path
+}
+
+// Index of the secret a fill must reveal, or None if the fill stays inside a
+// part that an earlier fill already used. Mirrors 1inch's
+// `_isValidPartialFill`: a fill that completes the order uses the extra
+// secret N.
+pub fn expected_secret_index(total: u128, filled: u128, fill_amount: u128, parts: u32) -> Option<u32> {
+    let filled_after = filled + fill_amount;
+    let index = mul_div_floor(filled_after - 1, parts, total) as u32;
+
+    if filled_after == total {
+        return Some(index + 1);
+    }
+    if filled > 0 && mul_div_floor(filled - 1, parts, total) as u32 == index {
+        return None;
+    }
+    Some(index)
+}
+
+// floor(x * p / t) without overflowing u128, via 256-bit long division
+fn mul_div_floor(x: u128, p: u32, t: u128) -> u128 {
+    let p = p as u128;
+    let lo = (x & u64::MAX as u128) * p;
+    let mid = (x >> 64) * p;
+    let low = lo.wrapping_add(mid << 64);
+    let high = (mid >> 64) + u128::from(low < lo);
+
+    let mut quotient = 0u128;
+    let mut rem = 0u128;
+    for i in (0..256).rev() {
+        let bit = if i >= 128 { (high >> (i - 128)) & 1 } else { (low >> i) & 1 };
+        let carry = rem >> 127;
+        rem = (rem << 1) | bit;
+        if carry == 1 || rem >= t {
+            rem = rem.wrapping_sub(t);
+            if i < 128 {
+                quotient |= 1 << i;
+            }
+        }
+    }
+    quotient
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn secret_hash(i: u8) -> Hash {
+        Sha256::digest([i; 32]).into()
+    }
+
+    #[test]
+    fn test_proofs_verify_for_every_leaf() {
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Keccak256] {
+            let leaves: Vec<Hash> = (0..5u8).map(|i| leaf(algorithm, i as u32, &secret_hash(i))).collect();
+            let root = root(algorithm, &leaves);
+
+            for (i, l) in leaves.iter().enumerate() {
+                assert!(verify_proof(algorithm, &root, *l, &proof(algorithm, &leaves, i)));
+            }
+            assert!(!verify_proof(algorithm, &root, leaf(algorithm, 9, &secret_hash(0)), &proof(algorithm, &leaves, 0)));
+        }
+    }
+
+    #[test]
+    fn test_tree_depends_on_the_algorithm() {
+        let leaves: Vec<Hash> =
+            (0..3u8).map(|i| leaf(HashAlgorithm::Keccak256, i as u32, &secret_hash(i))).collect();
+        let root = root(HashAlgorithm::Keccak256, &leaves);
+        assert_eq!(leaves[0].to_vec(), near_sdk::env::keccak256(&[&0u64.to_be_bytes()[..], &secret_hash(0)].concat()));
+        assert!(!verify_proof(HashAlgorithm::Sha256, &root, leaves[1], &proof(HashAlgorithm::Keccak256, &leaves, 1)));
+    }
+
+    #[test]
+    fn test_expected_secret_index() {
+        // 4 parts of 100: the first 30 lands in part 1, completing uses secret 4
+        assert_eq!(expected_secret_index(100, 0, 30, 4), Some(1));
+        assert_eq!(expected_secret_index(100, 30, 10, 4), None);
+        assert_eq!(expected_secret_index(100, 30, 30, 4), Some(2));
+        assert_eq!(expected_secret_index(100, 60, 40, 4), Some(4));
+        assert_eq!(mul_div_floor(u128::MAX, 7, u128::MAX), 7);
+    }
+}
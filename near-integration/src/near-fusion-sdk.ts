@@ -6,12 +6,14 @@ import BN from 'bn.js';
 interface FusionPlusContract extends Contract {
   // Change methods
   create_htlc(args: {
-    receiver: string;
-    hashlock: string;
-    timelock_seconds: number;
-    allow_partial_fills: boolean;
-    min_fill_amount?: string;
-    require_safety_deposit: boolean;
+    args: {
+      receiver: string | null;
+      hashlock: string;
+      timelock_seconds: number;
+      allow_partial_fills: boolean;
+      min_fill_amount?: string;
+      require_safety_deposit: boolean;
+    };
   }, gas?: string, deposit?: string): Promise<string>;
 
   withdraw(args: {
@@ -39,6 +41,14 @@ interface FusionPlusContract extends Contract {
     fill_id: string;
   }): Promise<void>;
 
+  cancel_order(args: {
+    htlc_id: string;
+  }): Promise<void>;
+
+  prune_htlcs(args: {
+    htlc_ids: string[];
+  }): Promise<number>;
+
   create_safety_deposit(args: {
     htlc_id: string;
   }, gas?: string, deposit?: string): Promise<string>;
@@ -50,19 +60,26 @@ interface FusionPlusContract extends Contract {
   // View methods
   get_htlc(args: { htlc_id: string }): Promise<FusionHTLC | null>;
   get_htlc_by_hashlock(args: { hashlock: string }): Promise<FusionHTLC | null>;
-  get_user_htlcs(args: { user: string; offset: number; limit: number }): Promise<FusionHTLC[]>;
-  get_active_htlcs(args: { offset: number; limit: number }): Promise<FusionHTLC[]>;
+  get_escrows_by_hashlock(args: { hashlock: string }): Promise<HashlockEscrows | null>;
+  get_user_htlcs(args: { user: string; from_cursor?: string; limit?: number }): Promise<HTLCPage>;
+  get_active_htlcs(args: { from_cursor?: string; limit?: number }): Promise<HTLCPage>;
   get_partial_fills(args: { htlc_id: string }): Promise<PartialFill[]>;
-  get_stats(): Promise<[string, number, number]>;
+  query_htlcs(args: { filter: HTLCFilter; from_cursor?: string; limit?: number }): Promise<HTLCPage>;
+  get_token_stats(args: { token_id: string | null }): Promise<TokenStats>;
+  get_stats(): Promise<TokenStats[]>;
+  verify_solvency(): Promise<AssetSolvency[]>;
   can_withdraw(args: { htlc_id: string }): Promise<boolean>;
   can_refund(args: { htlc_id: string }): Promise<boolean>;
+  can_prune(args: { htlc_id: string }): Promise<boolean>;
 }
 
 // Types
 export interface FusionHTLC {
   id: string;
   sender: string;
-  receiver: string;
+  receiver: string | null;
+  open_order: boolean;
+  taken_until: string | null;
   token_id?: string;
   total_amount: string;
   remaining_amount: string;
@@ -76,6 +93,48 @@ export interface FusionHTLC {
   created_at: string;
 }
 
+export interface HTLCFilter {
+  status?: FusionHTLC['status'];
+  token?: 'Native' | { Token: string };
+  sender?: string;
+  receiver?: string;
+  resolver?: string;
+  filler?: string;
+  expires_after?: string;
+  expires_before?: string;
+}
+
+export interface HTLCPage {
+  htlcs: FusionHTLC[];
+  next_cursor: string | null;
+}
+
+export interface TokenStats {
+  token_id: string | null;
+  htlcs_created: number;
+  active_htlcs: number;
+  completed_htlcs: number;
+  refunded_htlcs: number;
+  volume: string;
+  withdrawn_volume: string;
+  refunded_volume: string;
+}
+
+// Obligations against holdings of one asset; token_id is null for NEAR
+export interface AssetSolvency {
+  token_id: string | null;
+  escrowed: string;
+  pending_fills: string;
+  safety_deposits: string;
+  resolver_stake: string;
+  accrued_fees: string;
+  storage_balances: string;
+  obligations: string;
+  held: string;
+  surplus: string;
+  deficit: string;
+}
+
 export interface PartialFill {
   id: string;
   htlc_id: string;
@@ -83,10 +142,28 @@ export interface PartialFill {
   amount: string;
   status: 'Pending' | 'Completed' | 'Refunded';
   created_at: string;
+  settled_at: string | null;
+}
+
+export interface SafetyDeposit {
+  id: string;
+  htlc_id: string;
+  depositor: string;
+  amount: string;
+  created_at: string;
+}
+
+// The HTLC behind a hashlock with every escrow tied to it
+export interface HashlockEscrows {
+  htlc: FusionHTLC;
+  fills: PartialFill[];
+  safety_deposits: SafetyDeposit[];
 }
 
 export interface CreateHTLCParams {
-  receiver: string;
+  // Left out for an open order, taken by the first resolver to post the
+  // safety deposit
+  receiver?: string;
   secret?: string;
   hashlock?: string;
   timelockSeconds: number;
@@ -157,12 +234,17 @@ export class NearFusionSDK {
         viewMethods: [
           'get_htlc',
           'get_htlc_by_hashlock',
+          'get_escrows_by_hashlock',
           'get_user_htlcs',
           'get_active_htlcs',
           'get_partial_fills',
+          'query_htlcs',
+          'get_token_stats',
           'get_stats',
+          'verify_solvency',
           'can_withdraw',
-          'can_refund'
+          'can_refund',
+          'can_prune'
         ],
         changeMethods: [
           'create_htlc',
@@ -171,6 +253,8 @@ export class NearFusionSDK {
           'withdraw_partial',
           'refund',
           'refund_partial_fill',
+          'cancel_order',
+          'prune_htlcs',
           'create_safety_deposit',
           'claim_safety_deposit'
         ],
@@ -203,12 +287,14 @@ export class NearFusionSDK {
 
     const htlcId = await this.contract.create_htlc(
       {
-        receiver: params.receiver,
-        hashlock,
-        timelock_seconds: params.timelockSeconds,
-        allow_partial_fills: params.allowPartialFills || false,
-        min_fill_amount: params.minFillAmount,
-        require_safety_deposit: params.requireSafetyDeposit || false,
+        args: {
+          receiver: params.receiver ?? null,
+          hashlock,
+          timelock_seconds: params.timelockSeconds,
+          allow_partial_fills: params.allowPartialFills || false,
+          min_fill_amount: params.minFillAmount,
+          require_safety_deposit: params.requireSafetyDeposit || false,
+        },
       },
       '100000000000000', // 100 TGas
       params.amount
@@ -261,6 +347,19 @@ export class NearFusionSDK {
     await this.contract.refund({ htlc_id: htlcId });
   }
 
+  // Take back an order no resolver has taken or filled yet
+  async cancelOrder(htlcId: string): Promise<void> {
+    if (!this.contract) throw new Error('Not connected');
+    await this.contract.cancel_order({ htlc_id: htlcId });
+  }
+
+  // Remove settled HTLCs past the retention period; returns how many were
+  // removed
+  async pruneHTLCs(htlcIds: string[]): Promise<number> {
+    if (!this.contract) throw new Error('Not connected');
+    return await this.contract.prune_htlcs({ htlc_ids: htlcIds });
+  }
+
   // Get HTLC details
   async getHTLC(htlcId: string): Promise<FusionHTLC | null> {
     if (!this.contract) throw new Error('Not connected');
@@ -273,16 +372,22 @@ export class NearFusionSDK {
     return await this.contract.get_htlc_by_hashlock({ hashlock });
   }
 
-  // Get user HTLCs
-  async getUserHTLCs(user: string, offset = 0, limit = 10): Promise<FusionHTLC[]> {
+  // Get the HTLC behind a hashlock with its fills and safety deposits
+  async getEscrowsByHashlock(hashlock: string): Promise<HashlockEscrows | null> {
+    if (!this.contract) throw new Error('Not connected');
+    return await this.contract.get_escrows_by_hashlock({ hashlock });
+  }
+
+  // Get user HTLCs, a page at a time; pass `next_cursor` back for the next one
+  async getUserHTLCs(user: string, fromCursor?: string, limit = 10): Promise<HTLCPage> {
     if (!this.contract) throw new Error('Not connected');
-    return await this.contract.get_user_htlcs({ user, offset, limit });
+    return await this.contract.get_user_htlcs({ user, from_cursor: fromCursor, limit });
   }
 
-  // Get active HTLCs
-  async getActiveHTLCs(offset = 0, limit = 10): Promise<FusionHTLC[]> {
+  // Get active HTLCs, a page at a time
+  async getActiveHTLCs(fromCursor?: string, limit = 10): Promise<HTLCPage> {
     if (!this.contract) throw new Error('Not connected');
-    return await this.contract.get_active_htlcs({ offset, limit });
+    return await this.contract.get_active_htlcs({ from_cursor: fromCursor, limit });
   }
 
   // Get partial fills
@@ -291,14 +396,32 @@ export class NearFusionSDK {
     return await this.contract.get_partial_fills({ htlc_id: htlcId });
   }
 
-  // Get contract stats
+  // Query HTLCs page by page; pass the returned next_cursor to continue
+  async queryHTLCs(filter: HTLCFilter = {}, fromCursor?: string, limit?: number): Promise<HTLCPage> {
+    if (!this.contract) throw new Error('Not connected');
+    return await this.contract.query_htlcs({ filter, from_cursor: fromCursor, limit });
+  }
+
+  // Per-asset surplus or deficit of the contract
+  async verifySolvency(): Promise<AssetSolvency[]> {
+    if (!this.contract) throw new Error('Not connected');
+    return await this.contract.verify_solvency();
+  }
+
+  // Get the stats of one token, NEAR by default
+  async getTokenStats(tokenId: string | null = null): Promise<TokenStats> {
+    if (!this.contract) throw new Error('Not connected');
+    return await this.contract.get_token_stats({ token_id: tokenId });
+  }
+
+  // Get contract stats for NEAR HTLCs
   async getStats(): Promise<{ totalVolume: string; totalHTLCs: number; activeHTLCs: number }> {
     if (!this.contract) throw new Error('Not connected');
-    const [totalVolume, totalHTLCs, activeHTLCs] = await this.contract.get_stats();
+    const stats = await this.getTokenStats(null);
     return {
-      totalVolume,
-      totalHTLCs,
-      activeHTLCs,
+      totalVolume: stats.volume,
+      totalHTLCs: stats.htlcs_created,
+      activeHTLCs: stats.active_htlcs,
     };
   }
 
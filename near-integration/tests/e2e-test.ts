@@ -224,9 +224,9 @@ async function testContractStats() {
   console.log(`Active HTLCs: ${stats.activeHTLCs}`);
 
   // Get active HTLCs
-  const activeHTLCs = await sdk.getActiveHTLCs(0, 10);
-  console.log(`\nActive HTLCs (showing ${activeHTLCs.length}):`);
-  for (const htlc of activeHTLCs) {
+  const activeHTLCs = await sdk.getActiveHTLCs(undefined, 10);
+  console.log(`\nActive HTLCs (showing ${activeHTLCs.htlcs.length}):`);
+  for (const htlc of activeHTLCs.htlcs) {
     console.log(`- ${htlc.id}: ${NearFusionSDK.formatNearAmount(htlc.total_amount)} NEAR`);
   }
 
@@ -160,11 +160,14 @@ describe('NEAR Fusion+ Integration Tests', () => {
         htlcContract,
         'create_htlc_partial',
         {
-          receiver: bob.accountId,
-          hashlock,
-          timelock_seconds: 3600,
-          allow_partial_fills: true,
-          min_fill_amount: NEAR.parse('0.1').toString(),
+          args: {
+            receiver: bob.accountId,
+            hashlock,
+            timelock_seconds: 3600,
+            allow_partial_fills: true,
+            min_fill_amount: NEAR.parse('2.5').toString(),
+            parts_count: 4,
+          },
         },
         {
           attachedDeposit: NEAR.parse('10').toString(),
@@ -177,28 +180,36 @@ describe('NEAR Fusion+ Integration Tests', () => {
       expect(htlc.allow_partial_fills).toBe(true);
       expect(htlc.total_amount).toBe(NEAR.parse('10').toString());
       expect(htlc.remaining_amount).toBe(NEAR.parse('10').toString());
-      expect(htlc.min_fill_amount).toBe(NEAR.parse('0.1').toString());
+      expect(htlc.min_fill_amount).toBe(NEAR.parse('2.5').toString());
     });
 
     test('should create and claim partial fills', async () => {
+      // A single-fill order: the Merkle root is the one leaf,
+      // sha256(u64_be(0) || sha256(secret))
       const secret = 'partial_fill_secret';
-      const hashlock = crypto.createHash('sha256')
+      const secretHash = crypto.createHash('sha256')
         .update(Buffer.from(secret))
         .digest('hex');
+      const hashlock = crypto.createHash('sha256')
+        .update(Buffer.concat([Buffer.alloc(8), Buffer.from(secretHash, 'hex')]))
+        .digest('hex');
 
       // Create HTLC with partial fills
       const htlcResult = await alice.call(
         htlcContract,
         'create_htlc_partial',
         {
-          receiver: bob.accountId,
-          hashlock,
-          timelock_seconds: 3600,
-          allow_partial_fills: true,
-          min_fill_amount: NEAR.parse('1').toString(),
+          args: {
+            receiver: bob.accountId,
+            hashlock,
+            timelock_seconds: 3600,
+            allow_partial_fills: true,
+            min_fill_amount: NEAR.parse('3').toString(),
+            parts_count: 1,
+          },
         },
         {
-          attachedDeposit: NEAR.parse('10').toString(),
+          attachedDeposit: NEAR.parse('3').toString(),
         }
       );
 
@@ -216,6 +227,9 @@ describe('NEAR Fusion+ Integration Tests', () => {
         {
           htlc_id: htlcId,
           fill_amount: NEAR.parse('3').toString(),
+          secret_index: 0,
+          secret_hash: secretHash,
+          merkle_proof: [],
         },
         {
           attachedDeposit: NEAR.parse('3').toString(),
@@ -302,7 +316,7 @@ describe('NEAR Fusion+ Integration Tests', () => {
   });
 
   describe('Safety Deposits', () => {
-    test('should create and claim safety deposit', async () => {
+    test('should lock safety deposit until the HTLC settles', async () => {
       const hashlock = crypto.randomBytes(32).toString('hex');
 
       // Create HTLC
@@ -341,16 +355,17 @@ describe('NEAR Fusion+ Integration Tests', () => {
       const depositId = depositResult as string;
       expect(depositId).toMatch(/^deposit_\d+$/);
 
-      // Claim safety deposit
-      await resolver.call(htlcContract, 'claim_safety_deposit', {
-        deposit_id: depositId,
-      });
+      // The deposit stays locked until the HTLC settles
+      await expect(
+        resolver.call(htlcContract, 'claim_safety_deposit', {
+          deposit_id: depositId,
+        })
+      ).rejects.toThrow('HTLC not settled');
 
-      // Verify deposit removed
       const deposit = await htlcContract.view('get_safety_deposit', {
         deposit_id: depositId,
       });
-      expect(deposit).toBeNull();
+      expect(deposit).not.toBeNull();
     });
   });
 
@@ -1,12 +1,13 @@
-use near_sdk::test_utils::{accounts, VMContextBuilder};
-use near_sdk::{testing_env, NearToken};
-use sha2::{Digest, Sha256};
+// Each module runs against the contract variant its cargo feature builds, e.g.
+// `cargo test --no-default-features --features tokens,partial-fills`
 
 // Test the full-featured HTLC contract
-#[cfg(test)]
+#[cfg(feature = "tokens")]
 mod fusion_htlc_tests {
-    use super::*;
-    use fusion_htlc_near::fusion_htlc::{FusionHTLCContract, HTLC, SafetyDeposit};
+    use fusion_htlc_near::fusion_htlc::FusionHTLCContract;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, NearToken};
+    use sha2::{Digest, Sha256};
 
     #[test]
     fn test_htlc_with_safety_deposit() {
@@ -41,18 +42,21 @@ mod fusion_htlc_tests {
         // Test safety deposit creation
         testing_env!(VMContextBuilder::new()
             .current_account_id(accounts(0))
-            .predecessor_account_id(accounts(3)) // Resolver
+            .predecessor_account_id(accounts(2)) // Resolver taking the swap
             .attached_deposit(NearToken::from_millinear(100))
             .block_timestamp(1_000_000_000_000_000_000)
             .build());
 
         let deposit_id = contract.create_safety_deposit(htlc_id.clone());
         let deposit = contract.get_safety_deposit(deposit_id.clone()).unwrap();
-        assert_eq!(deposit.resolver, accounts(3));
+        assert_eq!(deposit.resolver, accounts(2));
         assert_eq!(deposit.htlc_id, htlc_id);
 
-        // Claim safety deposit back
-        contract.claim_safety_deposit(deposit_id);
+        // The deposit is released to the resolver once it completed the swap
+        contract.withdraw(htlc_id.clone(), hex::encode(secret_bytes));
+        assert_eq!(contract.get_htlc(htlc_id).unwrap().settled_by, Some(accounts(2)));
+        contract.claim_safety_deposit(deposit_id.clone());
+        assert!(contract.get_safety_deposit(deposit_id).is_none());
     }
 
     #[test]
@@ -104,206 +108,223 @@ mod fusion_htlc_tests {
 }
 
 // Test the partial fills HTLC contract
-#[cfg(test)]
+#[cfg(feature = "partial-fills")]
 mod fusion_htlc_partial_tests {
-    use super::*;
-    use fusion_htlc_near::fusion_htlc_partial::{FusionHTLCPartialContract, HTLCPartial, PartialFill};
+    use fusion_htlc_near::fusion_htlc_partial::{CreateHTLCPartialArgs, FusionHTLCPartialContract};
+    use fusion_htlc_near::htlc_core::HashAlgorithm;
+    use fusion_htlc_near::merkle;
     use near_sdk::json_types::U128;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId, NearToken};
+    use sha2::{Digest, Sha256};
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    // One maker secret per fill and the Merkle leaves committing to them
+    fn fill_secrets(parts: u32) -> (Vec<[u8; 32]>, Vec<merkle::Hash>) {
+        let secrets: Vec<[u8; 32]> = (0..parts).map(|i| [i as u8 + 1; 32]).collect();
+        let leaves = secrets
+            .iter()
+            .enumerate()
+            .map(|(i, secret)| merkle::leaf(HashAlgorithm::Sha256, i as u32, &Sha256::digest(secret)))
+            .collect();
+        (secrets, leaves)
+    }
 
-    #[test]
-    fn test_partial_fill_creation() {
-        let context = VMContextBuilder::new()
+    fn create_htlc(contract: &mut FusionHTLCPartialContract, near: u128, leaves: &[merkle::Hash]) -> String {
+        testing_env!(VMContextBuilder::new()
             .current_account_id(accounts(0))
             .predecessor_account_id(accounts(1))
-            .attached_deposit(NearToken::from_near(10))
-            .block_timestamp(1_000_000_000_000_000_000)
-            .build();
-        testing_env!(context);
+            .attached_deposit(NearToken::from_near(near))
+            .block_timestamp(START)
+            .build());
+        contract.create_htlc_partial(CreateHTLCPartialArgs {
+            receiver: accounts(2),
+            hashlock: hex::encode(merkle::root(HashAlgorithm::Sha256, leaves)),
+            timelock_seconds: 3600,
+            allow_partial_fills: true,
+            // As low as the secrets allow
+            min_fill_amount: U128(NearToken::from_near(near).as_yoctonear().div_ceil(leaves.len() as u128)),
+            parts_count: Some(leaves.len() as u32),
+            fill_timelock_seconds: Some(1800), // each fill is withdrawable for half an hour
+        })
+    }
 
-        let mut contract = FusionHTLCPartialContract::new();
-        let hashlock = hex::encode([3u8; 32]);
+    fn fill(
+        contract: &mut FusionHTLCPartialContract,
+        htlc_id: &str,
+        filler: AccountId,
+        near: u128,
+        secrets: &[[u8; 32]],
+        leaves: &[merkle::Hash],
+        index: u32,
+    ) -> String {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(filler)
+            .attached_deposit(NearToken::from_near(near))
+            .block_timestamp(START)
+            .build());
+        contract.create_partial_fill(
+            htlc_id.to_string(),
+            U128(NearToken::from_near(near).as_yoctonear()),
+            index,
+            hex::encode(Sha256::digest(secrets[index as usize])),
+            merkle::proof(HashAlgorithm::Sha256, leaves, index as usize).iter().map(hex::encode).collect(),
+        )
+    }
 
-        // Create HTLC with partial fills enabled
-        let htlc_id = contract.create_htlc_partial(
-            accounts(2),
-            hashlock,
-            3600,
-            true, // allow partial fills
-            U128(NearToken::from_near(1).as_yoctonear()), // min fill amount
-        );
+    fn at(predecessor: AccountId, seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .block_timestamp(START + seconds * 1_000_000_000)
+            .build());
+    }
+
+    #[test]
+    fn test_partial_fill_creation() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionHTLCPartialContract::new();
+        let (secrets, leaves) = fill_secrets(4);
+        let htlc_id = create_htlc(&mut contract, 10, &leaves);
 
         let htlc = contract.get_htlc_partial(htlc_id.clone()).unwrap();
         assert!(htlc.allow_partial_fills);
+        assert_eq!(htlc.parts_count, 4);
         assert_eq!(htlc.total_amount.0, NearToken::from_near(10).as_yoctonear());
         assert_eq!(htlc.remaining_amount.0, NearToken::from_near(10).as_yoctonear());
 
-        // Create partial fill
-        testing_env!(VMContextBuilder::new()
-            .current_account_id(accounts(0))
-            .predecessor_account_id(accounts(3)) // Filler
-            .attached_deposit(NearToken::from_near(3))
-            .block_timestamp(1_000_000_000_000_000_000)
-            .build());
-
-        let fill_id = contract.create_partial_fill(
-            htlc_id.clone(),
-            U128(NearToken::from_near(3).as_yoctonear()),
-        );
+        let fill_id = fill(&mut contract, &htlc_id, accounts(3), 3, &secrets, &leaves, 0);
 
         // Verify fill created
         let fill = contract.get_partial_fill(fill_id.clone()).unwrap();
         assert_eq!(fill.filler, accounts(3));
         assert_eq!(fill.amount.0, NearToken::from_near(3).as_yoctonear());
+        assert_eq!(fill.secret_hash, hex::encode(Sha256::digest(secrets[0])));
+        assert_eq!(fill.timelock.0, START / 1_000_000_000 + 1800);
         assert!(!fill.claimed);
 
         // Check HTLC updated
         let htlc = contract.get_htlc_partial(htlc_id.clone()).unwrap();
         assert_eq!(htlc.remaining_amount.0, NearToken::from_near(7).as_yoctonear());
+        assert_eq!(htlc.next_secret_index, 1);
         assert_eq!(htlc.fills.len(), 1);
     }
 
     #[test]
     fn test_partial_fill_withdrawal() {
-        let context = VMContextBuilder::new()
-            .current_account_id(accounts(0))
-            .predecessor_account_id(accounts(1))
-            .attached_deposit(NearToken::from_near(5))
-            .block_timestamp(1_000_000_000_000_000_000)
-            .build();
-        testing_env!(context);
-
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
         let mut contract = FusionHTLCPartialContract::new();
-        
-        let secret = "partial_secret";
-        let mut hasher = Sha256::new();
-        hasher.update(secret.as_bytes());
-        let hashlock = hex::encode(hasher.finalize());
-
-        // Create HTLC with partial fills
-        let htlc_id = contract.create_htlc_partial(
-            accounts(2),
-            hashlock,
-            3600,
-            true,
-            U128(NearToken::from_near(1).as_yoctonear()),
-        );
-
-        // Create partial fill
-        testing_env!(VMContextBuilder::new()
-            .current_account_id(accounts(0))
-            .predecessor_account_id(accounts(3))
-            .attached_deposit(NearToken::from_near(2))
-            .block_timestamp(1_000_000_000_000_000_000)
-            .build());
-
-        let fill_id = contract.create_partial_fill(
-            htlc_id.clone(),
-            U128(NearToken::from_near(2).as_yoctonear()),
-        );
+        let (secrets, leaves) = fill_secrets(2);
+        let htlc_id = create_htlc(&mut contract, 5, &leaves);
+        let first = fill(&mut contract, &htlc_id, accounts(3), 3, &secrets, &leaves, 0);
+        let second = fill(&mut contract, &htlc_id, accounts(4), 2, &secrets, &leaves, 1);
 
         // Withdraw as receiver
-        testing_env!(VMContextBuilder::new()
-            .current_account_id(accounts(0))
-            .predecessor_account_id(accounts(2))
-            .block_timestamp(1_000_000_000_000_000_000 + 1800_000_000_000)
-            .build());
-
-        contract.withdraw_partial_fill(fill_id.clone(), hex::encode(secret));
+        at(accounts(2), 600);
+        contract.withdraw_partial_fill(first.clone(), hex::encode(secrets[0]));
 
-        // Verify fill claimed
-        let fill = contract.get_partial_fill(fill_id).unwrap();
+        // Verify fill claimed; the other fill's secret is still unknown
+        let fill = contract.get_partial_fill(first).unwrap();
         assert!(fill.claimed);
-        assert_eq!(fill.secret, Some(hex::encode(secret)));
+        assert_eq!(fill.secret, Some(hex::encode(secrets[0])));
+        assert!(!contract.get_partial_fill(second).unwrap().claimed);
     }
 
     #[test]
-    fn test_partial_fill_refund() {
-        let context = VMContextBuilder::new()
-            .current_account_id(accounts(0))
-            .predecessor_account_id(accounts(1))
-            .attached_deposit(NearToken::from_near(5))
-            .block_timestamp(1_000_000_000_000_000_000)
-            .build();
-        testing_env!(context);
-
+    #[should_panic(expected = "Invalid secret")]
+    fn test_fill_secret_does_not_unlock_other_fills() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
         let mut contract = FusionHTLCPartialContract::new();
-        let hashlock = hex::encode([4u8; 32]);
-
-        let htlc_id = contract.create_htlc_partial(
-            accounts(2),
-            hashlock,
-            3600,
-            true,
-            U128(NearToken::from_near(1).as_yoctonear()),
-        );
+        let (secrets, leaves) = fill_secrets(2);
+        let htlc_id = create_htlc(&mut contract, 5, &leaves);
+        fill(&mut contract, &htlc_id, accounts(3), 3, &secrets, &leaves, 0);
+        let second = fill(&mut contract, &htlc_id, accounts(4), 2, &secrets, &leaves, 1);
 
-        // Create partial fill
-        testing_env!(VMContextBuilder::new()
-            .current_account_id(accounts(0))
-            .predecessor_account_id(accounts(3))
-            .attached_deposit(NearToken::from_near(2))
-            .block_timestamp(1_000_000_000_000_000_000)
-            .build());
+        at(accounts(2), 600);
+        contract.withdraw_partial_fill(second, hex::encode(secrets[0]));
+    }
 
-        let fill_id = contract.create_partial_fill(
-            htlc_id.clone(),
-            U128(NearToken::from_near(2).as_yoctonear()),
-        );
+    #[test]
+    #[should_panic(expected = "Invalid secret index")]
+    fn test_fill_must_take_next_secret() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionHTLCPartialContract::new();
+        let (secrets, leaves) = fill_secrets(4);
+        let htlc_id = create_htlc(&mut contract, 5, &leaves);
+        fill(&mut contract, &htlc_id, accounts(3), 2, &secrets, &leaves, 2);
+    }
 
-        // Fast forward past expiry
-        testing_env!(VMContextBuilder::new()
-            .current_account_id(accounts(0))
-            .predecessor_account_id(accounts(3))
-            .block_timestamp(1_000_000_000_000_000_000 + 7200_000_000_000)
-            .build());
+    #[test]
+    fn test_partial_fill_refund() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionHTLCPartialContract::new();
+        let (secrets, leaves) = fill_secrets(4);
+        let htlc_id = create_htlc(&mut contract, 5, &leaves);
+        let fill_id = fill(&mut contract, &htlc_id, accounts(3), 2, &secrets, &leaves, 0);
 
-        // Refund the fill
+        // The fill's own timelock passed, the HTLC's has not
+        at(accounts(3), 1800);
         contract.refund_partial_fill(fill_id.clone());
 
-        // Verify fill removed and HTLC updated
+        // Verify fill removed; its secret stays used, so its amount is not
+        // fillable again
         assert!(contract.get_partial_fill(fill_id).is_none());
         let htlc = contract.get_htlc_partial(htlc_id).unwrap();
-        assert_eq!(htlc.remaining_amount.0, NearToken::from_near(5).as_yoctonear());
+        assert_eq!(htlc.remaining_amount.0, NearToken::from_near(3).as_yoctonear());
+        assert_eq!(htlc.next_secret_index, 1);
+        assert!(htlc.fills.is_empty());
     }
 
     #[test]
-    fn test_multiple_partial_fills() {
-        let context = VMContextBuilder::new()
+    #[should_panic(expected = "Parts count cannot cover the amount")]
+    fn test_parts_must_cover_amount() {
+        testing_env!(VMContextBuilder::new()
             .current_account_id(accounts(0))
             .predecessor_account_id(accounts(1))
             .attached_deposit(NearToken::from_near(10))
-            .block_timestamp(1_000_000_000_000_000_000)
-            .build();
-        testing_env!(context);
+            .block_timestamp(START)
+            .build());
+        let mut contract = FusionHTLCPartialContract::new();
+        let (_, leaves) = fill_secrets(2);
+        contract.create_htlc_partial(CreateHTLCPartialArgs {
+            receiver: accounts(2),
+            hashlock: hex::encode(merkle::root(HashAlgorithm::Sha256, &leaves)),
+            timelock_seconds: 3600,
+            allow_partial_fills: true,
+            min_fill_amount: U128(NearToken::from_near(1).as_yoctonear()),
+            parts_count: Some(2),
+            fill_timelock_seconds: None,
+        });
+    }
 
+    #[test]
+    #[should_panic(expected = "Fill expired")]
+    fn test_expired_fill_cannot_be_withdrawn() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
         let mut contract = FusionHTLCPartialContract::new();
-        let hashlock = hex::encode([5u8; 32]);
+        let (secrets, leaves) = fill_secrets(4);
+        let htlc_id = create_htlc(&mut contract, 5, &leaves);
+        let fill_id = fill(&mut contract, &htlc_id, accounts(3), 2, &secrets, &leaves, 0);
 
-        let htlc_id = contract.create_htlc_partial(
-            accounts(2),
-            hashlock,
-            3600,
-            true,
-            U128(NearToken::from_near(1).as_yoctonear()),
-        );
+        at(accounts(2), 1800);
+        contract.withdraw_partial_fill(fill_id, hex::encode(secrets[0]));
+    }
 
-        // Create multiple partial fills from different fillers
-        let fill_amounts = vec![2, 3, 1, 4];
-        let mut fill_ids = vec![];
+    #[test]
+    fn test_multiple_partial_fills() {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionHTLCPartialContract::new();
+        let (secrets, leaves) = fill_secrets(4);
+        let htlc_id = create_htlc(&mut contract, 10, &leaves);
 
+        // Create multiple partial fills from different fillers
+        // The last one takes what is left, below the minimum
+        let fill_amounts = [3, 3, 3, 1];
         for (i, amount) in fill_amounts.iter().enumerate() {
-            testing_env!(VMContextBuilder::new()
-                .current_account_id(accounts(0))
-                .predecessor_account_id(accounts(3 + i))
-                .attached_deposit(NearToken::from_near(*amount))
-                .block_timestamp(1_000_000_000_000_000_000)
-                .build());
-
-            let fill_id = contract.create_partial_fill(
-                htlc_id.clone(),
-                U128(NearToken::from_near(*amount).as_yoctonear()),
-            );
-            fill_ids.push(fill_id);
+            let filler: AccountId = format!("filler{}.near", i).parse().unwrap();
+            fill(&mut contract, &htlc_id, filler, *amount, &secrets, &leaves, i as u32);
         }
 
         // Verify all fills created
@@ -312,8 +333,108 @@ mod fusion_htlc_partial_tests {
         assert_eq!(htlc.remaining_amount.0, 0);
 
         // Check filler fills
-        let filler_fills = contract.get_filler_fills(accounts(3));
+        let filler_fills = contract.get_filler_fills("filler0.near".parse().unwrap());
         assert_eq!(filler_fills.len(), 1);
-        assert_eq!(filler_fills[0].amount.0, NearToken::from_near(2).as_yoctonear());
+        assert_eq!(filler_fills[0].amount.0, NearToken::from_near(3).as_yoctonear());
+    }
+}
+
+// Test the Fusion+ contract
+#[cfg(feature = "fusion-plus")]
+mod fusion_plus_tests {
+    use fusion_htlc_near::fusion_plus::{CreateHTLCArgs, FusionPlusContract, HTLCStatus};
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId, NearToken};
+    use sha2::{Digest, Sha256};
+
+    const START: u64 = 1_000_000_000_000_000_000;
+
+    fn at(predecessor: AccountId, seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .block_timestamp(START + seconds * 1_000_000_000)
+            .build());
+    }
+
+    // A one-hour HTLC of 1 NEAR from accounts(1) to accounts(2), locked to
+    // the SHA-256 of `secret`
+    fn create_htlc(secret: [u8; 32]) -> (FusionPlusContract, String) {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        let mut contract = FusionPlusContract::new();
+
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_near(1))
+            .block_timestamp(START)
+            .build());
+        contract.storage_deposit(None, None);
+        let htlc_id = contract.create_htlc(CreateHTLCArgs {
+            receiver: Some(accounts(2)),
+            hashlock: hex::encode(Sha256::digest(secret)),
+            timelock_seconds: 3600,
+            ..Default::default()
+        });
+        (contract, htlc_id)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_create_htlc() {
+        let (contract, htlc_id) = create_htlc([1u8; 32]);
+
+        let htlc = contract.get_htlc(htlc_id.clone()).unwrap();
+        assert_eq!(htlc.sender, accounts(1));
+        assert_eq!(htlc.receiver, Some(accounts(2)));
+        assert_eq!(htlc.total_amount.0, NearToken::from_near(1).as_yoctonear());
+        assert_eq!(htlc.status, HTLCStatus::Active);
+
+        let page = contract.get_user_htlcs(accounts(2), None, None);
+        assert_eq!(page.htlcs[0].id, htlc_id);
+        assert_eq!(contract.get_active_htlcs(None, None).htlcs.len(), 1);
+    }
+
+    #[test]
+    fn test_withdraw_with_secret() {
+        let (mut contract, htlc_id) = create_htlc([1u8; 32]);
+
+        at(accounts(2), 60);
+        contract.withdraw(htlc_id.clone(), hex::encode([1u8; 32]));
+
+        let htlc = contract.get_htlc(htlc_id).unwrap();
+        assert_eq!(htlc.status, HTLCStatus::Completed);
+        assert_eq!(htlc.secret, Some(hex::encode([1u8; 32])));
+        assert!(contract.get_active_htlcs(None, None).htlcs.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid secret")]
+    fn test_withdraw_rejects_wrong_secret() {
+        let (mut contract, htlc_id) = create_htlc([1u8; 32]);
+
+        at(accounts(2), 60);
+        contract.withdraw(htlc_id, hex::encode([2u8; 32]));
+    }
+
+    #[test]
+    fn test_refund_after_timelock() {
+        let (mut contract, htlc_id) = create_htlc([1u8; 32]);
+
+        at(accounts(1), 3600);
+        contract.refund(htlc_id.clone());
+
+        let htlc = contract.get_htlc(htlc_id).unwrap();
+        assert_eq!(htlc.status, HTLCStatus::Refunded);
+        assert_eq!(htlc.settled_by, Some(accounts(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not expired")]
+    fn test_refund_before_timelock() {
+        let (mut contract, htlc_id) = create_htlc([1u8; 32]);
+
+        at(accounts(1), 60);
+        contract.refund(htlc_id);
+    }
+}